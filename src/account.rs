@@ -0,0 +1,327 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    utils::get_required_headers,
+    api::do_json,
+    session, guest, social, leaderboard, block,
+    api_key, login_history, magic_link, oauth,
+    session::Session,
+    login_history::LoginAttempt,
+    social::{ Friendship, FriendRequest, Follow },
+    block::Block,
+    leaderboard::Score,
+    api_key::ApiKey,
+    oauth::OAuthClient,
+    audit::{ self, AuditEvent, Outcome },
+    storage,
+    dict::DICTIONARY,
+    user::{ User, UserClaims, AuthorizationStatus, authenticate },
+    webhook::WebhookEvent,
+    outbox,
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use base64;
+use std::{ fs, thread, time::Duration };
+use mongodb::{
+    bson::{ doc, Document },
+    options::{ FindOneAndUpdateOptions, ReturnDocument },
+    sync::Collection,
+};
+
+/*- Constants -*/
+const DELETION_GRACE_PERIOD:u64 = 60*60*24*14;
+const PURGE_INTERVAL:u64        = 60*60;
+
+/// # AccountExport
+/// Everything we store about a user, in a
+/// machine-readable format. The password, key
+/// and secret hashes are left out since they're
+/// of no use to anyone.
+#[derive(Serialize, Debug)]
+struct AccountExport {
+    exported_at     : u64,
+    username        : String,
+    displayname     : String,
+    email           : String,
+    uid             : String,
+    suid            : String,
+    project_id      : String,
+    deletion_scheduled_at: Option<u64>,
+    profile         : std::collections::BTreeMap<String, serde_json::Value>,
+    privacy         : std::collections::BTreeMap<String, crate::profile::Visibility>,
+
+    /*- Base64 encoded uploaded profile image, if any -*/
+    profile_image   : Option<String>,
+
+    sessions        : Vec<Session>,
+    login_history   : Vec<LoginAttempt>,
+    friends         : Vec<Friendship>,
+    friend_requests : Vec<FriendRequest>,
+    follows         : Vec<Follow>,
+    blocks          : Vec<Block>,
+    scores          : Vec<Score>,
+    api_keys        : Vec<ApiKey>,
+    oauth_clients   : Vec<OAuthClient>,
+}
+
+/*- Get the authorized user, or respond 401 -*/
+fn authorized_user(stream:&mut Stream) -> Option<(User, Collection<User>)> {
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            return None;
+        }
+    };
+
+    /*- Get the user -*/
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    match collection.find_one(doc!{ "suid": &claims.suid }, None) {
+        Ok(Some(user)) => Some((user, collection)),
+        Ok(None) => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        },
+        Err(_) => {
+            stream.respond(500, do_json(500, "Internal server error"));
+            None
+        }
+    }
+}
+
+/*- Export all data stored about the user -*/
+pub(crate) fn export(stream: &mut Stream) -> () {
+    let (user, _) = match authorized_user(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    /*- Read the profile image -*/
+    let profile_image:Option<String> = match &user.avatar {
+        Some(avatar) => storage::store().get(&avatar.key)
+            .ok()
+            .flatten()
+            .map(|blob| base64::encode(blob.bytes)),
+        None => None
+    };
+
+    /*- Everything stored elsewhere. An export missing
+        some of it would look complete, so any failure
+        fails the whole export -*/
+    let related = (|| Some((
+        session::all_of(&user.suid)?,
+        login_history::all_of(&user.suid)?,
+        social::all_of(&user.suid)?,
+        block::all_of(&user.suid)?,
+        leaderboard::all_of(&user.suid)?,
+        api_key::all_of(&user.suid)?,
+        oauth::clients_of(&user.suid)?,
+    )))();
+    let (sessions, login_history, (friends, friend_requests, follows), blocks, scores, api_keys, oauth_clients) = match related {
+        Some(e) => e,
+        None => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- Create the archive -*/
+    let export = AccountExport {
+        exported_at     : utils::get_unix_epoch_time(),
+        username        : user.username,
+        displayname     : user.displayname,
+        email           : user.email,
+        uid             : user.uid,
+        suid            : user.suid,
+        project_id      : user.project_id,
+        deletion_scheduled_at: user.deletion_scheduled_at,
+        profile         : user.profile,
+        privacy         : user.privacy,
+        profile_image,
+        sessions,
+        login_history,
+        friends,
+        friend_requests,
+        follows,
+        blocks,
+        scores,
+        api_keys,
+        oauth_clients,
+    };
+
+    /*- Respond -*/
+    audit::record(stream, AuditEvent::AccountExported, Outcome::Success, Some(&export.suid), Some(&export.suid));
+    stream.respond(
+        200u16,
+        Respond::new()
+            .json(
+                &serde_json::to_string(
+                    &export
+                ).unwrap_or(String::new())
+            )
+    );
+}
+
+/*- If a deletion request is confirmed. Guests have no
+    password, so they confirm with their username instead -*/
+fn deletion_confirmed(user:&User, password:Option<&str>, confirm:Option<&str>) -> bool {
+    match user.guest {
+        true => confirm == Some(user.username.as_str()),
+        false => password.map_or(false, |password| user.password == utils::hash(password))
+    }
+}
+
+/*- Schedule or cancel a deletion, going through the change
+    feed so that consumers see the scheduled deletion -*/
+fn set_deletion(user:&User, update:Document) -> mongodb::error::Result<Option<User>> {
+    outbox::record(&user.project_id, WebhookEvent::AccountUpdated, |users, session| {
+        users.find_one_and_update_with_session(
+            doc!{ "suid": &user.suid },
+            update.clone(),
+            FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            session
+        )
+    })
+}
+
+/*- Schedule the account for deletion after the grace period -*/
+pub(crate) fn delete(stream: &mut Stream) -> () {
+    let (user, collection) = match authorized_user(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    /*- Require some headers to be specified -*/
+    let required:&str = if user.guest { "confirm" } else { "password" };
+    if stream.headers.get(required).map_or(true, |e| e.is_empty()) {
+        return stream.respond(400, do_json(400, "Invalid headers"));
+    };
+
+    /*- Check the confirmation -*/
+    if !deletion_confirmed(&user, stream.headers.get("password").copied(), stream.headers.get("confirm").copied()) {
+        audit::record(stream, AuditEvent::AccountDeletionScheduled, Outcome::Failure, Some(&user.suid), Some(&user.suid));
+        return stream.respond(
+            401u16,
+            do_json(401, match user.guest {
+                true => DICTIONARY.error.guest_deletion,
+                false => DICTIONARY.error.login
+            })
+        );
+    };
+
+    /*- Schedule the deletion -*/
+    let deletion_time:u64 = utils::get_unix_epoch_time() + DELETION_GRACE_PERIOD;
+    match set_deletion(&user, doc!{ "$set": { "deletion_scheduled_at": deletion_time as i64 } }) {
+        Ok(_) => {
+            audit::record(stream, AuditEvent::AccountDeletionScheduled, Outcome::Success, Some(&user.suid), Some(&user.suid));
+            stream.respond(200, Respond::new().json(&format!(
+            "{{\"status\": 200, \"deletion_scheduled_at\": {}}}",
+            deletion_time
+            )))
+        },
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Cancel a scheduled deletion during the grace period -*/
+pub(crate) fn undo_delete(stream: &mut Stream) -> () {
+    let (user, collection) = match authorized_user(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    match set_deletion(&user, doc!{ "$unset": { "deletion_scheduled_at": "" } }) {
+        Ok(_) => {
+            audit::record(stream, AuditEvent::AccountDeletionCancelled, Outcome::Success, Some(&user.suid), Some(&user.suid));
+            stream.respond(200, do_json(200, "Success!"))
+        },
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Remove everything stored about a user. Their uploaded
+    files are content-addressed and may be shared, so they're
+    left to the blob garbage collector once unreferenced -*/
+pub(crate) fn purge_user(suid:&str) -> () {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+
+    /*- The users' project, for the change feed and the audit
+        log. If it can't be read the purge is tried again later -*/
+    let project_id:Option<String> = match collection.find_one(doc!{ "suid": suid }, None) {
+        Ok(user) => user.map(|user| user.project_id),
+        Err(_) => return
+    };
+
+    session::forget(suid);
+    api_key::forget(suid);
+    login_history::forget(suid);
+    magic_link::forget(suid);
+    oauth::forget(suid);
+    social::forget(suid);
+    leaderboard::forget(suid);
+    block::forget(suid);
+
+    /*- The deletion goes to the change feed of the users' project -*/
+    let project_id:String = match project_id {
+        Some(e) => e,
+        None => return
+    };
+    let outcome:Outcome = match outbox::record(&project_id, WebhookEvent::AccountDeleted, |users, session| {
+        users.find_one_and_delete_with_session(doc!{ "suid": suid }, None, session)
+    }) {
+        Ok(_) => Outcome::Success,
+        Err(_) => Outcome::Failure
+    };
+    audit::record_system(&project_id, AuditEvent::AccountPurged, outcome, Some(suid));
+}
+
+/*- Purge every account whose grace period has passed.
+    Runs forever, so call it from its own thread -*/
+pub(crate) fn purge_loop() -> () {
+    loop {
+        let collection:Collection<User> = utils::establish_mclient::<User>("users");
+        let now:i64 = utils::get_unix_epoch_time() as i64;
+
+        /*- Get expired users -*/
+        if let Ok(cursor) = collection.find(doc!{ "deletion_scheduled_at": { "$lte": now } }, None) {
+            for user in cursor.flatten() {
+                purge_user(&user.suid);
+            };
+        };
+
+        /*- Guests nobody has played as in a while -*/
+        guest::purge_stale();
+
+        thread::sleep(Duration::from_secs(PURGE_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_confirm_deletion_with_their_password() {
+        let user = User { password: utils::hash("hunter22"), ..User::default() };
+        assert!(deletion_confirmed(&user, Some("hunter22"), None));
+        assert!(!deletion_confirmed(&user, Some("wrong"), None));
+        assert!(!deletion_confirmed(&user, None, Some(&user.username)));
+    }
+
+    #[test]
+    fn guests_confirm_deletion_with_their_username() {
+        let guest = User { username: "guest_0123456789ab".to_string(), guest: true, ..User::default() };
+        assert!(deletion_confirmed(&guest, None, Some("guest_0123456789ab")));
+        assert!(!deletion_confirmed(&guest, None, Some("someone_else")));
+
+        /*- Their empty password isn't a way in -*/
+        assert!(!deletion_confirmed(&guest, Some(""), None));
+        assert!(!deletion_confirmed(&guest, None, None));
+    }
+}
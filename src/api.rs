@@ -11,6 +11,16 @@ use crate::{
     utils,
     utils::get_required_headers,
     safe_user::SafeUser,
+    profile::{ Viewer, Visibility },
+    audit::{ self, AuditEvent, Outcome },
+    session,
+    login_history, social, block, search,
+    webhook::WebhookEvent,
+    outbox, metrics,
+    project::{ self, Project },
+    avatar,
+    storage::{ self, Blob },
+    username::{ UsernamePolicy, skeleton },
 };
 use crate::dict::DICTIONARY;
 use responder::response::{ Respond, ResponseType };
@@ -30,6 +40,7 @@ use crate::user::{
     User, UserClaims, AuthorizationStatus,
     get_expiration_time, generate_uuid,
     generate_suid, authenticate, check_email,
+    EMAIL_INDEX,
 };
 use std::{
     io::{
@@ -42,6 +53,7 @@ use std::{
 };
 use mongodb::{
     bson::{ doc, Document },
+    options::{ FindOneAndUpdateOptions, ReturnDocument },
     sync::{
         Client,
         Collection,
@@ -69,8 +81,14 @@ pub(super) fn create_account(stream: &mut Stream) -> () {
         return stream.respond(400, do_json(400, "Invalid headers"));
     };
 
+    /*- Get the project the account is for -*/
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
     /*- Get the headers -*/
-    let user:User = match utils::get_headers_checked(
+    let mut user:User = match utils::get_headers_checked(
         &stream.headers,
         &["username", "displayname", "password", "email"]
     ) {
@@ -83,26 +101,55 @@ pub(super) fn create_account(stream: &mut Stream) -> () {
                 email: values[3].to_string(),
                 uid         : generate_uuid(),
                 suid        : generate_suid(),
+                project_id  : project.id.clone(),
+                username_skeleton: skeleton(values[0]),
+                ..User::default()
             }
         },
         None => return stream.respond(405, do_json(405, "Error parsing userdata"))
     };
-    dbg!(2);
+    search::index(&mut user);
 
     /*- If the email is invalid -*/
     if !check_email(&user.email) {
+        audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.email));
         return stream.respond(
             400u16,
             do_json(400, DICTIONARY.error.invalid.email)
         );
     };
 
+    /*- If the password breaks the projects' password policy -*/
+    if let Err(message) = project.check_password(stream.headers.get("password").unwrap_or(&"")) {
+        audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.username));
+        return stream.respond(
+            400u16,
+            do_json(400, &message)
+        );
+    };
+
+    /*- If the username breaks the username policy -*/
+    if let Err(err) = UsernamePolicy::configured().validate(&user.username) {
+        audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.username));
+        return stream.respond(
+            400u16,
+            do_json(400, &err.message())
+        );
+    };
+
     /*- Establish the mongodb connection -*/
     let collection:Collection<User> = utils::establish_mclient::<User>("users");
 
-    /*- Check if username already exists -*/
-    let username_exists = collection.find(doc!{"username": user.username.clone()}, None).unwrap().next().is_some();
+    /*- Check if username (or a look-alike of it) already exists -*/
+    let username_exists = collection.find(doc!{
+        "project_id": &project.id,
+        "$or": [
+            { "username": user.username.clone() },
+            { "username_skeleton": user.username_skeleton.clone() },
+        ]
+    }, None).unwrap().next().is_some();
     if username_exists {
+        audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.username));
         return stream.respond(
             409u16,
             do_json(409, DICTIONARY.error.in_use.username)
@@ -110,16 +157,36 @@ pub(super) fn create_account(stream: &mut Stream) -> () {
     };
     
     /*- Check if email already exists -*/
-    let email_exists = collection.find(doc!{"email": user.email.clone()}, None).unwrap().next().is_some();
+    let email_exists = collection.find(doc!{"project_id": &project.id, "email": user.email.clone()}, None).unwrap().next().is_some();
     if email_exists {
+        audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.email));
         return stream.respond(
             409u16,
             do_json(409, DICTIONARY.error.in_use.email)
         );
     };
     
-    /*- Insert the document -*/
-    collection.insert_one(&user, None).ok();
+    /*- Insert the document, and append it to the change feed -*/
+    match outbox::record(&project.id, WebhookEvent::AccountCreated, |users, session| {
+        users.insert_one_with_session(&user, None, session).map(|_| Some(user.clone()))
+    }) {
+        Ok(_) => (),
+
+        /*- The email or a look-alike username was registered since the checks above -*/
+        Err(err) if utils::is_duplicate_key_on(&err, EMAIL_INDEX) => {
+            audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.email));
+            return stream.respond(409, do_json(409, DICTIONARY.error.in_use.email));
+        },
+        Err(err) if utils::is_duplicate_key(&err) => {
+            audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.username));
+            return stream.respond(409, do_json(409, DICTIONARY.error.in_use.username));
+        },
+        Err(_) => {
+            audit::record(stream, AuditEvent::AccountCreated, Outcome::Failure, None, Some(&user.suid));
+            return stream.respond(500, do_json(500, "Internal server error"));
+        }
+    };
+    audit::record(stream, AuditEvent::AccountCreated, Outcome::Success, Some(&user.suid), Some(&user.suid));
 
     /*- Respond success -*/
     stream.respond(
@@ -128,7 +195,8 @@ pub(super) fn create_account(stream: &mut Stream) -> () {
     );
 }
 /*- Make quick json response -*/
-fn do_json(status: u16, message: &str) -> Respond {
+pub(crate) fn do_json(status: u16, message: &str) -> Respond {
+    metrics::record_status(status);
     let response = Respond::new().json(
         &format!(
             "{{\"status\": {}, \"message\": \"{}\"}}",
@@ -160,15 +228,22 @@ pub(super) fn login(stream: &mut Stream) -> () {
         (_, _) => return stream.respond(400, do_json(400, "Invalid headers")),
     };
 
+    /*- Get the project the account belongs to -*/
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
     /*- Establish the mongodb connection -*/
     let collection:Collection<User> = utils::establish_mclient::<User>("users");
 
     /*- Check if email exists -*/
-    let email_exists = match collection.find(doc!{"email": email.to_string()}, None) {
+    let email_exists = match collection.find(doc!{"project_id": &project.id, "email": email.to_string()}, None) {
         Ok(cursor) => cursor,
         Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
     }.next().is_some();
     if !email_exists {
+        audit::record(stream, AuditEvent::Login, Outcome::Failure, None, Some(&email));
         return stream.respond(
             404u16,
             do_json(404, DICTIONARY.error.invalid.email)
@@ -176,7 +251,7 @@ pub(super) fn login(stream: &mut Stream) -> () {
     };
 
     /*- Get the user -*/
-    let user = match match match collection.find(doc!{"email": email.to_string()}, None) {
+    let user = match match match collection.find(doc!{"project_id": &project.id, "email": email.to_string()}, None) {
         Ok(cursor) => cursor,
         Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
     }.next() {
@@ -189,14 +264,39 @@ pub(super) fn login(stream: &mut Stream) -> () {
 
     /*- Check if password is correct -*/
     if &user.password != &utils::hash(&password) {
+        audit::record(stream, AuditEvent::Login, Outcome::Failure, None, Some(&user.suid));
+        login_history::record(stream, &user.suid, None, false);
         return stream.respond(
             401u16,
             do_json(401, DICTIONARY.error.login)
         );
     };
 
+    /*- If the user has reported a login as not theirs -*/
+    if user.password_reset_required {
+        audit::record(stream, AuditEvent::Login, Outcome::Failure, None, Some(&user.suid));
+        login_history::record(stream, &user.suid, None, false);
+        return stream.respond(
+            403u16,
+            do_json(403, DICTIONARY.error.password_reset_required)
+        );
+    };
+
+    respond_with_token(stream, &user, &project);
+}
+
+/*- Start a session for a user who just proved who they are,
+    and respond with its token. Shared by every way to log in -*/
+pub(crate) fn respond_with_token(stream:&mut Stream, user:&User, project:&Project) -> () {
+    /*- Create a session for the token -*/
+    let device_name:Option<String> = stream.headers.get("device-name").map(|e| e.to_string());
+    let session = match session::create(stream, &user.suid, device_name) {
+        Some(e) => e,
+        None => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
     /*- Create the token -*/
-    let token = match User::generate_JWT(user.clone()) {
+    let token = match User::generate_JWT(user.clone(), &session.sid, project) {
         Ok(token) => token,
         Err(_)  => {
             return stream.respond(
@@ -206,7 +306,13 @@ pub(super) fn login(stream: &mut Stream) -> () {
         },
     };
 
+    /*- Alert the user if the device or network is new -*/
+    if login_history::record(stream, &user.suid, Some(&session.sid), true) {
+        login_history::alert_new_device(stream, user, &session.sid, project);
+    };
+
     /*- Respond with a account data -*/
+    audit::record(stream, AuditEvent::Login, Outcome::Success, Some(&user.suid), Some(&user.suid));
     stream.respond(
         200u16,
 
@@ -223,37 +329,46 @@ pub(super) fn login(stream: &mut Stream) -> () {
 pub(crate) fn check_jws_token(stream: &mut Stream) -> () {
     /*- Require some headers to be specified -*/
     if stream.expect_headers_ignore_caps(get_required_headers("check_jws_token")) { return; };
-    let token:&str = match stream.headers.get("token") {
+    let token:String = match stream.headers.get("token") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 401)
+    };
+
+    /*- Tokens are only valid for the project the request is for -*/
+    let project:Project = match project::require(stream) {
         Some(e) => e,
-        None => return stream.respond_status(401)
+        None => return
     };
 
     /*- Decode token -*/
-    let user_claims:UserClaims = match User::decode__JWT__token(token) {
+    let user_claims:UserClaims = match User::validate_JWT_token(&token, &project) {
         Ok(claims) => claims,
-        Err(_) => return stream.respond_status(401)
-    };
-
-    /*- Respond -*/
-    match User::decode__JWT__token(token) {
-        Ok(e) => {
-            stream.respond(200, Respond::new().json(
-                &format!("{{\"suid\":\"{}\"}}", e.suid)
-            ))
-        },
         Err(_) => {
-            stream.respond_status(401)
+            audit::record(stream, AuditEvent::TokenVerified, Outcome::Failure, None, None);
+            return utils::respond_status(stream, 401);
         }
     };
+
+    /*- Respond -*/
+    audit::record(stream, AuditEvent::TokenVerified, Outcome::Success, Some(&user_claims.suid), Some(&user_claims.suid));
+    stream.respond(200, Respond::new().json(
+        &format!("{{\"suid\":\"{}\"}}", user_claims.suid)
+    ));
 }
 
 /*- Get other user's profile -*/
 pub(crate) fn profile_data_suid(stream: &mut Stream) -> () {
     /*- No headers required, the requested users'
         suid is specified in the URL-params -*/
-    let request_suid:&str = match &stream.params.get("suid") {
+    let request_suid:String = match &stream.params.get("suid") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+
+    /*- Profiles are only visible within their project -*/
+    let project:Project = match project::require(stream) {
         Some(e) => e,
-        None => return stream.respond_status(410)
+        None => return
     };
 
     /*- Establish the mongodb connection -*/
@@ -262,39 +377,36 @@ pub(crate) fn profile_data_suid(stream: &mut Stream) -> () {
     /*- Check if the user exists -*/
     let user_exists = collection.find_one(
         doc!{
+            "project_id": &project.id,
             "suid": request_suid.to_string()
         }, None
     );
 
-    /*- Get the userdata or respond 404 if not available,
-        and convert the user to a SafeUser for safety -*/
-    let user_data:SafeUser = User::to_safe(match user_exists {
-        Ok(async_cursor) => {
-            match async_cursor {
-                Some(user_data) => user_data,
-                None => return stream.respond_status(404)
-            }
-        },
-        Err(_) => return stream.respond_status(404)
-    });
+    /*- Get the userdata or respond 404 if not available -*/
+    let user_data:User = match user_exists {
+        Ok(Some(user_data)) => user_data,
+        _ => {
+            audit::record(stream, AuditEvent::ProfileViewed, Outcome::Failure, None, Some(&request_suid));
+            return utils::respond_status(stream, 404);
+        }
+    };
+    audit::record(stream, AuditEvent::ProfileViewed, Outcome::Success, None, Some(&user_data.suid));
 
     /*- Respond with the userdata -*/
-    stream.respond(
-        200u16,
-        Respond::new()
-            .json(
-                &serde_json::to_string(
-                    &user_data
-                ).unwrap_or(String::new())
-            )
-    );
+    respond_with_profile(stream, &project, user_data);
 }
 pub(crate) fn profile_data_name(stream: &mut Stream) -> () {
     /*- No headers required, the requested users'
         username is specified in the URL-params -*/
-    let request_username:&str = match &stream.params.get("name") {
+    let request_username:String = match &stream.params.get("name") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+
+    /*- Profiles are only visible within their project -*/
+    let project:Project = match project::require(stream) {
         Some(e) => e,
-        None => return stream.respond_status(410)
+        None => return
     };
 
     /*- Establish the mongodb connection -*/
@@ -303,86 +415,162 @@ pub(crate) fn profile_data_name(stream: &mut Stream) -> () {
     /*- Check if the user exists -*/
     let user_exists = collection.find(
         doc!{
+            "project_id": &project.id,
             "username": request_username.to_string()
         }, None
     );
 
-    /*- Get the userdata or respond 404 if not available,
-        and convert the user to a SafeUser for safety  -*/
-    let user_data:SafeUser = User::to_safe(match user_exists {
+    /*- Get the userdata or respond 404 if not available -*/
+    let user_data:User = match user_exists {
         Ok(mut async_cursor) => {
             match async_cursor.next() {
-                Some(user_data) => match user_data {
-                    Ok(user_data) => user_data,
-                    Err(_) => return stream.respond_status(404)
-                },
-                None => return stream.respond_status(404)
+                Some(Ok(user_data)) => user_data,
+                _ => {
+                    audit::record(stream, AuditEvent::ProfileViewed, Outcome::Failure, None, Some(&request_username));
+                    return utils::respond_status(stream, 404);
+                }
             }
         },
-        Err(_) => return stream.respond_status(404)
-    });
+        Err(_) => {
+            audit::record(stream, AuditEvent::ProfileViewed, Outcome::Failure, None, Some(&request_username));
+            return utils::respond_status(stream, 404);
+        }
+    };
+    audit::record(stream, AuditEvent::ProfileViewed, Outcome::Success, None, Some(&user_data.suid));
 
     /*- Respond with the userdata -*/
-    stream.respond(
-        200u16,
-        Respond::new()
-            .json(
-                &serde_json::to_string(
-                    &user_data
-                ).unwrap_or(String::new())
-            )
-    );
+    respond_with_profile(stream, &project, user_data);
+}
+
+/*- Respond with a profile as the caller may see it, converted to
+    a SafeUser for safety, and how it relates to the caller if
+    they supplied a token. Anonymous callers see public parts only -*/
+fn respond_with_profile(stream:&mut Stream, project:&Project, user:User) -> () {
+    let viewer:Viewer = Viewer::from_headers(&project.id, &stream.headers);
+
+    /*- Blocked users can't see each other -*/
+    if let Some(caller) = &viewer.suid {
+        if block::is_blocked(&project.id, caller, &user.suid) {
+            return utils::respond_status(stream, 404);
+        };
+    };
+
+    let relationship = viewer.suid.clone()
+        .filter(|caller| caller != &user.suid)
+        .map(|caller| social::relationship(&project.id, &caller, &user.suid));
+    let user_data:SafeUser = User::to_safe_for(user, &project.settings.profile_schema, &viewer);
+    let mut body:serde_json::Value = match serde_json::to_value(&user_data) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    if let Some(relationship) = relationship {
+        body["relationship"] = serde_json::to_value(&relationship).unwrap_or_default();
+    };
+
+    stream.respond(200u16, Respond::new().json(&body.to_string()));
 }
 
 /*- Get a users profile image -*/
 pub(crate) fn profile_image(stream: &mut Stream,) -> () {
     
-    /*- Get the param named 'profile_image' -*/
-    let profile_image:&str = match &stream.params.get("profile_image") {
+    /*- Get the param named 'profile_image', the
+        requested users' suid and an optional ?size= -*/
+    let param:String = match &stream.params.get("profile_image") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+    let (suid, query) = utils::split_query(&param);
+
+    /*- Get the requested size, or the full size image -*/
+    let size:Option<u32> = match query.get("size") {
+        Some(size) => match size.parse::<u32>() {
+            Ok(size) if avatar::THUMBNAIL_SIZES.contains(&size) => Some(size),
+            _ => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.image_size))
+        },
+        None => None
+    };
+
+    /*- Profiles are only visible within their project -*/
+    let project:Project = match project::require(stream) {
         Some(e) => e,
-        None => return stream.respond_status(410)
+        None => return
     };
 
-    /*- Search for the image in the static/ dir -*/
-    let image_path:String  = format!("uploads/{}.jpg", profile_image);
+    /*- Get the users' uploaded avatar, fall back to their
+        identicon, and to the static default image if the
+        user doesn't exist or has blocked the caller. Callers
+        who may not see the avatar get the identicon, made from
+        the username if they may not see the displayname either -*/
     let pfp_not_found:&str = &"static/images/default-user.jpg";
+    let store = storage::store();
+    let viewer:Viewer = Viewer::from_headers(&project.id, &stream.headers);
+    let hidden:bool = match &viewer.suid {
+        Some(caller) => block::is_blocked(&project.id, caller, suid),
+        None => false
+    };
+    let (blob, restricted):(Option<Blob>, bool) = match utils::establish_mclient::<User>("users").find_one(doc!{ "project_id": &project.id, "suid": suid }, None) {
+        Ok(Some(user)) if !hidden => {
+            let name:&str = match viewer.can_see(&user, "displayname") {
+                true => &user.displayname,
+                false => &user.username
+            };
+            let blob:Option<Blob> = match user.avatar.as_ref().filter(|_| viewer.can_see(&user, "avatar")).and_then(|avatar| avatar.key_for(size)) {
+                Some(key) => store.get(key).ok().flatten(),
+                None => None
+            }.or_else(|| avatar::identicon(store.as_ref(), &user.suid, name, size).ok());
+            (blob, ["avatar", "displayname"].iter().any(|part| user.privacy.get(*part).map_or(false, |e| *e != Visibility::Everyone)))
+        },
+        _ => (None, false)
+    };
 
-    /*- Buffers -*/
-    let mut buf = Vec::new();
-    let file = std::fs::File::open(image_path);
-
-    /*- Error handling -*/
-    let mut file = match file {
-        Ok(file) => file,
-        Err(_) => match std::fs::File::open(pfp_not_found) {
-            Ok(file) => file,
-            Err(_) => return stream.respond_status(404u16)
+    /*- Error handling. The default image has no meaningful
+        modification time, so it's only cached by its ETag -*/
+    let (content_type, buf, last_modified):(&str, Vec<u8>, Option<u64>) = match blob {
+        Some(blob) => (avatar::AVATAR_CONTENT_TYPE, blob.bytes, Some(blob.modified)),
+        None => match std::fs::read(pfp_not_found) {
+            Ok(buf) => (avatar::content_type(pfp_not_found), buf, None),
+            Err(_) => return utils::respond_status(stream, 404u16)
         }
     };
-    
-    file.read_to_end(&mut buf).unwrap_or_default();
-    
-    /*- Encode -*/
-    let mut encoded = Vec::new();
-    {
-        let mut encoder = Encoder::with_chunks_size(&mut encoded, 64);
-        encoder.write_all(&buf).unwrap_or_default();
-    }
-
-    /*- Create the response -*/
-    let headers = [
-        "HTTP/1.1 200 OK",
-        "Content-type: image/png",
-        "Transfer-Encoding: chunked",
-        "\r\n"
+
+    /*- What the caller gets depends on who they are when they
+        sent credentials (friends, blocks) or the owner restricts
+        parts of their profile, so shared caches mustn't keep it -*/
+    let credentials:bool = ["Authorization", "X-Api-Key"].iter()
+        .any(|header| utils::get_header_ignore_caps(&stream.headers, header).is_some());
+    let personal:bool = credentials || hidden || restricted;
+
+    /*- Caching -*/
+    let etag:String = format!("\"{}\"", utils::hash_bytes(&buf));
+    let mut headers:Vec<(&str, String)> = vec![
+        ("Content-Type",  content_type.to_string()),
+        ("ETag",          etag.clone()),
+        ("Cache-Control", match personal {
+            true => "private, max-age=300, must-revalidate",
+            false => "public, max-age=300, must-revalidate"
+        }.to_string()),
+        ("Vary",          "Authorization, X-Api-Key".to_string()),
     ];
-    let mut response = headers.join("\r\n")
-        .to_string()
-        .into_bytes();
-        response.extend(encoded);
+    if let Some(last_modified) = last_modified {
+        headers.push(("Last-Modified", utils::http_date(last_modified)));
+    };
 
-    /*- Respond with the image -*/
-    stream.get_mut_inner_ref().write(&response).unwrap_or_default();
+    /*- If-None-Match takes precedence over If-Modified-Since -*/
+    let not_modified:bool = match utils::get_header_ignore_caps(&stream.headers, "If-None-Match") {
+        Some(tags) => tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"),
+        None => match (utils::get_header_ignore_caps(&stream.headers, "If-Modified-Since"), last_modified) {
+            (Some(date), Some(last_modified)) => utils::parse_http_date(date).map_or(false, |since| last_modified <= since),
+            _ => false
+        }
+    };
+
+    /*- Respond with the image. Not audited, since images are
+        fetched anonymously on every page that shows a user -*/
+    if not_modified {
+        utils::respond_bytes(stream, "304 Not Modified", &headers, &[]);
+    } else {
+        utils::respond_bytes(stream, "200 OK", &headers, &buf);
+    };
 }
 
 /*- Upload profile picture -*/
@@ -393,33 +581,59 @@ pub(crate) fn upload_profile_image(stream: &mut Stream) -> () {
         .unwrap_or(&&"")
         .to_string();
 
+    /*- Tokens are only valid for the project the request is for -*/
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
     /*- Get the user from the token -*/
-    let u_claims:UserClaims = match User::decode__JWT__token(token) {
-        Ok(e) => e,
-        Err(_) => return stream.respond_status(401u16)
+    let u_claims:UserClaims = match User::validate_JWT_token(token, &project) {
+        Ok(e) if e.scope.is_none() => e,
+        _ => {
+            audit::record(stream, AuditEvent::ProfileImageUploaded, Outcome::Failure, None, None);
+            return utils::respond_status(stream, 401u16);
+        }
     };
 
-    /*- Get the user suid (We'll name the image the users suid) -*/
+    /*- Get the user suid -*/
     let suid:&str = &u_claims.suid;
 
-    /*- Binary image in body -*/
-    let image:&str = &&stream.body;
-    println!("{image:?}");
-
-    /*- Create the image path -*/
-    let image_path:String = format!("uploads/{}.png", suid);
-
-    /*- Create the file -*/
-    let mut file = match std::fs::File::create(image_path) {
-        Ok(file) => file,
-        Err(_) => return stream.respond_status(500u16)
+    /*- Decode, validate and re-encode the uploaded
+        image, in full size and every thumbnail size -*/
+    let renditions = match avatar::renditions(&avatar::body_bytes(&stream.body)) {
+        Ok(e) => e,
+        Err(err) => {
+            audit::record(stream, AuditEvent::ProfileImageUploaded, Outcome::Failure, Some(suid), Some(suid));
+            return stream.respond(err.status(), err.to_response());
+        }
     };
 
-    /*- Write the image to the file -*/
-    file.write_all(image.as_bytes()).unwrap_or_default();
+    /*- Store every rendition and point the user to them.
+        The previous avatars' blobs are garbage collected -*/
+    let avatar_ref = match avatar::store_renditions(storage::store().as_ref(), renditions) {
+        Ok(e) => e,
+        Err(_) => return utils::respond_status(stream, 500u16)
+    };
+    let avatar_ref = match mongodb::bson::to_bson(&avatar_ref) {
+        Ok(e) => e,
+        Err(_) => return utils::respond_status(stream, 500u16)
+    };
+    match outbox::record(&project.id, WebhookEvent::AvatarUploaded, |users, session| {
+        users.find_one_and_update_with_session(
+            doc!{ "suid": suid },
+            doc!{ "$set": { "avatar": avatar_ref.clone() } },
+            FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            session
+        )
+    }) {
+        Ok(Some(_)) => (),
+        _ => return utils::respond_status(stream, 500u16)
+    };
 
     /*- Respond with a success message -*/
-    stream.respond_status(200u16);
+    audit::record(stream, AuditEvent::ProfileImageUploaded, Outcome::Success, Some(suid), Some(suid));
+    utils::respond_status(stream, 200u16);
 }
 
 
@@ -0,0 +1,338 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    api::do_json,
+    audit::{ self, AuditEvent, Outcome },
+    dict::DICTIONARY,
+    project::{ self, Project },
+    user::{ User, UserClaims, AuthorizationStatus, authenticate, is_admin, generate_suid },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use rand::{ self, Rng, distributions::Alphanumeric };
+use mongodb::{
+    bson::doc,
+    options::FindOptions,
+    sync::Collection,
+};
+use std::collections::HashMap;
+
+/*- Constants -*/
+const API_KEY_COLLECTION:&'static str = "api_keys";
+
+/*- Every key starts with this, so that keys can be
+    told apart from JWTs and found by secret scanners -*/
+pub(crate) const KEY_PREFIX:&'static str = "crk_";
+const LOOKUP_PREFIX_LEN:usize = 12;
+const SECRET_LEN:usize = 40;
+
+/*- Scopes keys can be minted with. `*` is the same
+    access as a token from `login` -*/
+pub(crate) const SUPPORTED_SCOPES:&[&str] = &["*", "openid", "profile", "email", "scores", "changes"];
+
+/*- Only write last_used_at once per this many seconds -*/
+const LAST_USED_RESOLUTION:u64 = 60;
+
+/// # API key
+/// A long-lived credential for servers. Keys are owned
+/// by a user, or by a project when `owner` is none. Only
+/// the lookup prefix and a hash of the full key are kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ApiKey {
+    pub id          : String,
+    pub project_id  : String,
+    pub owner       : Option<String>,
+    pub name        : String,
+    pub prefix      : String,
+    pub hash        : String,
+    pub scopes      : Vec<String>,
+    pub created_at  : u64,
+    pub created_by  : String,
+    pub last_used_at: Option<u64>,
+    pub expires_at  : Option<u64>,
+    pub revoked_at  : Option<u64>,
+}
+
+/*- Quick way of getting the key collection -*/
+fn collection() -> Collection<ApiKey> {
+    utils::establish_mclient::<ApiKey>(API_KEY_COLLECTION)
+}
+
+/*- A random alphanumeric string -*/
+fn random_string(len:usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/*- Split a key into its lookup prefix. The full
+    key is `crk_<prefix>_<secret>` -*/
+fn lookup_prefix(key:&str) -> Option<&str> {
+    let rest:&str = key.strip_prefix(KEY_PREFIX)?;
+    let (prefix, secret) = rest.split_once('_')?;
+    if prefix.len() != LOOKUP_PREFIX_LEN || secret.len() != SECRET_LEN { return None; };
+    Some(prefix)
+}
+
+/*- Public fields of a key -*/
+fn describe(key:&ApiKey) -> serde_json::Value {
+    serde_json::json!({
+        "id"          : key.id,
+        "name"        : key.name,
+        "prefix"      : format!("{}{}", KEY_PREFIX, key.prefix),
+        "owner"       : key.owner,
+        "scopes"      : key.scopes,
+        "created_at"  : key.created_at,
+        "last_used_at": key.last_used_at,
+        "expires_at"  : key.expires_at,
+    })
+}
+
+/*- Check an API key for a project. On success returns claims
+    like the ones of a JWT. Keys with the `*` scope have full
+    access, other keys only pass `authenticate_scoped` -*/
+pub(crate) fn authenticate_key(key:&str, project:&Project) -> AuthorizationStatus {
+    let prefix:&str = match lookup_prefix(key) {
+        Some(e) => e,
+        None => return AuthorizationStatus::Unauthorized
+    };
+    let now:u64 = utils::get_unix_epoch_time();
+    let stored:ApiKey = match collection().find_one(doc!{
+        "prefix": prefix,
+        "project_id": &project.id,
+        "revoked_at": null
+    }, None) {
+        Ok(Some(e)) => e,
+        Ok(None) => return AuthorizationStatus::Unauthorized,
+        Err(_) => return AuthorizationStatus::Err
+    };
+    if stored.hash != utils::hash_bytes(key.as_bytes()) { return AuthorizationStatus::Unauthorized; };
+    if stored.expires_at.map_or(false, |expires_at| expires_at <= now) { return AuthorizationStatus::Unauthorized; };
+
+    /*- Keys of users act as that user -*/
+    let (username, uid, suid) = match &stored.owner {
+        Some(owner) => {
+            let users:Collection<User> = utils::establish_mclient::<User>("users");
+            match users.find_one(doc!{ "suid": owner, "project_id": &project.id }, None) {
+                Ok(Some(user)) => (user.username, user.uid, user.suid),
+                Ok(None) => return AuthorizationStatus::Unauthorized,
+                Err(_) => return AuthorizationStatus::Err
+            }
+        },
+        None => (project.id.clone(), String::new(), String::new())
+    };
+
+    /*- Mark as used -*/
+    collection().update_one(
+        doc!{ "id": &stored.id, "$or": [
+            { "last_used_at": null },
+            { "last_used_at": { "$lt": (now - LAST_USED_RESOLUTION) as i64 } }
+        ] },
+        doc!{ "$set": { "last_used_at": now as i64 } },
+        None
+    ).ok();
+
+    let scope:Option<String> = match stored.scopes.iter().any(|scope| scope == "*") {
+        true => None,
+        false => Some(stored.scopes.join(" "))
+    };
+    AuthorizationStatus::Authorized(UserClaims {
+        username,
+        uid,
+        suid,
+        exp      : stored.expires_at.unwrap_or(0) as usize,
+        iat      : stored.created_at as usize,
+        sid      : String::new(),
+        aud      : project.token_audience.clone(),
+        scope,
+        client_id: None,
+        api_key  : Some(stored.id),
+    })
+}
+
+/*- Revoke every key a user owns -*/
+pub(crate) fn revoke_all(suid:&str) -> () {
+    collection().update_many(
+        doc!{ "owner": suid, "revoked_at": null },
+        doc!{ "$set": { "revoked_at": utils::get_unix_epoch_time() as i64 } },
+        None
+    ).ok();
+}
+
+/*- Every key a user owns, without their hashes. Used by exports -*/
+pub(crate) fn all_of(suid:&str) -> Option<Vec<ApiKey>> {
+    let keys:Vec<ApiKey> = utils::find_all(&collection(), doc!{ "owner": suid })?;
+    Some(keys.into_iter().map(|key| ApiKey { hash: String::new(), ..key }).collect())
+}
+
+/*- Remove every key a user owns. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    collection().delete_many(doc!{ "owner": suid }, None).ok();
+}
+
+/*- Get the callers' claims. Keys can't be used to
+    manage keys, or a leaked key could outlive its
+    own revocation -*/
+fn authorized_claims(stream:&mut Stream) -> Option<UserClaims> {
+    match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if claims.api_key.is_none() => Some(claims),
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        }
+    }
+}
+
+/*- Whether the request is about the projects' own
+    keys, which only its admins may manage -*/
+fn for_project(stream:&Stream) -> bool {
+    matches!(stream.headers.get("project-key"), Some(&"true"))
+}
+
+/*- Mint a key. The key itself is only ever returned here -*/
+pub(crate) fn create(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authorized_claims(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let name:String = match stream.headers.get("name") {
+        Some(e) if !e.trim().is_empty() && e.len() <= 64 => e.trim().to_string(),
+        _ => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+
+    /*- Project keys are for the projects' admins, and must
+        be scoped since they don't act as any one user -*/
+    let owner:Option<String> = match for_project(stream) {
+        true if !is_admin(&claims.suid) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        true => None,
+        false => Some(claims.suid.clone())
+    };
+    let scopes:Vec<String> = match stream.headers.get("scopes") {
+        Some(scopes) => scopes.split(' ').filter(|e| !e.is_empty()).map(String::from).collect(),
+        None => Vec::new()
+    };
+    if scopes.is_empty()
+        || !scopes.iter().all(|scope| SUPPORTED_SCOPES.contains(&scope.as_str()))
+        || (owner.is_none() && scopes.iter().any(|scope| scope == "*")) {
+        return stream.respond(400, do_json(400, DICTIONARY.error.invalid.api_key_scope));
+    };
+
+    /*- Optional lifetime in seconds -*/
+    let now:u64 = utils::get_unix_epoch_time();
+    let expires_at:Option<u64> = match stream.headers.get("expires-in") {
+        Some(seconds) => match seconds.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Some(now + seconds),
+            _ => return stream.respond(400, do_json(400, "Invalid headers"))
+        },
+        None => None
+    };
+
+    let prefix:String = random_string(LOOKUP_PREFIX_LEN);
+    let secret:String = format!("{}{}_{}", KEY_PREFIX, prefix, random_string(SECRET_LEN));
+    let key = ApiKey {
+        id          : generate_suid(),
+        project_id  : project.id.clone(),
+        owner,
+        name,
+        hash        : utils::hash_bytes(secret.as_bytes()),
+        prefix,
+        scopes,
+        created_at  : now,
+        created_by  : claims.suid.clone(),
+        last_used_at: None,
+        expires_at,
+        revoked_at  : None,
+    };
+    if collection().insert_one(&key, None).is_err() {
+        return stream.respond(500, do_json(500, "Internal server error"));
+    };
+    audit::record(stream, AuditEvent::ApiKeyCreated, Outcome::Success, Some(&claims.suid), Some(&key.id));
+
+    let mut body:serde_json::Value = describe(&key);
+    body["key"] = serde_json::Value::String(secret);
+    stream.respond(200, Respond::new().json(&body.to_string()));
+}
+
+/*- List the callers' keys, or the projects' keys -*/
+pub(crate) fn list(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authorized_claims(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let filter = match for_project(stream) {
+        true if !is_admin(&claims.suid) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        true => doc!{ "project_id": &project.id, "owner": null, "revoked_at": null },
+        false => doc!{ "project_id": &project.id, "owner": &claims.suid, "revoked_at": null },
+    };
+
+    let options = FindOptions::builder().sort(doc!{ "created_at": -1 }).build();
+    let keys:Vec<serde_json::Value> = match collection().find(filter, options) {
+        Ok(cursor) => cursor.flatten().map(|key| describe(&key)).collect(),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    stream.respond(
+        200u16,
+        Respond::new()
+            .json(
+                &serde_json::to_string(
+                    &keys
+                ).unwrap_or(String::new())
+            )
+    );
+}
+
+/*- Revoke one of the callers' keys, or one of the projects' keys -*/
+pub(crate) fn revoke(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authorized_claims(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let id:String = match stream.params.get("id") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+    let filter = match for_project(stream) {
+        true if !is_admin(&claims.suid) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        true => doc!{ "id": &id, "project_id": &project.id, "owner": null, "revoked_at": null },
+        false => doc!{ "id": &id, "project_id": &project.id, "owner": &claims.suid, "revoked_at": null },
+    };
+
+    match collection().update_one(
+        filter,
+        doc!{ "$set": { "revoked_at": utils::get_unix_epoch_time() as i64 } },
+        None
+    ) {
+        Ok(result) if result.modified_count == 1 => {
+            audit::record(stream, AuditEvent::ApiKeyRevoked, Outcome::Success, Some(&claims.suid), Some(&id));
+            stream.respond(200, do_json(200, "Success!"));
+        },
+        Ok(_) => {
+            audit::record(stream, AuditEvent::ApiKeyRevoked, Outcome::Failure, Some(&claims.suid), Some(&id));
+            stream.respond(404, do_json(404, DICTIONARY.error.not_found.api_key));
+        },
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
@@ -0,0 +1,330 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, metrics,
+    api::do_json,
+    dict::DICTIONARY,
+    user::{ AuthorizationStatus, authenticate, is_admin },
+    project::{ self, DEFAULT_PROJECT_ID },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use std::{ env, net::IpAddr, sync::OnceLock };
+use mongodb::{
+    bson::{ doc, Document },
+    options::FindOptions,
+    sync::Collection,
+};
+
+/*- Constants -*/
+const AUDIT_COLLECTION:&'static str = "audit_log";
+const DEFAULT_QUERY_LIMIT:i64       = 100;
+const MAX_QUERY_LIMIT:i64           = 1000;
+
+/*- Comma separated addresses or networks (as in "10.0.0.0/8")
+    of reverse proxies whose X-Forwarded-For is believed -*/
+const TRUSTED_PROXIES_VAR:&'static str = "TRUSTED_PROXIES";
+
+/// # AuditEntry
+/// One security-relevant event. Entries are only
+/// ever inserted, never updated or deleted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AuditEntry {
+    pub timestamp : u64,
+    pub project_id: Option<String>,
+    pub event     : AuditEvent,
+    pub outcome   : Outcome,
+
+    /*- Suid of whoever performed the action, if known -*/
+    pub actor     : Option<String>,
+
+    /*- Suid, username or email the action was performed on -*/
+    pub target    : Option<String>,
+    pub ip        : Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/*- Kinds of events -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditEvent {
+    AccountCreated,
+    Login,
+    TokenVerified,
+    ProfileViewed,
+
+    /*- No longer recorded, kept so that older entries still read -*/
+    ProfileImageViewed,
+    ProfileImageUploaded,
+    AccountExported,
+    AccountDeletionScheduled,
+    AccountDeletionCancelled,
+    AccountPurged,
+    DebugAccountsListed,
+    DebugAccountDeleted,
+    AuditLogQueried,
+    SessionRevoked,
+    AccountSecured,
+    PasswordChanged,
+    OAuthClientRegistered,
+    OAuthAuthorized,
+    OAuthTokenIssued,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    MagicLinkSent,
+    GuestCreated,
+    GuestUpgraded,
+    WebhookCreated,
+    WebhookDeleted,
+    WebhookRedelivered,
+}
+
+/*- Whether the action succeeded -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    Success,
+    Failure,
+}
+
+/*- If an address is, or is in, an address or network -*/
+fn in_network(ip:&IpAddr, network:&str) -> bool {
+    let (address, prefix) = match network.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse::<u32>().ok()),
+        None => (network, None)
+    };
+    match (ip, address.trim().parse::<IpAddr>()) {
+        (IpAddr::V4(ip), Ok(IpAddr::V4(address))) => {
+            let prefix:u32 = prefix.unwrap_or(32).min(32);
+            let mask:u32 = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(address) & mask
+        },
+        (IpAddr::V6(ip), Ok(IpAddr::V6(address))) => {
+            let prefix:u32 = prefix.unwrap_or(128).min(128);
+            let mask:u128 = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(*ip) & mask == u128::from(address) & mask
+        },
+        _ => false
+    }
+}
+
+/*- The reverse proxies set in TRUSTED_PROXIES -*/
+fn trusted_proxies() -> &'static [String] {
+    static PROXIES:OnceLock<Vec<String>> = OnceLock::new();
+    PROXIES.get_or_init(|| env::var(TRUSTED_PROXIES_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect())
+}
+
+/*- The client behind a chain of proxies. X-Forwarded-For is
+    only believed when it was passed along by trusted proxies,
+    and is read from the right, so that addresses the client
+    put in the header itself are never taken -*/
+fn forwarded_client(peer:IpAddr, forwarded:Option<&str>, trusted:&[String]) -> IpAddr {
+    let is_trusted = |ip:&IpAddr| trusted.iter().any(|network| in_network(ip, network));
+    if !is_trusted(&peer) { return peer; };
+
+    let mut client:IpAddr = peer;
+    for hop in forwarded.unwrap_or("").rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !is_trusted(&ip) { break; };
+            },
+            Err(_) => break
+        };
+    };
+    client
+}
+
+/*- Get the clients' IP. Behind the reverse proxies set
+    in TRUSTED_PROXIES, the one they passed along -*/
+pub(crate) fn client_ip(stream:&mut Stream) -> Option<String> {
+    let peer:IpAddr = stream.get_mut_inner_ref().peer_addr().ok()?.ip();
+    let forwarded:Option<&str> = utils::get_header_ignore_caps(&stream.headers, "X-Forwarded-For");
+    Some(forwarded_client(peer, forwarded, trusted_proxies()).to_string())
+}
+
+/*- Append an entry to the audit log. Failing to write
+    the audit log never fails the request itself -*/
+pub(crate) fn record(stream:&mut Stream, event:AuditEvent, outcome:Outcome, actor:Option<&str>, target:Option<&str>) -> () {
+    let entry = AuditEntry {
+        timestamp : utils::get_unix_epoch_time(),
+        project_id: Some(project::requested_id(&stream.headers).unwrap_or(DEFAULT_PROJECT_ID.to_string())),
+        event,
+        outcome,
+        actor     : actor.map(String::from),
+        target    : target.map(String::from),
+        ip        : client_ip(stream),
+        user_agent: utils::get_header_ignore_caps(&stream.headers, "User-Agent").map(String::from),
+    };
+
+    append(&entry);
+}
+
+/*- Append an entry which didn't originate from a request.
+    Entries are still recorded under the project they
+    concern, or admins couldn't query them -*/
+pub(crate) fn record_system(project_id:&str, event:AuditEvent, outcome:Outcome, target:Option<&str>) -> () {
+    append(&AuditEntry {
+        timestamp : utils::get_unix_epoch_time(),
+        project_id: Some(project_id.to_string()),
+        event,
+        outcome,
+        actor     : None,
+        target    : target.map(String::from),
+        ip        : None,
+        user_agent: None,
+    });
+}
+
+/*- Insert an entry -*/
+fn append(entry:&AuditEntry) -> () {
+    metrics::audit(entry.event, entry.outcome);
+    utils::establish_mclient::<AuditEntry>(AUDIT_COLLECTION).insert_one(entry, None).ok();
+}
+
+/*- Build a mongo filter from the request headers.
+    Supported filters: event, outcome, actor, target,
+    ip, since and until (unix time) -*/
+fn filter_from_headers(stream:&Stream, project_id:&str) -> Document {
+    let mut filter:Document = doc!{ "project_id": project_id };
+
+    for key in ["event", "outcome", "actor", "target", "ip"] {
+        if let Some(value) = stream.headers.get(key) {
+            filter.insert(key, value.to_string());
+        };
+    };
+
+    /*- Time range -*/
+    let mut range:Document = doc!{};
+    if let Some(since) = stream.headers.get("since").and_then(|e| e.parse::<i64>().ok()) {
+        range.insert("$gte", since);
+    };
+    if let Some(until) = stream.headers.get("until").and_then(|e| e.parse::<i64>().ok()) {
+        range.insert("$lte", until);
+    };
+    if !range.is_empty() {
+        filter.insert("timestamp", range);
+    };
+
+    filter
+}
+
+/*- Get entries matching the filters, newest first -*/
+fn query(stream:&mut Stream) -> Option<Vec<AuditEntry>> {
+    /*- Admins only, and only of their own project -*/
+    let (suid, project_id) = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if is_admin(&claims.suid) => (
+            claims.suid,
+            project::requested_id(&stream.headers).unwrap_or(DEFAULT_PROJECT_ID.to_string())
+        ),
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            return None;
+        }
+    };
+    record(stream, AuditEvent::AuditLogQueried, Outcome::Success, Some(&suid), None);
+
+    let limit:i64 = stream.headers.get("limit")
+        .and_then(|e| e.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .clamp(1, MAX_QUERY_LIMIT);
+    let options = FindOptions::builder()
+        .sort(doc!{ "timestamp": -1 })
+        .limit(limit)
+        .build();
+
+    match utils::establish_mclient::<AuditEntry>(AUDIT_COLLECTION).find(filter_from_headers(stream, &project_id), options) {
+        Ok(cursor) => Some(cursor.flatten().collect()),
+        Err(_) => {
+            stream.respond(500, do_json(500, "Internal server error"));
+            None
+        }
+    }
+}
+
+/*- Query the audit log as a JSON array -*/
+pub(crate) fn get_entries(stream: &mut Stream) -> () {
+    let entries:Vec<AuditEntry> = match query(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    stream.respond(
+        200u16,
+        Respond::new()
+            .json(
+                &serde_json::to_string(
+                    &entries
+                ).unwrap_or(String::new())
+            )
+    );
+}
+
+/*- Export the audit log as JSON Lines -*/
+pub(crate) fn export_entries(stream: &mut Stream) -> () {
+    let entries:Vec<AuditEntry> = match query(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    let body:String = entries.iter()
+        .filter_map(|entry| serde_json::to_string(entry).ok())
+        .map(|line| line + "\n")
+        .collect();
+
+    utils::respond_bytes(
+        stream, "200 OK",
+        &[("Content-Type", "application/x-ndjson".to_string())],
+        body.as_bytes()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip:&str) -> IpAddr { ip.parse().unwrap() }
+    fn proxies() -> Vec<String> { vec!["10.0.0.0/8".to_string(), "::1".to_string()] }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        assert_eq!(forwarded_client(ip("203.0.113.7"), Some("1.2.3.4"), &proxies()), ip("203.0.113.7"));
+        assert_eq!(forwarded_client(ip("203.0.113.7"), Some("1.2.3.4"), &[]), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn forwarded_for_is_read_from_the_right() {
+        /*- The client sent "1.2.3.4" itself, the proxy appended the real address -*/
+        assert_eq!(forwarded_client(ip("10.0.0.2"), Some("1.2.3.4, 198.51.100.9"), &proxies()), ip("198.51.100.9"));
+        assert_eq!(forwarded_client(ip("10.0.0.2"), Some("198.51.100.9, 10.1.2.3"), &proxies()), ip("198.51.100.9"));
+        assert_eq!(forwarded_client(ip("::1"), Some("2001:db8::1"), &proxies()), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn trusted_peers_without_forwarded_for_are_the_client() {
+        assert_eq!(forwarded_client(ip("10.0.0.2"), None, &proxies()), ip("10.0.0.2"));
+        assert_eq!(forwarded_client(ip("10.0.0.2"), Some("garbage"), &proxies()), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn networks() {
+        assert!(in_network(&ip("10.200.3.4"), "10.0.0.0/8"));
+        assert!(!in_network(&ip("11.0.0.1"), "10.0.0.0/8"));
+        assert!(in_network(&ip("192.168.1.1"), "192.168.1.1"));
+        assert!(in_network(&ip("1.2.3.4"), "0.0.0.0/0"));
+        assert!(in_network(&ip("2001:db8::5"), "2001:db8::/32"));
+        assert!(!in_network(&ip("2001:db8::5"), "10.0.0.0/8"));
+    }
+}
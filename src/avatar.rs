@@ -0,0 +1,274 @@
+/*- Imports -*/
+use crate::{
+    dict::DICTIONARY,
+    storage::{ self, AvatarRef, Blob, BlobStore, StorageError, Thumbnail },
+    metrics,
+};
+use responder::response::Respond;
+use base64;
+use image::{
+    self, io::Reader,
+    imageops::FilterType,
+    DynamicImage, ImageFormat, ImageOutputFormat,
+    Rgb, RgbImage,
+};
+use sha3::{ Digest, Sha3_256 };
+use std::io::Cursor;
+
+/*- Constants -*/
+pub(crate) const MAX_UPLOAD_BYTES:usize    = 5 * 1024 * 1024;
+pub(crate) const MAX_UPLOAD_DIMENSION:u32  = 4096;
+pub(crate) const AVATAR_SIZE:u32           = 512;
+pub(crate) const AVATAR_EXTENSION:&'static str    = "png";
+pub(crate) const AVATAR_CONTENT_TYPE:&'static str = "image/png";
+pub(crate) const THUMBNAIL_SIZES:&'static [u32]   = &[32, 64, 128, 256];
+const IDENTICON_GRID:u32                          = 5;
+const IDENTICON_BACKGROUND:Rgb<u8>                = Rgb([240, 240, 240]);
+const ACCEPTED_FORMATS:&'static [ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Bmp,
+];
+
+/*- Why an upload couldn't be used as an avatar -*/
+#[derive(Debug, PartialEq)]
+pub(crate) enum AvatarError {
+    Empty,
+    TooLarge,
+    UnsupportedFormat,
+    DimensionsTooLarge,
+    Undecodable,
+    Encode,
+}
+
+/*- Function implementations -*/
+impl AvatarError {
+
+    /*- Machine-readable error code -*/
+    pub fn code(&self) -> &'static str {
+        match self {
+            AvatarError::Empty              => "empty",
+            AvatarError::TooLarge           => "too_large",
+            AvatarError::UnsupportedFormat  => "unsupported_format",
+            AvatarError::DimensionsTooLarge => "dimensions_too_large",
+            AvatarError::Undecodable        => "undecodable",
+            AvatarError::Encode             => "encode",
+        }
+    }
+
+    /*- The status we respond with -*/
+    pub fn status(&self) -> u16 {
+        match self {
+            AvatarError::Empty              => 400,
+            AvatarError::TooLarge           => 413,
+            AvatarError::UnsupportedFormat  => 415,
+            AvatarError::DimensionsTooLarge => 422,
+            AvatarError::Undecodable        => 422,
+            AvatarError::Encode             => 500,
+        }
+    }
+
+    /*- Human-readable message -*/
+    pub fn message(&self) -> String {
+        match self {
+            AvatarError::Empty              => DICTIONARY.error.image.empty.to_string(),
+            AvatarError::TooLarge           => DICTIONARY.error.image.too_large.replace("{}", &(MAX_UPLOAD_BYTES / 1024 / 1024).to_string()),
+            AvatarError::UnsupportedFormat  => DICTIONARY.error.image.unsupported_format.to_string(),
+            AvatarError::DimensionsTooLarge => DICTIONARY.error.image.dimensions.replace("{}", &MAX_UPLOAD_DIMENSION.to_string()),
+            AvatarError::Undecodable        => DICTIONARY.error.image.undecodable.to_string(),
+            AvatarError::Encode             => "Internal server error".to_string(),
+        }
+    }
+
+    /*- Structured JSON response -*/
+    pub fn to_response(&self) -> Respond {
+        metrics::record_status(self.status());
+        Respond::new().json(&format!(
+            "{{\"status\": {}, \"error\": \"{}\", \"message\": \"{}\"}}",
+            self.status(), self.code(), self.message()
+        ))
+    }
+}
+
+/*- The request body is a string, so binary uploads get mangled.
+    Clients send the image base64 encoded (optionally as a data
+    URL), raw bytes are only accepted as a fallback -*/
+pub(crate) fn body_bytes(body:&str) -> Vec<u8> {
+    let trimmed:&str = body.trim();
+
+    /*- Strip "data:image/png;base64," -*/
+    let encoded:&str = match trimmed.split_once(";base64,") {
+        Some((_, data)) => data,
+        None => trimmed
+    };
+
+    match base64::decode(encoded) {
+        Ok(bytes) => bytes,
+        Err(_) => body.as_bytes().to_vec()
+    }
+}
+
+/*- Decode an upload, check it, center-crop it to a square
+    and return the decoded image. Re-encoding the decoded
+    pixels drops any metadata (EXIF, GPS, comments) -*/
+pub(crate) fn decode_upload(bytes:&[u8]) -> Result<DynamicImage, AvatarError> {
+    if bytes.is_empty() { return Err(AvatarError::Empty); };
+    if bytes.len() > MAX_UPLOAD_BYTES { return Err(AvatarError::TooLarge); };
+
+    /*- Check the format from the magic bytes, never from the client -*/
+    let format:ImageFormat = match image::guess_format(bytes) {
+        Ok(format) => format,
+        Err(_) => return Err(AvatarError::UnsupportedFormat)
+    };
+    if !ACCEPTED_FORMATS.contains(&format) {
+        return Err(AvatarError::UnsupportedFormat);
+    };
+
+    /*- Check dimensions before decoding the whole image -*/
+    let (width, height) = match Reader::with_format(Cursor::new(bytes), format).into_dimensions() {
+        Ok(e) => e,
+        Err(_) => return Err(AvatarError::Undecodable)
+    };
+    if width > MAX_UPLOAD_DIMENSION || height > MAX_UPLOAD_DIMENSION {
+        return Err(AvatarError::DimensionsTooLarge);
+    };
+    if width == 0 || height == 0 {
+        return Err(AvatarError::Undecodable);
+    };
+
+    /*- Decode -*/
+    let image:DynamicImage = match image::load_from_memory_with_format(bytes, format) {
+        Ok(e) => e,
+        Err(_) => return Err(AvatarError::Undecodable)
+    };
+
+    Ok(center_crop(image))
+}
+
+/*- Crop the largest centered square out of an image -*/
+pub(crate) fn center_crop(image:DynamicImage) -> DynamicImage {
+    let side:u32 = image.width().min(image.height());
+    let x:u32 = (image.width() - side) / 2;
+    let y:u32 = (image.height() - side) / 2;
+
+    image.crop_imm(x, y, side, side)
+}
+
+/*- Resize a square image and encode it to the canonical format -*/
+pub(crate) fn encode(image:&DynamicImage, size:u32) -> Result<Vec<u8>, AvatarError> {
+    let resized:DynamicImage = image.resize_exact(size, size, FilterType::Lanczos3);
+    let mut buf:Vec<u8> = Vec::new();
+
+    match resized.write_to(&mut Cursor::new(&mut buf), ImageOutputFormat::Png) {
+        Ok(_) => Ok(buf),
+        Err(_) => Err(AvatarError::Encode)
+    }
+}
+
+/*- Turn any upload into the full size avatar and every
+    thumbnail. `None` is the full size rendition -*/
+pub(crate) fn renditions(bytes:&[u8]) -> Result<Vec<(Option<u32>, Vec<u8>)>, AvatarError> {
+    let image:DynamicImage = decode_upload(bytes)?;
+    let mut renditions = Vec::with_capacity(THUMBNAIL_SIZES.len() + 1);
+
+    renditions.push((None, encode(&image, AVATAR_SIZE.min(image.width()))?));
+    for size in THUMBNAIL_SIZES {
+        renditions.push((Some(*size), encode(&image, *size)?));
+    };
+
+    Ok(renditions)
+}
+
+/*- Store every rendition content-addressed, and return
+    the reference to be saved on the user record -*/
+pub(crate) fn store_renditions(store:&dyn BlobStore, renditions:Vec<(Option<u32>, Vec<u8>)>) -> Result<AvatarRef, StorageError> {
+    let mut avatar:AvatarRef = AvatarRef {
+        key        : String::new(),
+        thumbnails : Vec::with_capacity(THUMBNAIL_SIZES.len()),
+        uploaded_at: crate::utils::get_unix_epoch_time(),
+    };
+
+    for (size, bytes) in renditions {
+        let key:String = storage::content_key(storage::AVATAR_PREFIX, &bytes, AVATAR_EXTENSION);
+        store.put(&key, &bytes, AVATAR_CONTENT_TYPE)?;
+
+        match size {
+            Some(size) => avatar.thumbnails.push(Thumbnail { size, key }),
+            None => avatar.key = key
+        };
+    };
+
+    Ok(avatar)
+}
+
+/*- Content type from a file extension -*/
+pub(crate) fn content_type(path:&str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or("") {
+        "png"          => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif"          => "image/gif",
+        "webp"         => "image/webp",
+        _              => "application/octet-stream",
+    }
+}
+
+/*- Render a deterministic identicon. The suid decides
+    the pattern and the displayname the color, so the
+    avatar changes if the user renames themselves -*/
+pub(crate) fn render_identicon(suid:&str, displayname:&str, size:u32) -> RgbImage {
+    let pattern = Sha3_256::digest(suid.as_bytes());
+    let tint = Sha3_256::digest(displayname.as_bytes());
+
+    /*- Keep the color saturated enough to stand out from the background -*/
+    let color:Rgb<u8> = Rgb([
+        64 + tint[0] % 160,
+        64 + tint[1] % 160,
+        64 + tint[2] % 160,
+    ]);
+
+    /*- A 5x5 grid, mirrored around the middle column,
+        with half a cell of padding around it -*/
+    let cell:u32 = (size / (IDENTICON_GRID + 1)).max(1);
+    let padding:u32 = (size - cell * IDENTICON_GRID) / 2;
+    let half:u32 = (IDENTICON_GRID + 1) / 2;
+
+    RgbImage::from_fn(size, size, |x, y| {
+        if x < padding || y < padding { return IDENTICON_BACKGROUND; };
+        let (column, row) = ((x - padding) / cell, (y - padding) / cell);
+        if column >= IDENTICON_GRID || row >= IDENTICON_GRID { return IDENTICON_BACKGROUND; };
+
+        /*- Mirror the right half onto the left half -*/
+        let column:u32 = if column >= half { IDENTICON_GRID - 1 - column } else { column };
+        let bit:usize = (row * half + column) as usize;
+
+        if pattern[bit] % 2 == 0 { color } else { IDENTICON_BACKGROUND }
+    })
+}
+
+/*- The key of an identicon up to its size. Every
+    size of a users' identicon shares it -*/
+pub(crate) fn identicon_stem(suid:&str, displayname:&str) -> String {
+    format!("{}{}", storage::IDENTICON_PREFIX, crate::utils::hash(&format!("{}:{}", suid, displayname)))
+}
+
+/*- Get the identicon for a user, rendering and caching it
+    in the blob store on first request -*/
+pub(crate) fn identicon(store:&dyn BlobStore, suid:&str, displayname:&str, size:Option<u32>) -> Result<Blob, AvatarError> {
+    let size:u32 = size.unwrap_or(AVATAR_SIZE);
+    let key:String = format!("{}_{}.{}", identicon_stem(suid, displayname), size, AVATAR_EXTENSION);
+
+    /*- Cached -*/
+    if let Ok(Some(blob)) = store.get(&key) { return Ok(blob); };
+
+    /*- Render and cache -*/
+    let image:DynamicImage = DynamicImage::ImageRgb8(render_identicon(suid, displayname, size));
+    let mut bytes:Vec<u8> = Vec::new();
+    if image.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png).is_err() {
+        return Err(AvatarError::Encode);
+    };
+    store.put(&key, &bytes, AVATAR_CONTENT_TYPE).ok();
+
+    Ok(Blob { bytes, modified: crate::utils::get_unix_epoch_time() })
+}
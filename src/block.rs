@@ -0,0 +1,182 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, social,
+    api::do_json,
+    dict::DICTIONARY,
+    project::Project,
+    user::UserClaims,
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use mongodb::{
+    bson::doc,
+    options::{ FindOptions, UpdateOptions },
+    sync::Collection,
+};
+
+/*- Constants -*/
+const BLOCK_COLLECTION:&'static str = "blocks";
+const DEFAULT_PAGE_SIZE:i64         = 50;
+const MAX_PAGE_SIZE:i64             = 200;
+
+/*- Blocks hide two users from each other entirely. Mutes
+    are one-way and silent, the muted user isn't told and
+    can still see the user who muted them -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BlockKind {
+    Block,
+    Mute,
+}
+
+/// # Block
+/// A block or mute of `target` by `suid`. A user has
+/// at most one of either for every other user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Block {
+    pub project_id: String,
+    pub suid      : String,
+    pub target    : String,
+    pub kind      : BlockKind,
+    pub since     : u64,
+}
+
+/*- Quick way of getting the block collection -*/
+fn collection() -> Collection<Block> {
+    utils::establish_mclient::<Block>(BLOCK_COLLECTION)
+}
+
+/*- If either user has blocked the other -*/
+pub(crate) fn is_blocked(project_id:&str, a:&str, b:&str) -> bool {
+    matches!(collection().find_one(doc!{
+        "project_id": project_id,
+        "kind": "block",
+        "$or": [
+            { "suid": a, "target": b },
+            { "suid": b, "target": a },
+        ]
+    }, None), Ok(Some(_)))
+}
+
+/*- Everyone who has blocked, or was blocked by, a user.
+    None of them should ever be shown to the user -*/
+pub(crate) fn hidden_from(project_id:&str, suid:&str) -> Vec<String> {
+    match collection().find(doc!{
+        "project_id": project_id,
+        "kind": "block",
+        "$or": [ { "suid": suid }, { "target": suid } ]
+    }, None) {
+        Ok(cursor) => cursor
+            .flatten()
+            .map(|block| if block.suid == suid { block.target } else { block.suid })
+            .collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/*- Everyone a user has muted -*/
+pub(crate) fn muted_by(project_id:&str, suid:&str) -> Vec<String> {
+    match collection().find(doc!{ "project_id": project_id, "suid": suid, "kind": "mute" }, None) {
+        Ok(cursor) => cursor.flatten().map(|block| block.target).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/*- Everyone who has muted a user -*/
+pub(crate) fn muters_of(project_id:&str, suid:&str) -> Vec<String> {
+    match collection().find(doc!{ "project_id": project_id, "target": suid, "kind": "mute" }, None) {
+        Ok(cursor) => cursor.flatten().map(|block| block.suid).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/*- Block or mute, replacing any earlier block or mute -*/
+fn set(stream:&mut Stream, kind:BlockKind) -> () {
+    let (project, claims) = match social::caller(stream) { Some(e) => e, None => return };
+    let target:String = match social::target_user(stream, &project, &claims) { Some(e) => e, None => return };
+
+    match collection().update_one(
+        doc!{ "project_id": &project.id, "suid": &claims.suid, "target": &target },
+        doc!{ "$set": {
+            "kind" : mongodb::bson::to_bson(&kind).unwrap_or_default(),
+            "since": utils::get_unix_epoch_time() as i64,
+        } },
+        UpdateOptions::builder().upsert(true).build()
+    ) {
+        Ok(_) => (),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- Blocked users stop being friends, followers
+        and stop having friend requests between them -*/
+    if kind == BlockKind::Block {
+        social::sever(&project.id, &claims.suid, &target);
+    };
+    stream.respond(200, do_json(200, "Success!"));
+}
+
+/*- Remove a block or mute -*/
+fn unset(stream:&mut Stream, kind:BlockKind) -> () {
+    let (project, claims) = match social::caller(stream) { Some(e) => e, None => return };
+    let target:String = match social::target_user(stream, &project, &claims) { Some(e) => e, None => return };
+
+    match collection().delete_one(doc!{
+        "project_id": &project.id,
+        "suid": &claims.suid,
+        "target": &target,
+        "kind": mongodb::bson::to_bson(&kind).unwrap_or_default()
+    }, None) {
+        Ok(result) if result.deleted_count == 1 => stream.respond(200, do_json(200, "Success!")),
+        Ok(_) => stream.respond(404, do_json(404, DICTIONARY.error.not_found.block)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- List the users the caller has blocked or muted -*/
+fn list(stream:&mut Stream, kind:BlockKind) -> () {
+    let (project, claims) = match social::caller(stream) { Some(e) => e, None => return };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    let options = FindOptions::builder()
+        .sort(doc!{ "since": -1 })
+        .skip(offset)
+        .limit(limit + 1)
+        .build();
+    match collection().find(doc!{
+        "project_id": &project.id,
+        "suid": &claims.suid,
+        "kind": mongodb::bson::to_bson(&kind).unwrap_or_default()
+    }, options) {
+        Ok(cursor) => {
+            let entries:Vec<(String, u64)> = cursor.flatten().map(|block| (block.target, block.since)).collect();
+            social::respond_page(stream, &project.id, &claims.suid, entries, offset, limit);
+        },
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Handlers -*/
+pub(crate) fn block(stream: &mut Stream) -> () { set(stream, BlockKind::Block) }
+pub(crate) fn unblock(stream: &mut Stream) -> () { unset(stream, BlockKind::Block) }
+pub(crate) fn blocked(stream: &mut Stream) -> () { list(stream, BlockKind::Block) }
+pub(crate) fn mute(stream: &mut Stream) -> () { set(stream, BlockKind::Mute) }
+pub(crate) fn unmute(stream: &mut Stream) -> () { unset(stream, BlockKind::Mute) }
+pub(crate) fn muted(stream: &mut Stream) -> () { list(stream, BlockKind::Mute) }
+
+/*- Everyone a user blocked or muted. Used by exports -*/
+pub(crate) fn all_of(suid:&str) -> Option<Vec<Block>> {
+    utils::find_all(&collection(), doc!{ "suid": suid })
+}
+
+/*- Remove everything a user is part of. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    collection().delete_many(doc!{ "$or": [ { "suid": suid }, { "target": suid } ] }, None).ok();
+}
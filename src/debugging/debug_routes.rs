@@ -1,5 +1,11 @@
 /*- Imports -*/
-use crate::utils;
+use crate::{
+    utils,
+    audit::{ self, AuditEvent, Outcome },
+    project::{ self, DEFAULT_PROJECT_ID },
+    outbox,
+    webhook::WebhookEvent,
+};
 use responder::{response::{ Respond, ResponseType }, Stream};
 use serde_json;
 use crate::user::User;
@@ -18,10 +24,12 @@ pub(crate) fn get_all_accounts(
     /*- Establish the mongodb connection -*/
     let collection:Collection<User> = utils::establish_mclient::<User>("users");
 
-    /*- Get users -*/
-    let users:Vec<User> = collection.find(None, None).unwrap().map(|user| user.unwrap()).collect();
+    /*- Get the projects' users -*/
+    let project_id:String = project::requested_id(&stream.headers).unwrap_or(DEFAULT_PROJECT_ID.to_string());
+    let users:Vec<User> = collection.find(doc!{ "project_id": &project_id }, None).unwrap().map(|user| user.unwrap()).collect();
     
     /*- Respond with the userdata -*/
+    audit::record(stream, AuditEvent::DebugAccountsListed, Outcome::Success, None, None);
     stream.respond(
         200u16,
         Respond::new()
@@ -37,24 +45,39 @@ pub(crate) fn delete_account(
 ) -> () {
     /*- Establish the mongodb connection -*/
     let collection:Collection<User> = utils::establish_mclient::<User>("users");
-    let suid:&str = match stream.params.get("suid") {
-        Some(e) => e,
-        None => return stream.respond_status(404)
+    let suid:String = match stream.params.get("suid") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 404)
     };
+    let project_id:String = project::requested_id(&stream.headers).unwrap_or(DEFAULT_PROJECT_ID.to_string());
+
+    /*- Deletions go to the change feed, so that
+        read models drop the users as well -*/
+    let delete = |suid:&str| outbox::record(&project_id, WebhookEvent::AccountDeleted, |users, session| {
+        users.find_one_and_delete_with_session(doc!{ "project_id": &project_id, "suid": suid }, None, session)
+    });
 
     if suid == "all" {
         /*- Delete users -*/
-        stream.respond_status(200);
-        collection.delete_many(doc! {}, None).ok();
-        return;
+        let suids:Vec<String> = match collection.find(doc!{ "project_id": &project_id }, None) {
+            Ok(cursor) => cursor.flatten().map(|user| user.suid).collect(),
+            Err(_) => return utils::respond_status(stream, 500)
+        };
+        let deleted:usize = suids.iter().filter(|suid| matches!(delete(suid), Ok(Some(_)))).count();
+        audit::record(stream, AuditEvent::DebugAccountDeleted, Outcome::Success, None, Some(&suid));
+        return stream.respond(200, Respond::new().text(&format!("Deleted {}", deleted)));
     };
 
     /*- Delete users -*/
-    match collection.delete_one(doc!{
-        "suid": suid
-    }, None) {
-        Ok(e) => stream.respond(200, Respond::new().text(&format!("Deleted {}", e.deleted_count))),
-        Err(_) => stream.respond_status(404)
+    match delete(&suid) {
+        Ok(deleted) => {
+            audit::record(stream, AuditEvent::DebugAccountDeleted, Outcome::Success, None, Some(&suid));
+            stream.respond(200, Respond::new().text(&format!("Deleted {}", deleted.map_or(0, |_| 1))))
+        },
+        Err(_) => {
+            audit::record(stream, AuditEvent::DebugAccountDeleted, Outcome::Failure, None, Some(&suid));
+            utils::respond_status(stream, 404)
+        }
     }
 }
 
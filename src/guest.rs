@@ -0,0 +1,201 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, session, account, search, profile, outbox,
+    webhook::WebhookEvent,
+    api::{ do_json, respond_with_token },
+    audit::{ self, AuditEvent, Outcome },
+    dict::DICTIONARY,
+    project::{ self, Project },
+    username::{ UsernamePolicy, skeleton },
+    user::{ User, UserClaims, AuthorizationStatus, authenticate, generate_uuid, generate_suid, check_email, EMAIL_INDEX },
+};
+use responder::prelude::*;
+use mongodb::{
+    bson::{ doc, Document },
+    options::{ FindOneAndUpdateOptions, ReturnDocument },
+    sync::Collection,
+};
+
+/*- Constants -*/
+
+/*- Guests who haven't used any of their sessions
+    in this many seconds are purged -*/
+const GUEST_LIFETIME:u64 = 60*60*24*30;
+
+/*- Create a guest account, and respond with the
+    same token `login` would -*/
+pub(crate) fn create(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    /*- Guests have no credentials, an empty password
+        hash never matches the hash of any password -*/
+    let suid:String = generate_suid();
+    let username:String = format!("guest_{}", &suid[..12]);
+    let displayname:String = format!("Guest {}", &suid[..6].to_uppercase());
+    let mut user = User {
+        displayname,
+        username_skeleton: skeleton(&username),
+        username,
+        password    : String::new(),
+        email       : String::new(),
+        uid         : generate_uuid(),
+        suid,
+        project_id  : project.id.clone(),
+        guest       : true,
+        ..User::default()
+    };
+    search::index(&mut user);
+
+    if outbox::record(&project.id, WebhookEvent::AccountCreated, |users, session| {
+        users.insert_one_with_session(&user, None, session).map(|_| Some(user.clone()))
+    }).is_err() {
+        audit::record(stream, AuditEvent::GuestCreated, Outcome::Failure, None, Some(&user.suid));
+        return stream.respond(500, do_json(500, "Internal server error"));
+    };
+    audit::record(stream, AuditEvent::GuestCreated, Outcome::Success, Some(&user.suid), Some(&user.suid));
+
+    respond_with_token(stream, &user, &project);
+}
+
+/*- Turn the callers' guest account into a full account.
+    The suid stays the same, so everything tied to it stays -*/
+pub(crate) fn upgrade(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let (email, password) = match utils::get_headers_checked(&stream.headers, &["email", "password"]) {
+        Some(values) => (values[0].trim().to_string(), values[1].to_string()),
+        None => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    let user:User = match collection.find_one(doc!{ "suid": &claims.suid, "project_id": &project.id }, None) {
+        Ok(Some(e)) => e,
+        Ok(None) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    if !user.guest {
+        return stream.respond(409, do_json(409, DICTIONARY.error.not_guest));
+    };
+
+    /*- Same checks as `create_account` -*/
+    if !check_email(&email) {
+        return stream.respond(400, do_json(400, DICTIONARY.error.invalid.email));
+    };
+    if let Err(message) = project.check_password(&password) {
+        return stream.respond(400, do_json(400, &message));
+    };
+
+    /*- Guests may pick a username and displayname,
+        or keep the generated ones -*/
+    let username:String = match stream.headers.get("username") {
+        Some(username) => {
+            if let Err(err) = UsernamePolicy::configured().validate(username) {
+                return stream.respond(400, do_json(400, &err.message()));
+            };
+            username.to_string()
+        },
+        None => user.username.clone()
+    };
+    let displayname:String = match stream.headers.get("displayname") {
+        Some(e) => e.to_string(),
+        None => user.displayname.clone()
+    };
+    let username_skeleton:String = skeleton(&username);
+
+    let taken = collection.find_one(doc!{
+        "project_id": &project.id,
+        "suid": { "$ne": &user.suid },
+        "$or": [
+            { "username": &username },
+            { "username_skeleton": &username_skeleton },
+        ]
+    }, None);
+    match taken {
+        Ok(Some(_)) => return stream.respond(409, do_json(409, DICTIONARY.error.in_use.username)),
+        Ok(None) => (),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    match collection.find_one(doc!{ "project_id": &project.id, "email": &email }, None) {
+        Ok(Some(_)) => return stream.respond(409, do_json(409, DICTIONARY.error.in_use.email)),
+        Ok(None) => (),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- Only upgrade if still a guest, in case of two upgrades at once -*/
+    let mut update = doc!{
+        "username"         : &username,
+        "username_skeleton": &username_skeleton,
+        "displayname"      : &displayname,
+        "email"            : &email,
+        "password"         : utils::hash(&password),
+        "guest"            : false,
+    };
+    update.extend(search::index_doc(&username, profile::searchable_displayname(&user.privacy, &displayname)));
+    match outbox::record(&project.id, WebhookEvent::AccountUpdated, |users, session| {
+        users.find_one_and_update_with_session(
+            doc!{ "suid": &user.suid, "guest": true },
+            doc!{ "$set": update.clone() },
+            FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            session
+        )
+    }) {
+        Ok(Some(_)) => {
+            audit::record(stream, AuditEvent::GuestUpgraded, Outcome::Success, Some(&user.suid), Some(&user.suid));
+            stream.respond(200, do_json(200, "Success!"));
+        },
+        Ok(None) => stream.respond(409, do_json(409, DICTIONARY.error.not_guest)),
+        Err(err) if utils::is_duplicate_key_on(&err, EMAIL_INDEX) => stream.respond(409, do_json(409, DICTIONARY.error.in_use.email)),
+        Err(err) if utils::is_duplicate_key(&err) => stream.respond(409, do_json(409, DICTIONARY.error.in_use.username)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Purge guests who haven't been seen in a while. Which
+    ones those are is found in one query, by looking up a
+    recently seen session for each guest -*/
+pub(crate) fn purge_stale() -> () {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    let cutoff:u64 = utils::get_unix_epoch_time().saturating_sub(GUEST_LIFETIME);
+
+    let stale = collection.aggregate(vec![
+        doc!{ "$match": { "guest": true } },
+        doc!{ "$lookup": {
+            "from": session::SESSION_COLLECTION,
+            "let": { "suid": "$suid" },
+            "pipeline": [
+                { "$match": { "$expr": { "$and": [
+                    { "$eq": [ "$suid", "$$suid" ] },
+                    { "$gte": [ "$last_seen", cutoff as i64 ] },
+                ] } } },
+                { "$limit": 1 },
+            ],
+            "as": "recent_sessions",
+        } },
+        doc!{ "$match": { "recent_sessions": { "$size": 0 } } },
+        doc!{ "$project": { "_id": 0, "suid": 1 } },
+    ], None);
+
+    if let Ok(cursor) = stale {
+        for guest in cursor.flatten() {
+            if let Ok(suid) = guest.get_str("suid") {
+                account::purge_user(suid);
+            };
+        };
+    };
+}
@@ -0,0 +1,199 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, social, block,
+    api::do_json,
+    dict::DICTIONARY,
+    project::{ self, Project },
+    profile::Viewer,
+    user::{ User, UserClaims, AuthorizationStatus, authenticate, authenticate_scoped },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use mongodb::{
+    bson::{ doc, Document },
+    options::{ FindOptions, UpdateOptions },
+    sync::Collection,
+};
+
+/*- Constants -*/
+const SCORE_COLLECTION:&'static str = "scores";
+const DEFAULT_PAGE_SIZE:i64         = 50;
+const MAX_PAGE_SIZE:i64             = 200;
+
+/// # Score
+/// A users' best score on one board. Boards are
+/// created by submitting to them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Score {
+    pub project_id: String,
+    pub board     : String,
+    pub suid      : String,
+    pub score     : i64,
+    pub updated_at: u64,
+}
+
+/*- Quick way of getting the score collection -*/
+fn collection() -> Collection<Score> {
+    utils::establish_mclient::<Score>(SCORE_COLLECTION)
+}
+
+/*- Get the board from the URL-params -*/
+fn board(stream:&mut Stream) -> Option<String> {
+    match stream.params.get("board") {
+        Some(board) if !board.is_empty() && board.len() <= 64 => Some(board.to_string()),
+        _ => {
+            utils::respond_status(stream, 410);
+            None
+        }
+    }
+}
+
+/*- Respond with a page of ranked scores. Scores of users who
+    hide their ratings from the viewer are left out, but still
+    count towards the ranks of everyone else -*/
+fn respond_ranked(stream:&mut Stream, project:&Project, viewer:&Viewer, filter:Document, offset:u64, limit:i64) -> () {
+    let options = FindOptions::builder()
+        .sort(doc!{ "score": -1, "updated_at": 1 })
+        .skip(offset)
+        .limit(limit + 1)
+        .build();
+    let mut scores:Vec<Score> = match collection().find(filter, options) {
+        Ok(cursor) => cursor.flatten().collect(),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    let next:Option<u64> = match scores.len() as i64 > limit {
+        true => Some(offset + limit as u64),
+        false => None
+    };
+    scores.truncate(limit as usize);
+
+    let suids:Vec<String> = scores.iter().map(|score| score.suid.clone()).collect();
+    let mut users:Vec<User> = social::users(&project.id, &suids);
+    let items:Vec<serde_json::Value> = scores.iter()
+        .enumerate()
+        .filter_map(|(index, score)| {
+            let position:usize = users.iter().position(|user| user.suid == score.suid)?;
+            let user:User = users.swap_remove(position);
+            if !viewer.can_see(&user, "ratings") { return None; };
+            Some(serde_json::json!({
+                "rank" : offset + index as u64 + 1,
+                "score": score.score,
+                "user" : User::to_safe_for(user, &project.settings.profile_schema, viewer),
+            }))
+        })
+        .collect();
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "items" : items,
+        "offset": offset,
+        "limit" : limit,
+        "next"  : next,
+    }).to_string()));
+}
+
+/*- Submit a score. Only the best score per user is kept.
+    Project API keys may submit for any user of the
+    project by naming them in the `suid` header -*/
+pub(crate) fn submit(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authenticate_scoped(stream.headers.clone(), "scores") {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let board:String = match board(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let score:i64 = match stream.headers.get("score").and_then(|e| e.parse().ok()) {
+        Some(e) => e,
+        None => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    let suid:String = match (claims.suid.is_empty(), stream.headers.get("suid")) {
+        (false, _) => claims.suid.clone(),
+        (true, Some(suid)) => suid.to_string(),
+        (true, None) => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    if social::users(&project.id, &[suid.clone()]).is_empty() {
+        return stream.respond(404, do_json(404, DICTIONARY.error.not_found.user));
+    };
+
+    match collection().update_one(
+        doc!{ "project_id": &project.id, "board": &board, "suid": &suid },
+        doc!{
+            "$max": { "score": score },
+            "$set": { "updated_at": utils::get_unix_epoch_time() as i64 },
+        },
+        UpdateOptions::builder().upsert(true).build()
+    ) {
+        Ok(_) => stream.respond(200, do_json(200, "Success!")),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- The top scores of a board -*/
+pub(crate) fn top(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let board:String = match board(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    /*- Callers who supply a token don't see users they're blocked with -*/
+    let viewer:Viewer = Viewer::from_headers(&project.id, &stream.headers);
+    let mut filter:Document = doc!{ "project_id": &project.id, "board": &board };
+    if let Some(caller) = &viewer.suid {
+        filter.insert("suid", doc!{ "$nin": block::hidden_from(&project.id, caller) });
+    };
+    respond_ranked(stream, &project, &viewer, filter, offset, limit);
+}
+
+/*- The scores of the caller and their friends -*/
+pub(crate) fn friends(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if !claims.suid.is_empty() => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let board:String = match board(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    let viewer:Viewer = Viewer::of(&project.id, Some(claims.suid.clone()));
+    let mut suids:Vec<String> = viewer.friends.iter().cloned().collect();
+    suids.push(claims.suid.clone());
+    respond_ranked(
+        stream, &project, &viewer,
+        doc!{ "project_id": &project.id, "board": &board, "suid": { "$in": suids } },
+        offset, limit
+    );
+}
+
+/*- A users' scores on every board. Used by exports -*/
+pub(crate) fn all_of(suid:&str) -> Option<Vec<Score>> {
+    utils::find_all(&collection(), doc!{ "suid": suid })
+}
+
+/*- Remove a users' scores. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    collection().delete_many(doc!{ "suid": suid }, None).ok();
+}
@@ -0,0 +1,334 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, api_key,
+    api::do_json,
+    audit::{ self, AuditEvent, Outcome, client_ip },
+    dict::DICTIONARY,
+    mail::{ self, Mail },
+    session::{ self, DeviceInfo, parse_user_agent },
+    user::{ User, UserClaims, PurposeClaims, AuthorizationStatus, authenticate },
+    project::Project,
+    webhook::WebhookEvent,
+    outbox,
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use std::net::IpAddr;
+use mongodb::{
+    bson::{ doc, Document },
+    options::{ FindOptions, FindOneAndUpdateOptions, ReturnDocument },
+    sync::Collection,
+};
+
+/*- Constants -*/
+const HISTORY_COLLECTION:&'static str = "login_history";
+const HISTORY_LIMIT:i64               = 100;
+const TOKEN_COLLECTION:&'static str   = "account_tokens";
+const SECURE_LINK_LIFETIME:u64        = 60*60*24;
+const PASSWORD_RESET_LIFETIME:u64     = 60*30;
+const SECURE_PAGE:&'static str        = "static/secure.html";
+
+/// # LoginAttempt
+/// One sign-in attempt on an existing account.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LoginAttempt {
+    pub suid      : String,
+    pub timestamp : u64,
+    pub success   : bool,
+    pub sid       : Option<String>,
+    pub ip        : Option<String>,
+
+    /*- IPv4 /24 or IPv6 /48 the ip belongs to -*/
+    pub network   : Option<String>,
+    pub user_agent: Option<String>,
+    pub device    : DeviceInfo,
+}
+
+/*- Quick way of getting the history collection -*/
+fn collection() -> Collection<LoginAttempt> {
+    utils::establish_mclient::<LoginAttempt>(HISTORY_COLLECTION)
+}
+
+/// # AccountToken
+/// An issued secure link or password reset token. The token
+/// itself is a signed purpose token, only its hash is stored,
+/// to keep track of whether it has been used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AccountToken {
+    token_hash: String,
+    purpose   : String,
+    suid      : String,
+    created_at: u64,
+    expires_at: u64,
+    used      : bool,
+}
+
+/*- Issue a single-use purpose token -*/
+fn issue_token(suid:&str, sid:Option<&str>, purpose:&str, lifetime:u64, project:&Project) -> Option<String> {
+    let token:String = User::generate_purpose_token(suid, sid, purpose, lifetime, project).ok()?;
+    let now:u64 = utils::get_unix_epoch_time();
+    utils::establish_mclient::<AccountToken>(TOKEN_COLLECTION).insert_one(&AccountToken {
+        token_hash: utils::hash(&token),
+        purpose   : purpose.to_string(),
+        suid      : suid.to_string(),
+        created_at: now,
+        expires_at: now + lifetime,
+        used      : false,
+    }, None).ok()?;
+    Some(token)
+}
+
+/*- Use a token, atomically so it can't be used twice. Returns
+    None if it isn't one, has expired, or was already used -*/
+fn consume_token(token:&str, purpose:&str) -> Result<Option<(PurposeClaims, Project)>, ()> {
+    let (claims, project) = match User::decode_purpose_token(token, purpose) {
+        Ok(e) => e,
+        Err(_) => return Ok(None)
+    };
+    match utils::establish_mclient::<AccountToken>(TOKEN_COLLECTION).find_one_and_update(
+        doc!{ "token_hash": utils::hash(token), "purpose": purpose, "suid": &claims.suid, "used": false },
+        doc!{ "$set": { "used": true } },
+        None
+    ) {
+        Ok(Some(_)) => Ok(Some((claims, project))),
+        Ok(None) => Ok(None),
+        Err(_) => Err(())
+    }
+}
+
+/*- A users' login history. Used by exports -*/
+pub(crate) fn all_of(suid:&str) -> Option<Vec<LoginAttempt>> {
+    utils::find_all(&collection(), doc!{ "suid": suid })
+}
+
+/*- Remove a users' login history and account tokens. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    collection().delete_many(doc!{ "suid": suid }, None).ok();
+    utils::establish_mclient::<AccountToken>(TOKEN_COLLECTION).delete_many(doc!{ "suid": suid }, None).ok();
+}
+
+/*- The network an IP belongs to. Home connections often get
+    new addresses within the same network, so comparing whole
+    addresses would make every login look new -*/
+pub(crate) fn network_of(ip:&str) -> Option<String> {
+    match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            Some(format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2]))
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            Some(format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2]))
+        }
+    }
+}
+
+/*- Record a login attempt. On success, returns whether the
+    device or network hadn't been used by the account before -*/
+pub(crate) fn record(stream:&mut Stream, suid:&str, sid:Option<&str>, success:bool) -> bool {
+    let ip:Option<String> = client_ip(stream);
+    let user_agent:Option<String> = utils::get_header_ignore_caps(&stream.headers, "User-Agent").map(String::from);
+    let attempt = LoginAttempt {
+        suid      : suid.to_string(),
+        timestamp : utils::get_unix_epoch_time(),
+        success,
+        sid       : sid.map(String::from),
+        network   : ip.as_deref().and_then(network_of),
+        ip,
+        device    : parse_user_agent(user_agent.as_deref().unwrap_or("")),
+        user_agent,
+    };
+
+    /*- Compare against earlier successful logins before inserting -*/
+    let new_device:bool = success && is_unfamiliar(&attempt);
+    collection().insert_one(&attempt, None).ok();
+
+    new_device
+}
+
+/*- If an attempt came from a device or network the account
+    hasn't successfully logged in from. The very first
+    login of an account is never unfamiliar -*/
+fn is_unfamiliar(attempt:&LoginAttempt) -> bool {
+    let collection = collection();
+    let previous = |filter:Document| matches!(collection.find_one(filter, None), Ok(Some(_)));
+
+    if !previous(doc!{ "suid": &attempt.suid, "success": true }) {
+        return false;
+    };
+
+    let device = match mongodb::bson::to_bson(&attempt.device) {
+        Ok(e) => e,
+        Err(_) => return false
+    };
+    let known_device:bool = previous(doc!{ "suid": &attempt.suid, "success": true, "device": device });
+    let known_network:bool = match &attempt.network {
+        Some(network) => previous(doc!{ "suid": &attempt.suid, "success": true, "network": network }),
+        None => true
+    };
+
+    !(known_device && known_network)
+}
+
+/*- Mail the user about a login from a new device or network,
+    with a link that revokes the session and forces a reset -*/
+pub(crate) fn alert_new_device(stream:&mut Stream, user:&User, sid:&str, project:&Project) -> () {
+    /*- Guests have no email to alert -*/
+    if user.guest { return; };
+    let token:String = match issue_token(&user.suid, Some(sid), "secure", SECURE_LINK_LIFETIME, project) {
+        Some(e) => e,
+        None => return
+    };
+    let user_agent:&str = utils::get_header_ignore_caps(&stream.headers, "User-Agent").unwrap_or("Unknown");
+    let device:DeviceInfo = parse_user_agent(user_agent);
+    let ip:String = client_ip(stream).unwrap_or("an unknown address".to_string());
+
+    mail::transport().send(&Mail {
+        to     : user.email.clone(),
+        subject: DICTIONARY.mail.new_device_subject.to_string(),
+        body   : DICTIONARY.mail.new_device_body
+            .replacen("{}", &user.displayname, 1)
+            .replacen("{}", &format!("{} on {} ({})", device.browser, device.os, device.device_type), 1)
+            .replacen("{}", &ip, 1)
+            .replacen("{}", &format!("{}/account/secure/{}", mail::public_url(), token), 1),
+    }).ok();
+}
+
+/*- List the callers' recent login attempts -*/
+pub(crate) fn history(stream: &mut Stream) -> () {
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+
+    /*- Newest first -*/
+    let options = FindOptions::builder()
+        .sort(doc!{ "timestamp": -1 })
+        .limit(HISTORY_LIMIT)
+        .build();
+    let attempts:Vec<LoginAttempt> = match collection().find(doc!{ "suid": &claims.suid }, options) {
+        Ok(cursor) => cursor.flatten().collect(),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    stream.respond(
+        200u16,
+        Respond::new()
+            .json(
+                &serde_json::to_string(
+                    &attempts
+                ).unwrap_or(String::new())
+            )
+    );
+}
+
+/*- Serve the page the new device mail links to. Opening it
+    does nothing by itself (mail scanners open links too), the
+    user has to confirm, which posts the token to `secure_account` -*/
+pub(crate) fn secure_page(stream: &mut Stream) -> () {
+    match std::fs::read(SECURE_PAGE) {
+        Ok(page) => utils::respond_bytes(
+            stream, "200 OK",
+            &[
+                ("Content-Type", "text/html; charset=utf-8".to_string()),
+                ("X-Frame-Options", "DENY".to_string()),
+                ("Cache-Control", "no-store".to_string()),
+                ("Referrer-Policy", "no-referrer".to_string()),
+            ],
+            &page
+        ),
+        Err(_) => utils::respond_status(stream, 404)
+    };
+}
+
+/*- Confirmed from the secure page. Revokes the session,
+    requires a password reset before the next login, and hands
+    out a short-lived reset token. Each link works once -*/
+pub(crate) fn secure_account(stream: &mut Stream) -> () {
+    let token:String = match stream.headers.get("secure-token") {
+        Some(e) if !e.is_empty() => e.to_string(),
+        _ => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    let (claims, project) = match consume_token(&token, "secure") {
+        Ok(Some(e)) => e,
+        Ok(None) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- Revoke the session and require a reset -*/
+    if let Some(sid) = &claims.sid {
+        session::revoke(sid, &claims.suid);
+    };
+    utils::establish_mclient::<User>("users").update_one(
+        doc!{ "suid": &claims.suid },
+        doc!{ "$set": { "password_reset_required": true } },
+        None
+    ).ok();
+    audit::record(stream, AuditEvent::AccountSecured, Outcome::Success, Some(&claims.suid), claims.sid.as_deref());
+
+    /*- Hand out a reset token -*/
+    let reset_token:String = match issue_token(&claims.suid, None, "password_reset", PASSWORD_RESET_LIFETIME, &project) {
+        Some(e) => e,
+        None => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    stream.respond(200, Respond::new().json(&format!(
+        "{{\"status\": 200, \"message\": \"{}\", \"reset_token\": \"{}\"}}",
+        DICTIONARY.info.account_secured, reset_token
+    )));
+}
+
+/*- Set a new password using a reset token. Every
+    session is revoked, since the old password
+    may have been compromised -*/
+pub(crate) fn reset_password(stream: &mut Stream) -> () {
+    let (token, password) = match utils::get_headers_checked(&stream.headers, &["reset-token", "password"]) {
+        Some(values) => (values[0].to_string(), values[1].to_string()),
+        None => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    let (claims, project) = match User::decode_purpose_token(&token, "password_reset") {
+        Ok(e) => e,
+        Err(_) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+
+    /*- If the password breaks the projects' password policy. Checked
+        before the token is used up, so that the user can try again -*/
+    if let Err(message) = project.check_password(&password) {
+        return stream.respond(400, do_json(400, &message));
+    };
+
+    /*- Reset tokens work once -*/
+    match consume_token(&token, "password_reset") {
+        Ok(Some(_)) => (),
+        Ok(None) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    let password_hash:String = utils::hash(&password);
+    match outbox::record(&project.id, WebhookEvent::PasswordChanged, |users, session| {
+        users.find_one_and_update_with_session(
+            doc!{ "suid": &claims.suid },
+            doc!{
+                "$set": { "password": &password_hash },
+                "$unset": { "password_reset_required": "" }
+            },
+            FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            session
+        )
+    }) {
+        Ok(_) => {
+            session::revoke_all(&claims.suid);
+            api_key::revoke_all(&claims.suid);
+            audit::record(stream, AuditEvent::PasswordChanged, Outcome::Success, Some(&claims.suid), Some(&claims.suid));
+            stream.respond(200, do_json(200, "Success!"));
+        },
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
@@ -0,0 +1,185 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, project,
+    api::{ do_json, respond_with_token },
+    audit::{ self, AuditEvent, Outcome },
+    dict::DICTIONARY,
+    login_history,
+    mail::{ self, Mail },
+    user::{ User, PurposeClaims },
+    project::Project,
+    webhook::WebhookEvent,
+    outbox,
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use mongodb::{
+    bson::doc,
+    options::{ FindOneAndUpdateOptions, ReturnDocument },
+    sync::Collection,
+};
+
+/*- Constants -*/
+const LINK_COLLECTION:&'static str = "magic_links";
+const LINK_LIFETIME:u64            = 60*15;
+const LINK_PURPOSE:&'static str    = "magic_link";
+const LINK_PAGE:&'static str       = "static/magic-link.html";
+
+/// # Magic link
+/// A sent sign-in link. The link itself is a signed purpose
+/// token, this only keeps track of whether it has been used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MagicLink {
+    token_hash: String,
+    suid      : String,
+    project_id: String,
+    created_at: u64,
+    expires_at: u64,
+    used      : bool,
+}
+
+/*- Quick way of getting the link collection -*/
+fn collection() -> Collection<MagicLink> {
+    utils::establish_mclient::<MagicLink>(LINK_COLLECTION)
+}
+
+/*- Remove a users' sign-in links. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    collection().delete_many(doc!{ "suid": suid }, None).ok();
+}
+
+/*- Mail a sign-in link. Responds the same whether or
+    not the email belongs to an account -*/
+pub(crate) fn send(stream: &mut Stream) -> () {
+    let email:String = match stream.headers.get("email") {
+        Some(e) if !e.trim().is_empty() => e.trim().to_string(),
+        _ => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    let users:Collection<User> = utils::establish_mclient::<User>("users");
+    let user:Option<User> = match users.find_one(doc!{ "project_id": &project.id, "email": &email }, None) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    if let Some(user) = user {
+        let token:String = match User::generate_purpose_token(&user.suid, None, LINK_PURPOSE, LINK_LIFETIME, &project) {
+            Ok(e) => e,
+            Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+        };
+        let now:u64 = utils::get_unix_epoch_time();
+        let link = MagicLink {
+            token_hash: utils::hash(&token),
+            suid      : user.suid.clone(),
+            project_id: project.id.clone(),
+            created_at: now,
+            expires_at: now + LINK_LIFETIME,
+            used      : false,
+        };
+        if collection().insert_one(&link, None).is_err() {
+            return stream.respond(500, do_json(500, "Internal server error"));
+        };
+
+        let outcome:Outcome = match mail::transport().send(&Mail {
+            to     : user.email.clone(),
+            subject: DICTIONARY.mail.magic_link_subject.to_string(),
+            body   : DICTIONARY.mail.magic_link_body
+                .replacen("{}", &user.displayname, 1)
+                .replacen("{}", &format!("{}/login/magic-link/{}", mail::public_url(), token), 1),
+        }) {
+            Ok(_) => Outcome::Success,
+            Err(_) => Outcome::Failure
+        };
+        audit::record(stream, AuditEvent::MagicLinkSent, outcome, None, Some(&user.suid));
+    } else {
+        audit::record(stream, AuditEvent::MagicLinkSent, Outcome::Failure, None, Some(&email));
+    };
+
+    stream.respond(200, do_json(200, DICTIONARY.info.magic_link_sent));
+}
+
+/*- The page the mailed link opens. Opening it has no side
+    effects, since mail scanners prefetch links. Signing in
+    from it posts the token to `exchange` -*/
+pub(crate) fn page(stream: &mut Stream) -> () {
+    match std::fs::read(LINK_PAGE) {
+        Ok(page) => utils::respond_bytes(
+            stream, "200 OK",
+            &[
+                ("Content-Type", "text/html; charset=utf-8".to_string()),
+                ("X-Frame-Options", "DENY".to_string()),
+                ("Cache-Control", "no-store".to_string()),
+                ("Referrer-Policy", "no-referrer".to_string()),
+            ],
+            &page
+        ),
+        Err(_) => utils::respond_status(stream, 404)
+    };
+}
+
+/*- Exchange a sign-in link for the same token `login`
+    responds with. Links can only be used once -*/
+pub(crate) fn exchange(stream: &mut Stream) -> () {
+    let token:String = match stream.headers.get("magic-token") {
+        Some(e) if !e.is_empty() => e.to_string(),
+        _ => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    let (claims, project):(PurposeClaims, Project) = match User::decode_purpose_token(&token, LINK_PURPOSE) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(401, do_json(401, DICTIONARY.error.magic_link))
+    };
+
+    /*- Use the link, atomically so it can't be used twice -*/
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::Before).build();
+    match collection().find_one_and_update(
+        doc!{ "token_hash": utils::hash(&token), "suid": &claims.suid, "used": false },
+        doc!{ "$set": { "used": true } },
+        options
+    ) {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            audit::record(stream, AuditEvent::Login, Outcome::Failure, None, Some(&claims.suid));
+            return stream.respond(401, do_json(401, DICTIONARY.error.magic_link));
+        },
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    let users:Collection<User> = utils::establish_mclient::<User>("users");
+    let user:User = match users.find_one(doc!{ "suid": &claims.suid, "project_id": &project.id }, None) {
+        Ok(Some(e)) => e,
+        Ok(None) => return stream.respond(401, do_json(401, DICTIONARY.error.magic_link)),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- A link doesn't get around a reported login -*/
+    if user.password_reset_required {
+        audit::record(stream, AuditEvent::Login, Outcome::Failure, None, Some(&user.suid));
+        login_history::record(stream, &user.suid, None, false);
+        return stream.respond(403, do_json(403, DICTIONARY.error.password_reset_required));
+    };
+
+    /*- Following the link proves the email is theirs -*/
+    if !user.email_verified {
+        outbox::record(&project.id, WebhookEvent::AccountVerified, |users, session| {
+            users.find_one_and_update_with_session(
+                doc!{ "suid": &user.suid, "email_verified": false },
+                doc!{ "$set": { "email_verified": true } },
+                FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+                session
+            )
+        }).ok();
+    };
+
+    respond_with_token(stream, &user, &project);
+}
@@ -0,0 +1,153 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::utils;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use std::{
+    env,
+    fs::OpenOptions,
+    net::TcpStream,
+    io::{ BufRead, BufReader, Write },
+};
+
+/*- Constants -*/
+const DEFAULT_SPOOL_PATH:&'static str = "mail.spool";
+const DEFAULT_SMTP_ADDRESS:&'static str = "localhost:25";
+const DEFAULT_SENDER:&'static str = "accounts@localhost";
+
+/// # Mail
+/// An outgoing plain text email.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Mail {
+    pub to     : String,
+    pub subject: String,
+    pub body   : String,
+}
+
+/*- Mail errors -*/
+#[derive(Debug)]
+pub(crate) enum MailError {
+    Io(std::io::Error),
+    Rejected(String),
+}
+
+/// # MailTransport
+/// Something that delivers mail. Which transport is used is
+/// decided by the `MAIL_TRANSPORT` environment variable, see
+/// `transport()`.
+pub(crate) trait MailTransport {
+    fn send(&self, mail:&Mail) -> Result<(), MailError>;
+}
+
+/*- Quick way of getting the configured mail transport -*/
+pub(crate) fn transport() -> Box<dyn MailTransport> {
+    match env::var("MAIL_TRANSPORT").as_deref() {
+        Ok("smtp") => Box::new(SmtpTransport {
+            address: env::var("SMTP_ADDRESS").unwrap_or(DEFAULT_SMTP_ADDRESS.to_string()),
+            sender : sender(),
+        }),
+        _ => Box::new(FileSink {
+            path: env::var("MAIL_SPOOL").unwrap_or(DEFAULT_SPOOL_PATH.to_string()),
+        }),
+    }
+}
+
+/*- The address mail is sent from -*/
+fn sender() -> String {
+    env::var("MAIL_FROM").unwrap_or(DEFAULT_SENDER.to_string())
+}
+
+/*- The URL this server is reached at, for links in mail -*/
+pub(crate) fn public_url() -> String {
+    env::var("PUBLIC_URL")
+        .unwrap_or("http://127.0.0.1:8081".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// # FileSink
+/// Appends every mail as one JSON line to a spool file,
+/// instead of delivering it. Used for local development
+/// and tests.
+pub(crate) struct FileSink {
+    pub path: String,
+}
+impl MailTransport for FileSink {
+    fn send(&self, mail:&Mail) -> Result<(), MailError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(MailError::Io)?;
+
+        let line:String = serde_json::to_string(mail).unwrap_or_default();
+        writeln!(file, "{}", line).map_err(MailError::Io)
+    }
+}
+
+/// # SmtpTransport
+/// Hands mail to an SMTP relay, as in a local postfix or
+/// a mail catcher. Speaks plain SMTP without TLS or auth,
+/// so the relay is expected to be on a trusted network.
+pub(crate) struct SmtpTransport {
+    pub address: String,
+    pub sender : String,
+}
+impl SmtpTransport {
+
+    /*- Read a reply and check its status code -*/
+    fn expect(reader:&mut BufReader<TcpStream>, code:&str) -> Result<(), MailError> {
+        let mut line:String = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).map_err(MailError::Io)?;
+
+            /*- Multi-line replies have a dash after the code -*/
+            if line.len() < 4 || line.as_bytes()[3] != b'-' { break; };
+        };
+
+        match line.starts_with(code) {
+            true => Ok(()),
+            false => Err(MailError::Rejected(line.trim().to_string()))
+        }
+    }
+}
+impl MailTransport for SmtpTransport {
+    fn send(&self, mail:&Mail) -> Result<(), MailError> {
+        let stream:TcpStream = TcpStream::connect(&self.address).map_err(MailError::Io)?;
+        let mut writer:TcpStream = stream.try_clone().map_err(MailError::Io)?;
+        let mut reader = BufReader::new(stream);
+
+        /*- Dot-stuff the body -*/
+        let body:String = mail.body
+            .lines()
+            .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_string() })
+            .collect::<Vec<String>>()
+            .join("\r\n");
+
+        SmtpTransport::expect(&mut reader, "220")?;
+        let commands:[(String, &str); 6] = [
+            ("HELO localhost\r\n".to_string(), "250"),
+            (format!("MAIL FROM:<{}>\r\n", self.sender), "250"),
+            (format!("RCPT TO:<{}>\r\n", mail.to), "250"),
+            ("DATA\r\n".to_string(), "354"),
+            (format!(
+                "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n.\r\n",
+                self.sender, mail.to, mail.subject, utils::http_date(utils::get_unix_epoch_time()), body
+            ), "250"),
+            ("QUIT\r\n".to_string(), "221"),
+        ];
+        for (command, code) in commands.iter() {
+            writer.write_all(command.as_bytes()).map_err(MailError::Io)?;
+            SmtpTransport::expect(&mut reader, code)?;
+        };
+
+        Ok(())
+    }
+}
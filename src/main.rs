@@ -11,50 +11,239 @@ mod utils;
 mod user;
 mod safe_user;
 mod origin_control;
+mod username;
+mod account;
+mod avatar;
+mod storage;
+mod audit;
+mod session;
+mod mail;
+mod login_history;
+mod project;
+mod oauth;
+mod oidc;
+mod api_key;
+mod magic_link;
+mod guest;
+mod social;
+mod leaderboard;
+mod block;
+mod presence;
+mod search;
+mod profile;
+mod webhook;
+mod outbox;
+mod metrics;
 #[path = "debugging/debug_routes.rs"] mod debug_routes;
 #[path = "resources/dict.rs"] mod dict;
+#[path = "resources/confusables.rs"] mod confusables;
 use responder::prelude::*;
+use std::thread;
+
+/*- Wrap a handler so that requests to it are counted and
+    timed, labelled with the method and the full route -*/
+macro_rules! metered {
+    ($method:literal, $route:literal, $handler:path) => {
+        |stream: &mut Stream| metrics::observe($method, $route, stream, $handler)
+    };
+}
 
 /*- Startup -*/
 fn main() -> () {
     /*- The api routes -*/
     let routes = &[
-        Route::Get("login",           api::login),
-        Route::Post("create-account", api::create_account),
+        Route::Get("login",           metered!("GET", "login", api::login)),
+        Route::Post("login/magic-link",          metered!("POST", "login/magic-link", magic_link::send)),
+        Route::Get("login/magic-link/:token:",   metered!("GET", "login/magic-link/:token:", magic_link::page)),
+        Route::Post("login/magic-link/exchange", metered!("POST", "login/magic-link/exchange", magic_link::exchange)),
+        Route::Post("create-account", metered!("POST", "create-account", api::create_account)),
+        Route::Post("guest",          metered!("POST", "guest", guest::create)),
+        Route::Post("guest/upgrade",  metered!("POST", "guest/upgrade", guest::upgrade)),
+
+        Route::Stack("account", &[
+            Route::Get("export",       metered!("GET", "account/export", account::export)),
+            Route::Post("delete",      metered!("POST", "account/delete", account::delete)),
+            Route::Post("delete/undo", metered!("POST", "account/delete/undo", account::undo_delete)),
+            Route::Get("login-history",   metered!("GET", "account/login-history", login_history::history)),
+            Route::Get("secure/:token:",  metered!("GET", "account/secure/:token:", login_history::secure_page)),
+            Route::Post("secure",         metered!("POST", "account/secure", login_history::secure_account)),
+            Route::Post("reset-password", metered!("POST", "account/reset-password", login_history::reset_password)),
+        ]),
         
+        Route::Get("authorize", metered!("GET", "authorize", oauth::authorize_page)),
+        Route::Stack("oauth", &[
+            Route::Post("clients/register", metered!("POST", "oauth/clients/register", oauth::register_client)),
+            Route::Get("client",            metered!("GET", "oauth/client", oauth::client_info)),
+            Route::Post("authorize",        metered!("POST", "oauth/authorize", oauth::authorize)),
+            Route::Post("token",            metered!("POST", "oauth/token", oauth::token)),
+            Route::Get("userinfo",          metered!("GET", "oauth/userinfo", oidc::userinfo)),
+            Route::Post("introspect",       metered!("POST", "oauth/introspect", oauth::introspect)),
+            Route::Post("revoke",           metered!("POST", "oauth/revoke", oauth::revoke)),
+        ]),
+
+        /*- OpenID Connect discovery, for the requested
+            project or for one named in the path -*/
+        Route::Get(".well-known/openid-configuration", metered!("GET", ".well-known/openid-configuration", oidc::discovery)),
+        Route::Get("jwks.json",                        metered!("GET", "jwks.json", oidc::jwks)),
+        Route::Stack("projects", &[
+            Route::Get(":project:/.well-known/openid-configuration", metered!("GET", "projects/:project:/.well-known/openid-configuration", oidc::discovery)),
+            Route::Get(":project:/jwks.json",                        metered!("GET", "projects/:project:/jwks.json", oidc::jwks)),
+        ]),
+
+        Route::Stack("keys", &[
+            Route::Post("create",      metered!("POST", "keys/create", api_key::create)),
+            Route::Get("list",         metered!("GET", "keys/list", api_key::list)),
+            Route::Post("revoke/:id:", metered!("POST", "keys/revoke/:id:", api_key::revoke)),
+        ]),
+
+        Route::Stack("sessions", &[
+            Route::Get("list",          metered!("GET", "sessions/list", session::list)),
+            Route::Post("revoke/:sid:", metered!("POST", "sessions/revoke/:sid:", session::revoke_session)),
+        ]),
+
         Route::Stack("profile", &[
             Route::Stack("data", &[
-                Route::Get("by_name/:name:", api::profile_data_name),
-                Route::Get("by_suid/:suid:", api::profile_data_suid),
+                Route::Get("by_name/:name:", metered!("GET", "profile/data/by_name/:name:", api::profile_data_name)),
+                Route::Get("by_suid/:suid:", metered!("GET", "profile/data/by_suid/:suid:", api::profile_data_suid)),
             ]),
-            Route::Get("image/:profile_image:", api::profile_image),
-            Route::Get("verify-token",          api::check_jws_token),
-            Route::Post("upload-image",         api::upload_profile_image)
+            Route::Get("image/:profile_image:", metered!("GET", "profile/image/:profile_image:", api::profile_image)),
+            Route::Get("verify-token",          metered!("GET", "profile/verify-token", api::check_jws_token)),
+            Route::Post("upload-image",         metered!("POST", "profile/upload-image", api::upload_profile_image)),
+            Route::Get("me",                    metered!("GET", "profile/me", profile::own_profile)),
+            Route::Post("update",               metered!("POST", "profile/update", profile::update)),
+            Route::Post("privacy",              metered!("POST", "profile/privacy", profile::set_privacy)),
+            Route::Get("schema",                metered!("GET", "profile/schema", profile::schema)),
+
+            /*- Last, as it matches "search?q=..." -*/
+            Route::Get(":search:",              metered!("GET", "profile/:search:", search::search)),
+        ]),
+
+        Route::Stack("admin", &[
+            Route::Get("audit",        metered!("GET", "admin/audit", audit::get_entries)),
+            Route::Get("audit/export", metered!("GET", "admin/audit/export", audit::export_entries)),
+            Route::Post("projects/create", metered!("POST", "admin/projects/create", project::create)),
+            Route::Post("profile-schema",  metered!("POST", "admin/profile-schema", profile::set_schema)),
+        ]),
+
+        Route::Stack("webhooks", &[
+            Route::Post("create",         metered!("POST", "webhooks/create", webhook::create)),
+            Route::Get("list",            metered!("GET", "webhooks/list", webhook::list)),
+            Route::Post("delete/:id:",    metered!("POST", "webhooks/delete/:id:", webhook::delete)),
+            Route::Get("deliveries",      metered!("GET", "webhooks/deliveries", webhook::log)),
+            Route::Get("dead-letters",    metered!("GET", "webhooks/dead-letters", webhook::dead_letters)),
+            Route::Post("redeliver/:id:", metered!("POST", "webhooks/redeliver/:id:", webhook::redeliver)),
+        ]),
+
+        Route::Get("changes",        metered!("GET", "changes", outbox::poll)),
+        Route::Get("changes/stream", metered!("GET", "changes/stream", outbox::subscribe)),
+
+        Route::Stack("friends", &[
+            Route::Get("list",                  metered!("GET", "friends/list", social::list_friends)),
+            Route::Get("requests",              metered!("GET", "friends/requests", social::list_requests)),
+            Route::Post("request/:suid:",       metered!("POST", "friends/request/:suid:", social::send_request)),
+            Route::Post("accept/:suid:",        metered!("POST", "friends/accept/:suid:", social::accept_request)),
+            Route::Post("decline/:suid:",       metered!("POST", "friends/decline/:suid:", social::decline_request)),
+            Route::Post("cancel/:suid:",        metered!("POST", "friends/cancel/:suid:", social::cancel_request)),
+            Route::Post("remove/:suid:",        metered!("POST", "friends/remove/:suid:", social::remove_friend)),
+            Route::Get("mutual/:suid:",         metered!("GET", "friends/mutual/:suid:", social::mutual_friends)),
+        ]),
+        Route::Stack("follows", &[
+            Route::Get("followers",             metered!("GET", "follows/followers", social::followers)),
+            Route::Get("following",             metered!("GET", "follows/following", social::following)),
+            Route::Post("follow/:suid:",        metered!("POST", "follows/follow/:suid:", social::follow)),
+            Route::Post("unfollow/:suid:",      metered!("POST", "follows/unfollow/:suid:", social::unfollow)),
+        ]),
+
+        Route::Stack("presence", &[
+            Route::Get("connect",               metered!("GET", "presence/connect", presence::connect)),
+            Route::Get("connect/:token:",       metered!("GET", "presence/connect/:token:", presence::connect)),
+            Route::Post("status",               metered!("POST", "presence/status", presence::set_status)),
+            Route::Post("heartbeat",            metered!("POST", "presence/heartbeat", presence::heartbeat)),
+            Route::Get("list",                  metered!("GET", "presence/list", presence::list)),
+        ]),
+
+        Route::Stack("blocks", &[
+            Route::Get("list",                  metered!("GET", "blocks/list", block::blocked)),
+            Route::Get("muted",                 metered!("GET", "blocks/muted", block::muted)),
+            Route::Post("block/:suid:",         metered!("POST", "blocks/block/:suid:", block::block)),
+            Route::Post("unblock/:suid:",       metered!("POST", "blocks/unblock/:suid:", block::unblock)),
+            Route::Post("mute/:suid:",          metered!("POST", "blocks/mute/:suid:", block::mute)),
+            Route::Post("unmute/:suid:",        metered!("POST", "blocks/unmute/:suid:", block::unmute)),
         ]),
 
         Route::Stack("leaderboards", &[
-            Route::Get("default", leaderboard)
+            Route::Get(":board:",             metered!("GET", "leaderboards/:board:", leaderboard::top)),
+            Route::Get(":board:/friends",     metered!("GET", "leaderboards/:board:/friends", leaderboard::friends)),
+            Route::Post(":board:/submit",     metered!("POST", "leaderboards/:board:/submit", leaderboard::submit)),
         ]),
 
+        /*- Not metered itself, scrapes would skew request metrics -*/
+        Route::Get("metrics", metrics::endpoint),
+
         Route::Stack("debug", &[
-            Route::Get("accounts",      debug_routes::get_all_accounts),
-            Route::Get("delete/:suid:", debug_routes::delete_account),
+            Route::Get("accounts",      metered!("GET", "debug/accounts", debug_routes::get_all_accounts)),
+            Route::Get("delete/:suid:", metered!("GET", "debug/delete/:suid:", debug_routes::delete_account)),
         ])
     ];
 
+    /*- Refuse to start without a real signing key -*/
+    if !project::has_default_signing_key() {
+        eprintln!("DEFAULT_SIGNING_KEY has to be set to the key tokens of the default project are signed with");
+        std::process::exit(1);
+    };
+
+    /*- Refuse to start with a broken username policy -*/
+    if let Err(err) = username::UsernamePolicy::from_env() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    };
+
+    /*- Move users from before projects existed into the default project -*/
+    project::migrate_legacy_users();
+
+    /*- Skeletons for users from before them, and unique usernames -*/
+    username::ensure_indexes();
+
+    /*- Unique emails -*/
+    user::ensure_indexes();
+
+    /*- Indexes for user search -*/
+    search::ensure_indexes();
+
+    /*- Index for reading the change feed -*/
+    outbox::ensure_indexes();
+
+    /*- Expire sessions along with their tokens -*/
+    session::ensure_indexes();
+
+    /*- Friendships, friend requests and follows are stored once -*/
+    social::ensure_indexes();
+
+    /*- Purge accounts whose deletion grace period has passed -*/
+    thread::spawn(account::purge_loop);
+
+    /*- Remove uploaded files no user references anymore -*/
+    thread::spawn(storage::gc_loop);
+
+    /*- Take users whose heartbeats stopped offline -*/
+    thread::spawn(presence::expire_loop);
+
+    /*- Send queued webhook deliveries -*/
+    thread::spawn(webhook::delivery_loop);
+
+    /*- Serve metrics on their own address, if METRICS_ADDRESS is set -*/
+    thread::spawn(metrics::serve);
+
     /*- Start the server -*/
     Server::new()
         .address("127.0.0.1")
         .port(8081)
         .serve("./static")
         .threads(6)
-        // .origin_control(origin_control::origin_control)
+        .origin_control(origin_control::origin_control)
         .routes(routes)
         .start()
         .unwrap();
 }
-fn leaderboard(stream: &mut Stream) -> () {
-
-}
 
 
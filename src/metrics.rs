@@ -0,0 +1,301 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    audit::{ AuditEvent, Outcome },
+};
+use responder::prelude::*;
+use mongodb::event::command::{
+    CommandEventHandler, CommandStartedEvent,
+    CommandSucceededEvent, CommandFailedEvent,
+};
+use std::{
+    env, thread,
+    cell::Cell,
+    fmt::Write as _,
+    io::{ BufRead, BufReader, Write },
+    net::{ TcpListener, TcpStream },
+    collections::BTreeMap,
+    sync::{ Arc, Mutex, MutexGuard, OnceLock },
+    time::{ Duration, Instant },
+};
+
+/*- Constants -*/
+const CONTENT_TYPE:&'static str = "text/plain; version=0.0.4; charset=utf-8";
+
+/*- Upper bounds of the latency histogram buckets, in seconds -*/
+const LATENCY_BUCKETS:&'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/*- Every metric, with its type and help text -*/
+const METRICS:&'static [(&'static str, &'static str, &'static str)] = &[
+    ("http_requests_total",                 "counter",   "Requests handled, by method, route and status"),
+    ("http_request_duration_seconds",       "histogram", "Time spent handling requests, by method and route"),
+    ("http_requests_rejected_total",        "counter",   "Requests rejected by origin control before routing, by status"),
+    ("logins_total",                        "counter",   "Logins, by outcome"),
+    ("signups_total",                       "counter",   "Accounts created, by kind and outcome"),
+    ("token_validations_total",             "counter",   "Access token validations, by outcome"),
+    ("database_commands_total",             "counter",   "Database commands sent, by command"),
+    ("database_errors_total",               "counter",   "Database commands which failed, by command"),
+    ("database_command_duration_seconds",   "histogram", "Time database commands took, by command"),
+    ("storage_operations_total",            "counter",   "Blob store operations, by backend, operation and outcome"),
+    ("storage_operation_duration_seconds",  "histogram", "Time blob store operations took, by backend and operation"),
+    ("process_start_time_seconds",          "gauge",     "When the server started, in unix time"),
+];
+
+/*- Label names and values of one series -*/
+type Labels = Vec<(&'static str, String)>;
+
+/*- Cumulative counts are computed when rendering -*/
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum    : f64,
+    count  : u64,
+}
+
+/*- Every series, keyed by metric name and labels -*/
+#[derive(Default)]
+struct Registry {
+    counters  : BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+    started_at: u64,
+}
+
+/*- The registry is shared by all threads -*/
+fn registry() -> MutexGuard<'static, Registry> {
+    static REGISTRY:OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(Registry {
+            started_at: utils::get_unix_epoch_time(),
+            ..Registry::default()
+        }))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/*- Owned labels -*/
+fn labels(labels:&[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(name, value)| (*name, value.to_string())).collect()
+}
+
+/*- Add one to a counter -*/
+pub(crate) fn increment(name:&'static str, series:&[(&'static str, &str)]) -> () {
+    *registry().counters.entry((name, labels(series))).or_insert(0) += 1;
+}
+
+/*- Add an observation to a histogram -*/
+pub(crate) fn observe_seconds(name:&'static str, series:&[(&'static str, &str)], seconds:f64) -> () {
+    let mut registry = registry();
+    let histogram:&mut Histogram = registry.histograms.entry((name, labels(series))).or_insert_with(|| Histogram {
+        buckets: vec![0; LATENCY_BUCKETS.len()],
+        ..Histogram::default()
+    });
+    if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+        histogram.buckets[index] += 1;
+    };
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+/*- The status of the response the current request thread
+    sent, as told by `record_status`. Responder doesn't
+    expose it, so the ways this crate responds report it -*/
+thread_local! {
+    static STATUS:Cell<Option<u16>> = Cell::new(None);
+}
+pub(crate) fn record_status(status:u16) -> () {
+    STATUS.with(|cell| cell.set(Some(status)));
+}
+
+/*- Run a route handler, counting and timing it. Responses
+    which didn't report a status were sent with a 200 -*/
+pub(crate) fn observe(method:&'static str, route:&'static str, stream:&mut Stream, handler:fn(&mut Stream) -> ()) -> () {
+    STATUS.with(|cell| cell.set(None));
+    let started:Instant = Instant::now();
+
+    handler(stream);
+
+    let seconds:f64 = started.elapsed().as_secs_f64();
+    let status:u16 = STATUS.with(|cell| cell.take()).unwrap_or(200);
+    increment("http_requests_total", &[("method", method), ("route", route), ("status", &status.to_string())]);
+    observe_seconds("http_request_duration_seconds", &[("method", method), ("route", route)], seconds);
+}
+
+/*- Requests origin control turned away -*/
+pub(crate) fn rejected(status:u16) -> () {
+    increment("http_requests_rejected_total", &[("status", &status.to_string())]);
+}
+
+/*- Count logins and signups from the audit log, which every
+    way of logging in or signing up already writes to -*/
+pub(crate) fn audit(event:AuditEvent, outcome:Outcome) -> () {
+    let outcome:&str = match outcome {
+        Outcome::Success => "success",
+        Outcome::Failure => "failure"
+    };
+    match event {
+        AuditEvent::Login => increment("logins_total", &[("outcome", outcome)]),
+        AuditEvent::AccountCreated => increment("signups_total", &[("kind", "account"), ("outcome", outcome)]),
+        AuditEvent::GuestCreated => increment("signups_total", &[("kind", "guest"), ("outcome", outcome)]),
+        _ => ()
+    };
+}
+
+/*- Count an access token validation -*/
+pub(crate) fn token_validation(valid:bool) -> () {
+    increment("token_validations_total", &[("outcome", if valid { "valid" } else { "invalid" })]);
+}
+
+/*- Count and time a blob store operation -*/
+pub(crate) fn storage_operation(backend:&'static str, operation:&'static str, success:bool, seconds:f64) -> () {
+    increment("storage_operations_total", &[
+        ("backend", backend),
+        ("operation", operation),
+        ("outcome", if success { "success" } else { "failure" }),
+    ]);
+    observe_seconds("storage_operation_duration_seconds", &[("backend", backend), ("operation", operation)], seconds);
+}
+
+/// # Database metrics
+/// Counts and times every command the database driver
+/// sends. Set on every client, see `utils::mclient`.
+pub(crate) struct DatabaseMetrics;
+impl CommandEventHandler for DatabaseMetrics {
+    fn handle_command_started_event(&self, event:CommandStartedEvent) {}
+    fn handle_command_succeeded_event(&self, event:CommandSucceededEvent) {
+        increment("database_commands_total", &[("command", &event.command_name)]);
+        observe_seconds("database_command_duration_seconds", &[("command", &event.command_name)], event.duration.as_secs_f64());
+    }
+    fn handle_command_failed_event(&self, event:CommandFailedEvent) {
+        increment("database_commands_total", &[("command", &event.command_name)]);
+        increment("database_errors_total", &[("command", &event.command_name)]);
+        observe_seconds("database_command_duration_seconds", &[("command", &event.command_name)], event.duration.as_secs_f64());
+    }
+}
+
+/*- One handler shared by every client -*/
+pub(crate) fn database_handler() -> Arc<dyn CommandEventHandler> {
+    static HANDLER:OnceLock<Arc<DatabaseMetrics>> = OnceLock::new();
+    HANDLER.get_or_init(|| Arc::new(DatabaseMetrics)).clone()
+}
+
+/*- Format labels as {name="value",...}, escaping values -*/
+fn format_labels(labels:&[(&str, String)]) -> String {
+    if labels.is_empty() { return String::new(); };
+    let labels:Vec<String> = labels.iter()
+        .map(|(name, value)| format!(
+            "{}=\"{}\"",
+            name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+        ))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/*- Every metric in the Prometheus text format -*/
+pub(crate) fn render() -> String {
+    let registry = registry();
+    let mut out:String = String::new();
+
+    for (name, kind, help) in METRICS {
+        writeln!(out, "# HELP {} {}", name, help).ok();
+        writeln!(out, "# TYPE {} {}", name, kind).ok();
+
+        match *kind {
+            "counter" => for ((_, labels), value) in registry.counters.range((*name, Vec::new())..).take_while(|((key, _), _)| key == name) {
+                writeln!(out, "{}{} {}", name, format_labels(labels), value).ok();
+            },
+            "histogram" => for ((_, labels), histogram) in registry.histograms.range((*name, Vec::new())..).take_while(|((key, _), _)| key == name) {
+                let mut cumulative:u64 = 0;
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                    cumulative += count;
+                    let mut bucket_labels:Labels = labels.clone();
+                    bucket_labels.push(("le", bound.to_string()));
+                    writeln!(out, "{}_bucket{} {}", name, format_labels(&bucket_labels), cumulative).ok();
+                };
+                let mut bucket_labels:Labels = labels.clone();
+                bucket_labels.push(("le", "+Inf".to_string()));
+                writeln!(out, "{}_bucket{} {}", name, format_labels(&bucket_labels), histogram.count).ok();
+                writeln!(out, "{}_sum{} {}", name, format_labels(labels), histogram.sum).ok();
+                writeln!(out, "{}_count{} {}", name, format_labels(labels), histogram.count).ok();
+            },
+            _ => if *name == "process_start_time_seconds" {
+                writeln!(out, "{} {}", name, registry.started_at).ok();
+            }
+        };
+    };
+
+    out
+}
+
+/*- The address of the separate metrics listener, if any -*/
+fn separate_address() -> Option<String> {
+    env::var("METRICS_ADDRESS").ok().filter(|e| !e.trim().is_empty())
+}
+
+/*- Serve metrics on the main server, unless they're
+    served on their own address set by METRICS_ADDRESS -*/
+pub(crate) fn endpoint(stream: &mut Stream) -> () {
+    if separate_address().is_some() {
+        return utils::respond_status(stream, 404);
+    };
+    utils::respond_bytes(
+        stream, "200 OK",
+        &[("Content-Type", CONTENT_TYPE.to_string()), ("Cache-Control", "no-store".to_string())],
+        render().as_bytes()
+    );
+}
+
+/*- Answer one request on the metrics listener -*/
+fn answer(mut connection:TcpStream) -> () {
+    connection.set_read_timeout(Some(Duration::from_secs(5))).ok();
+    let mut reader = BufReader::new(match connection.try_clone() {
+        Ok(e) => e,
+        Err(_) => return
+    });
+
+    /*- Only the request line matters, the headers are skipped -*/
+    let mut request_line:String = String::new();
+    if reader.read_line(&mut request_line).is_err() { return; };
+    let mut line:String = String::new();
+    while reader.read_line(&mut line).map_or(false, |read| read > 0) && line.trim() != "" {
+        line.clear();
+    };
+
+    let path:&str = request_line.split_whitespace().nth(1).unwrap_or("");
+    let response:String = match (request_line.starts_with("GET "), path.split('?').next()) {
+        (true, Some("/metrics")) => {
+            let body:String = render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", CONTENT_TYPE, body.len(), body)
+        },
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    connection.write_all(response.as_bytes()).ok();
+    connection.shutdown(std::net::Shutdown::Both).ok();
+}
+
+/*- Serve metrics on METRICS_ADDRESS (as in "127.0.0.1:9100"),
+    so that they can be scraped without being exposed where the
+    api is. Does nothing else if it isn't set. Runs forever, so
+    call it from its own thread at startup -*/
+pub(crate) fn serve() -> () {
+    /*- Start the clock for process_start_time_seconds -*/
+    drop(registry());
+
+    let address:String = match separate_address() {
+        Some(e) => e,
+        None => return
+    };
+    let listener:TcpListener = match TcpListener::bind(&address) {
+        Ok(e) => e,
+        Err(err) => return eprintln!("Couldn't serve metrics on {}: {}", address, err)
+    };
+
+    for connection in listener.incoming().flatten() {
+        answer(connection);
+    };
+}
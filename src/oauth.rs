@@ -0,0 +1,960 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    api::do_json,
+    audit::{ self, AuditEvent, Outcome },
+    dict::DICTIONARY,
+    session, oidc,
+    project::{ self, Project },
+    user::{ User, UserClaims, AuthorizationStatus, authenticate, generate_suid, project_of_token },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use base64;
+use sha2::{ Digest, Sha256 };
+use std::collections::HashMap;
+use mongodb::{
+    bson::doc,
+    options::{ FindOneAndUpdateOptions, ReturnDocument },
+    sync::Collection,
+};
+
+/*- Constants -*/
+const CLIENT_COLLECTION:&'static str = "oauth_clients";
+const CODE_COLLECTION:&'static str   = "oauth_codes";
+const CODE_LIFETIME:u64              = 60*5;
+const CONSENT_PAGE:&'static str      = "static/authorize.html";
+pub(crate) const ACCESS_TOKEN_LIFETIME:u64 = crate::user::TOKEN_LIFETIME;
+
+/*- Scopes clients may ask for -*/
+pub(crate) const SUPPORTED_SCOPES:&'static [&'static str] = &[
+    "openid", "profile", "email",
+];
+
+/// # OAuthClient
+/// An application allowed to sign users in. Public clients
+/// (as in single page apps and games) have no secret and
+/// rely on PKCE alone.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct OAuthClient {
+    pub client_id     : String,
+    pub secret_hash   : Option<String>,
+    pub name          : String,
+    pub redirect_uris : Vec<String>,
+    pub scopes        : Vec<String>,
+    pub project_id    : String,
+    pub owner_suid    : String,
+    pub created_at    : u64,
+}
+
+/*- An issued authorization code. Only the codes'
+    hash is stored, and it can be redeemed once -*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AuthorizationCode {
+    code_hash     : String,
+    client_id     : String,
+    suid          : String,
+    redirect_uri  : String,
+    scope         : String,
+    code_challenge: String,
+    nonce         : Option<String>,
+
+    /*- When the user consented, used as the ID tokens' auth_time -*/
+    authorized_at : u64,
+    expires_at    : u64,
+    used          : bool,
+}
+
+/*- An error response as in RFC 6749 section 5.2 -*/
+fn oauth_error(stream:&mut Stream, status:u16, error:&str, description:&str) -> () {
+    let body:String = serde_json::json!({
+        "error": error,
+        "error_description": description,
+    }).to_string();
+
+    utils::respond_bytes(
+        stream, &status_line(status),
+        &[
+            ("Content-Type", "application/json".to_string()),
+            ("Cache-Control", "no-store".to_string()),
+        ],
+        body.as_bytes()
+    );
+}
+pub(crate) fn status_line(status:u16) -> String {
+    match status {
+        200 => "200 OK",
+        400 => "400 Bad Request",
+        401 => "401 Unauthorized",
+        403 => "403 Forbidden",
+        404 => "404 Not Found",
+        _   => "500 Internal Server Error",
+    }.to_string()
+}
+
+/*- Quick way of getting the collections -*/
+fn clients() -> Collection<OAuthClient> {
+    utils::establish_mclient::<OAuthClient>(CLIENT_COLLECTION)
+}
+fn codes() -> Collection<AuthorizationCode> {
+    utils::establish_mclient::<AuthorizationCode>(CODE_COLLECTION)
+}
+
+/*- Get a client by id -*/
+pub(crate) fn get_client(client_id:&str) -> Option<OAuthClient> {
+    clients().find_one(doc!{ "client_id": client_id }, None).ok().flatten()
+}
+
+/*- Every client a user registered, without their secret
+    hashes. Used by exports -*/
+pub(crate) fn clients_of(suid:&str) -> Option<Vec<OAuthClient>> {
+    let owned:Vec<OAuthClient> = utils::find_all(&clients(), doc!{ "owner_suid": suid })?;
+    Some(owned.into_iter().map(|client| OAuthClient { secret_hash: None, ..client }).collect())
+}
+
+/*- Remove a users' codes and clients, along with the codes
+    issued to those clients. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    let owned:Vec<String> = clients().find(doc!{ "owner_suid": suid }, None)
+        .map(|cursor| cursor.flatten().map(|client| client.client_id).collect())
+        .unwrap_or_default();
+    codes().delete_many(doc!{ "$or": [ { "suid": suid }, { "client_id": { "$in": owned } } ] }, None).ok();
+    clients().delete_many(doc!{ "owner_suid": suid }, None).ok();
+}
+
+/*- The client id and secret a request names, from HTTP Basic
+    or the client_id and client_secret form parameters -*/
+fn client_credentials(headers:&HashMap<&str, &str>, form:&HashMap<String, String>) -> Option<(String, Option<String>)> {
+    let basic:Option<(String, String)> = utils::get_header_ignore_caps(headers, "Authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| decoded.split_once(':').map(|(id, secret)| (
+            utils::percent_decode(id), utils::percent_decode(secret)
+        )));
+    match basic {
+        Some((id, secret)) => Some((id, Some(secret))),
+        None => Some((form.get("client_id")?.to_string(), form.get("client_secret").cloned()))
+    }
+}
+
+/*- Confidential clients have to present their secret. Public
+    clients only name themselves, and rely on PKCE -*/
+fn secret_matches(client:&OAuthClient, secret:Option<&str>) -> bool {
+    match (&client.secret_hash, secret) {
+        (Some(hash), Some(secret)) => hash == &utils::hash(secret),
+        (Some(_), None) => false,
+        (None, _) => true
+    }
+}
+
+/*- Authenticate a client using HTTP Basic or the client_secret
+    form parameter (RFC 6749 section 2.3.1). Public clients
+    only have to name themselves -*/
+pub(crate) fn authenticate_client(stream:&Stream, form:&HashMap<String, String>) -> Option<OAuthClient> {
+    let (client_id, secret) = client_credentials(&stream.headers, form)?;
+    let client:OAuthClient = get_client(&client_id)?;
+    match secret_matches(&client, secret.as_deref()) {
+        true => Some(client),
+        false => None
+    }
+}
+
+/*- base64url(sha256(verifier)), RFC 7636 section 4.2 -*/
+fn s256(verifier:&str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/*- Only keep requested scopes the client may use. Returns
+    None if the client asked for something it may not -*/
+fn grantable_scope(client:&OAuthClient, requested:&str) -> Option<String> {
+    let scopes:Vec<&str> = requested.split(' ').filter(|e| !e.is_empty()).collect();
+    if scopes.is_empty() || !scopes.iter().all(|scope| client.scopes.iter().any(|allowed| allowed == scope)) {
+        return None;
+    };
+
+    Some(scopes.join(" "))
+}
+
+/*- Redirect URIs have to be absolute, without fragments or
+    credentials, and https unless their host is exactly this
+    machine. Prefix checks would let `localhost.evil.com` in -*/
+fn valid_redirect_uri(uri:&str) -> bool {
+    if uri.contains('#') { return false; };
+    let (scheme, rest) = match uri.split_once("://") {
+        Some(e) => e,
+        None => return false
+    };
+    let authority:&str = rest.split(|c| c == '/' || c == '?').next().unwrap_or("");
+    if authority.contains('@') { return false; };
+
+    /*- Strip the port, minding IPv6 literals -*/
+    let host:&str = match authority.strip_prefix('[') {
+        Some(inner) => match inner.split_once(']') {
+            Some((_, port)) if port.is_empty() || port.starts_with(':') => &authority[..inner.find(']').unwrap_or(0) + 2],
+            _ => return false
+        },
+        None => authority.split(':').next().unwrap_or("")
+    };
+    if host.is_empty() { return false; };
+
+    match scheme.to_ascii_lowercase().as_str() {
+        "https" => true,
+        "http" => ["localhost", "127.0.0.1", "[::1]"].contains(&host.to_ascii_lowercase().as_str()),
+        _ => false
+    }
+}
+
+/*- Register a client. The secret is only ever shown once -*/
+pub(crate) fn register_client(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    /*- The client is stored under the project the caller signed in to -*/
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if claims.aud == project.token_audience => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let (name, redirect_uris) = match utils::get_headers_checked(&stream.headers, &["name", "redirect-uris"]) {
+        Some(values) => (values[0].to_string(), values[1].to_string()),
+        None => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+
+    /*- Redirect URIs -*/
+    let redirect_uris:Vec<String> = redirect_uris.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect();
+    if redirect_uris.is_empty() || !redirect_uris.iter().all(|uri| valid_redirect_uri(uri)) {
+        return stream.respond(400, do_json(400, DICTIONARY.error.oauth.redirect_uri));
+    };
+
+    /*- Scopes -*/
+    let scopes:Vec<String> = match stream.headers.get("scopes") {
+        Some(scopes) => scopes.split(' ').filter(|e| !e.is_empty()).map(String::from).collect(),
+        None => SUPPORTED_SCOPES.iter().map(|e| e.to_string()).collect()
+    };
+    if !scopes.iter().all(|scope| SUPPORTED_SCOPES.contains(&scope.as_str())) {
+        return stream.respond(400, do_json(400, DICTIONARY.error.oauth.scope));
+    };
+
+    /*- Confidential clients get a secret -*/
+    let confidential:bool = stream.headers.get("confidential").map_or(false, |e| e == &"true");
+    let secret:Option<String> = match confidential {
+        true => Some(format!("{}{}", generate_suid(), generate_suid())),
+        false => None
+    };
+
+    let client = OAuthClient {
+        client_id     : generate_suid(),
+        secret_hash   : secret.as_deref().map(utils::hash),
+        name,
+        redirect_uris,
+        scopes,
+        project_id    : project.id,
+        owner_suid    : claims.suid.clone(),
+        created_at    : utils::get_unix_epoch_time(),
+    };
+    if clients().insert_one(&client, None).is_err() {
+        return stream.respond(500, do_json(500, "Internal server error"));
+    };
+    audit::record(stream, AuditEvent::OAuthClientRegistered, Outcome::Success, Some(&claims.suid), Some(&client.client_id));
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "status": 200,
+        "client_id": client.client_id,
+        "client_secret": secret,
+        "redirect_uris": client.redirect_uris,
+        "scopes": client.scopes,
+    }).to_string()));
+}
+
+/*- Serve the consent page. It reads the authorization
+    request from its own query string and posts the
+    users' decision to `oauth/authorize` -*/
+pub(crate) fn authorize_page(stream: &mut Stream) -> () {
+    match std::fs::read(CONSENT_PAGE) {
+        Ok(page) => utils::respond_bytes(
+            stream, "200 OK",
+            &[
+                ("Content-Type", "text/html; charset=utf-8".to_string()),
+                ("X-Frame-Options", "DENY".to_string()),
+                ("Cache-Control", "no-store".to_string()),
+            ],
+            &page
+        ),
+        Err(_) => utils::respond_status(stream, 404)
+    };
+}
+
+/*- Describe a client to the consent page, and check the
+    authorization request before the user is asked -*/
+pub(crate) fn client_info(stream: &mut Stream) -> () {
+    let (client_id, redirect_uri) = match utils::get_headers_checked(&stream.headers, &["client-id", "redirect-uri"]) {
+        Some(values) => (values[0].to_string(), values[1].to_string()),
+        None => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+
+    /*- Never redirect to an unregistered URI, not even with an error -*/
+    let client:OAuthClient = match get_client(&client_id) {
+        Some(client) if client.redirect_uris.contains(&redirect_uri) => client,
+        _ => return stream.respond(400, do_json(400, DICTIONARY.error.oauth.redirect_uri))
+    };
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "name": client.name,
+        "scopes": client.scopes,
+    }).to_string()));
+}
+
+/*- The users' decision on the consent page. Responds with
+    where the browser should be redirected to: back to the
+    client with either a code or an error -*/
+pub(crate) fn authorize(stream: &mut Stream) -> () {
+    let header = |key:&str| -> Option<String> {
+        stream.headers.get(key).map(|e| e.to_string()).filter(|e| !e.is_empty())
+    };
+    let (client_id, redirect_uri) = match (header("client-id"), header("redirect-uri")) {
+        (Some(id), Some(uri)) => (id, uri),
+        _ => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+    let state:Option<String> = header("state");
+    let nonce:Option<String> = header("nonce");
+    let scope:String = header("scope").unwrap_or_default();
+    let response_type:String = header("response-type").unwrap_or_default();
+    let code_challenge:String = header("code-challenge").unwrap_or_default();
+    let code_challenge_method:String = header("code-challenge-method").unwrap_or_default();
+    let approved:bool = header("decision").map_or(false, |e| e == "approve");
+
+    /*- Never redirect to an unregistered URI, not even with an error -*/
+    let client:OAuthClient = match get_client(&client_id) {
+        Some(client) if client.redirect_uris.contains(&redirect_uri) => client,
+        _ => return stream.respond(400, do_json(400, DICTIONARY.error.oauth.redirect_uri))
+    };
+
+    /*- Everything from here is reported back to the client -*/
+    let redirect = |params:Vec<(&str, String)>| -> String {
+        let mut params = params;
+        if let Some(state) = &state { params.push(("state", state.clone())); };
+        let query:String = params.iter()
+            .map(|(key, value)| format!("{}={}", key, utils::percent_encode(value)))
+            .collect::<Vec<String>>()
+            .join("&");
+        let separator:&str = if redirect_uri.contains('?') { "&" } else { "?" };
+        format!("{}{}{}", redirect_uri, separator, query)
+    };
+    let respond_redirect = |stream:&mut Stream, location:String| {
+        stream.respond(200, Respond::new().json(&serde_json::json!({ "redirect": location }).to_string()));
+    };
+    let error = |code:&str, description:&str| redirect(vec![
+        ("error", code.to_string()),
+        ("error_description", description.to_string()),
+    ]);
+
+    /*- Check the request -*/
+    if response_type != "code" {
+        return respond_redirect(stream, error("unsupported_response_type", "Only the code response type is supported"));
+    };
+    if code_challenge.len() < 43 || code_challenge_method != "S256" {
+        return respond_redirect(stream, error("invalid_request", "PKCE with S256 is required"));
+    };
+    let scope:String = match grantable_scope(&client, &scope) {
+        Some(e) => e,
+        None => return respond_redirect(stream, error("invalid_scope", DICTIONARY.error.oauth.scope))
+    };
+
+    /*- The user has to be signed in to the clients' project -*/
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if project::get(&client.project_id)
+            .map_or(false, |project| project.token_audience == claims.aud) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    if !approved {
+        audit::record(stream, AuditEvent::OAuthAuthorized, Outcome::Failure, Some(&claims.suid), Some(&client.client_id));
+        return respond_redirect(stream, error("access_denied", "The user denied the request"));
+    };
+
+    /*- Issue the code -*/
+    let code:String = match issue_code(&client, &claims.suid, &redirect_uri, scope, code_challenge, nonce) {
+        Some(e) => e,
+        None => return respond_redirect(stream, error("server_error", "Internal server error"))
+    };
+
+    audit::record(stream, AuditEvent::OAuthAuthorized, Outcome::Success, Some(&claims.suid), Some(&client.client_id));
+    respond_redirect(stream, redirect(vec![("code", code)]));
+}
+
+/*- Store a new authorization code, and return it -*/
+fn issue_code(client:&OAuthClient, suid:&str, redirect_uri:&str, scope:String, code_challenge:String, nonce:Option<String>) -> Option<String> {
+    let code:String = format!("{}{}", generate_suid(), generate_suid());
+    let authorization_code = AuthorizationCode {
+        code_hash     : utils::hash(&code),
+        client_id     : client.client_id.clone(),
+        suid          : suid.to_string(),
+        redirect_uri  : redirect_uri.to_string(),
+        scope,
+        code_challenge,
+        nonce,
+        authorized_at : utils::get_unix_epoch_time(),
+        expires_at    : utils::get_unix_epoch_time() + CODE_LIFETIME,
+        used          : false,
+    };
+    codes().insert_one(&authorization_code, None).ok()?;
+    Some(code)
+}
+
+/*- Redeem a code for a token request. The code is only used
+    up once the request is known to be valid for it, so that
+    anyone holding a leaked code can't burn it. Using it up is
+    atomic, so that it can't be used twice -*/
+fn redeem_code(code:&str, client:&OAuthClient, redirect_uri:&str, verifier:&str) -> Option<AuthorizationCode> {
+    let issued:AuthorizationCode = codes().find_one(doc!{ "code_hash": utils::hash(code), "used": false }, None).ok()??;
+    if !code_is_valid(&issued, client, redirect_uri, verifier, utils::get_unix_epoch_time()) {
+        return None;
+    };
+
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::Before).build();
+    codes().find_one_and_update(
+        doc!{ "code_hash": &issued.code_hash, "client_id": &client.client_id, "used": false },
+        doc!{ "$set": { "used": true } },
+        options
+    ).ok().flatten()
+}
+
+/*- If a redeemed code was issued to this client, for
+    this redirect URI, to whoever holds the PKCE verifier -*/
+fn code_is_valid(code:&AuthorizationCode, client:&OAuthClient, redirect_uri:&str, verifier:&str, now:u64) -> bool {
+    code.expires_at >= now
+        && code.client_id == client.client_id
+        && code.redirect_uri == redirect_uri
+        && s256(verifier) == code.code_challenge
+}
+
+/*- The token endpoint (RFC 6749 section 3.2). Takes
+    an application/x-www-form-urlencoded body -*/
+pub(crate) fn token(stream: &mut Stream) -> () {
+    let form:HashMap<String, String> = utils::parse_form(&stream.body);
+
+    let client:OAuthClient = match authenticate_client(stream, &form) {
+        Some(e) => e,
+        None => return oauth_error(stream, 401, "invalid_client", "Client authentication failed")
+    };
+    if form.get("grant_type").map(String::as_str) != Some("authorization_code") {
+        return oauth_error(stream, 400, "unsupported_grant_type", "Only authorization_code is supported");
+    };
+    let (code, redirect_uri, verifier) = match (form.get("code"), form.get("redirect_uri"), form.get("code_verifier")) {
+        (Some(code), Some(uri), Some(verifier)) => (code, uri, verifier),
+        _ => return oauth_error(stream, 400, "invalid_request", "code, redirect_uri and code_verifier are required")
+    };
+
+    /*- Check and redeem the code -*/
+    let authorization_code:AuthorizationCode = match redeem_code(code, &client, redirect_uri, verifier) {
+        Some(e) => e,
+        None => {
+            audit::record(stream, AuditEvent::OAuthTokenIssued, Outcome::Failure, None, Some(&client.client_id));
+            return oauth_error(stream, 400, "invalid_grant", "The code is unknown, already used, or invalid for this request");
+        }
+    };
+
+    /*- Get the user and project -*/
+    let user:User = match utils::establish_mclient::<User>("users").find_one(doc!{ "suid": &authorization_code.suid }, None) {
+        Ok(Some(user)) => user,
+        _ => return oauth_error(stream, 400, "invalid_grant", "The user no longer exists")
+    };
+    let project:Project = match project::get(&client.project_id) {
+        Some(e) => e,
+        None => return oauth_error(stream, 500, "server_error", "Internal server error")
+    };
+
+    /*- Every token gets a session, so the user can see and revoke it -*/
+    let session = match session::create(stream, &user.suid, Some(client.name.clone())) {
+        Some(e) => e,
+        None => return oauth_error(stream, 500, "server_error", "Internal server error")
+    };
+    let access_token:String = match User::generate_scoped_JWT(
+        user.clone(), &session.sid, &project,
+        Some(&authorization_code.scope), Some(&client.client_id)
+    ) {
+        Ok(e) => e,
+        Err(_) => return oauth_error(stream, 500, "server_error", "Internal server error")
+    };
+
+    /*- OpenID Connect clients also get an ID token -*/
+    let mut body = serde_json::json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": ACCESS_TOKEN_LIFETIME,
+        "scope": authorization_code.scope,
+    });
+    if authorization_code.scope.split(' ').any(|scope| scope == "openid") {
+        match oidc::id_token(&user, &client, &project, &authorization_code.scope, authorization_code.nonce.clone(), authorization_code.authorized_at) {
+            Ok(id_token) => body["id_token"] = serde_json::Value::String(id_token),
+            Err(_) => return oauth_error(stream, 500, "server_error", "Internal server error")
+        };
+    };
+
+    audit::record(stream, AuditEvent::OAuthTokenIssued, Outcome::Success, Some(&user.suid), Some(&client.client_id));
+    let body:String = body.to_string();
+    utils::respond_bytes(
+        stream, "200 OK",
+        &[
+            ("Content-Type", "application/json".to_string()),
+            ("Cache-Control", "no-store".to_string()),
+            ("Pragma", "no-cache".to_string()),
+        ],
+        body.as_bytes()
+    );
+}
+
+/*- Authenticate a confidential client, or respond with
+    invalid_client. Introspection and revocation are only
+    for clients which can keep a secret -*/
+fn require_confidential_client(stream:&mut Stream, form:&HashMap<String, String>) -> Option<OAuthClient> {
+    match authenticate_client(stream, form) {
+        Some(client) if client.secret_hash.is_some() => Some(client),
+        _ => {
+            utils::respond_bytes(
+                stream, "401 Unauthorized",
+                &[
+                    ("Content-Type", "application/json".to_string()),
+                    ("WWW-Authenticate", "Basic realm=\"oauth\"".to_string()),
+                ],
+                serde_json::json!({ "error": "invalid_client" }).to_string().as_bytes()
+            );
+            None
+        }
+    }
+}
+
+/*- Validate a token on behalf of a client. Tokens
+    from other projects are treated as invalid -*/
+fn validate_for_client(token:&str, client:&OAuthClient) -> Option<(UserClaims, Project)> {
+    let project:Project = project_of_token(token)?;
+    if project.id != client.project_id { return None; };
+
+    let claims:UserClaims = User::validate_JWT_token(token, &project).ok()?;
+    Some((claims, project))
+}
+
+/*- Token introspection (RFC 7662) -*/
+pub(crate) fn introspect(stream: &mut Stream) -> () {
+    let form:HashMap<String, String> = utils::parse_form(&stream.body);
+    let client:OAuthClient = match require_confidential_client(stream, &form) {
+        Some(e) => e,
+        None => return
+    };
+    let token:&str = match form.get("token") {
+        Some(e) => e,
+        None => return oauth_error(stream, 400, "invalid_request", "token is required")
+    };
+
+    /*- Inactive tokens get no other information -*/
+    let body:serde_json::Value = match validate_for_client(token, &client) {
+        Some((claims, project)) => serde_json::json!({
+            "active": true,
+            "scope": claims.scope,
+            "client_id": claims.client_id,
+            "username": claims.username,
+            "token_type": "Bearer",
+            "sub": claims.suid,
+            "aud": claims.aud,
+            "iss": oidc::issuer(&project),
+            "exp": claims.exp,
+            "iat": claims.iat,
+        }),
+        None => serde_json::json!({ "active": false })
+    };
+
+    utils::respond_bytes(
+        stream, "200 OK",
+        &[
+            ("Content-Type", "application/json".to_string()),
+            ("Cache-Control", "no-store".to_string()),
+        ],
+        body.to_string().as_bytes()
+    );
+}
+
+/*- Token revocation (RFC 7009). Clients may only revoke
+    tokens issued to them. Invalid tokens are not an
+    error, since the outcome is the same -*/
+pub(crate) fn revoke(stream: &mut Stream) -> () {
+    let form:HashMap<String, String> = utils::parse_form(&stream.body);
+    let client:OAuthClient = match require_confidential_client(stream, &form) {
+        Some(e) => e,
+        None => return
+    };
+    let token:&str = match form.get("token") {
+        Some(e) => e,
+        None => return oauth_error(stream, 400, "invalid_request", "token is required")
+    };
+
+    if let Some((claims, _)) = validate_for_client(token, &client) {
+        if claims.client_id.as_deref() == Some(&client.client_id) {
+            session::revoke(&claims.sid, &claims.suid);
+            audit::record(stream, AuditEvent::SessionRevoked, Outcome::Success, Some(&claims.suid), Some(&claims.sid));
+        };
+    };
+
+    utils::respond_bytes(stream, "200 OK", &[("Cache-Control", "no-store".to_string())], &[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*- The example from RFC 7636 appendix B -*/
+    const VERIFIER:&'static str  = "dBjftJeZ4CVP-mJ92K9Q5ajjJM5qAh1qBT2xnCRC9-U";
+    const CHALLENGE:&'static str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+    const REDIRECT:&'static str  = "https://app.example.com/callback";
+
+    fn client(secret:Option<&str>) -> OAuthClient {
+        OAuthClient {
+            client_id     : generate_suid(),
+            secret_hash   : secret.map(utils::hash),
+            name          : "Test client".to_string(),
+            redirect_uris : vec![REDIRECT.to_string()],
+            scopes        : vec!["openid".to_string()],
+            project_id    : project::DEFAULT_PROJECT_ID.to_string(),
+            owner_suid    : generate_suid(),
+            created_at    : 0,
+        }
+    }
+
+    fn code_for(client:&OAuthClient) -> AuthorizationCode {
+        AuthorizationCode {
+            code_hash     : utils::hash("code"),
+            client_id     : client.client_id.clone(),
+            suid          : generate_suid(),
+            redirect_uri  : REDIRECT.to_string(),
+            scope         : "openid".to_string(),
+            code_challenge: CHALLENGE.to_string(),
+            nonce         : None,
+            authorized_at : 100,
+            expires_at    : 100 + CODE_LIFETIME,
+            used          : false,
+        }
+    }
+
+    #[test]
+    fn s256_matches_rfc_7636() {
+        assert_eq!(s256(VERIFIER), CHALLENGE);
+    }
+
+    #[test]
+    fn codes_need_the_pkce_verifier() {
+        let client = client(None);
+        let code = code_for(&client);
+        assert!(code_is_valid(&code, &client, REDIRECT, VERIFIER, 100));
+        assert!(!code_is_valid(&code, &client, REDIRECT, "not-the-verifier-not-the-verifier-not-the-ve", 100));
+        assert!(!code_is_valid(&code, &client, REDIRECT, CHALLENGE, 100));
+    }
+
+    #[test]
+    fn codes_are_bound_to_their_redirect_uri_client_and_lifetime() {
+        let client = client(None);
+        let code = code_for(&client);
+        assert!(!code_is_valid(&code, &client, "https://app.example.com/other", VERIFIER, 100));
+        assert!(!code_is_valid(&code, &client, "https://app.example.com/callback?x=1", VERIFIER, 100));
+        assert!(!code_is_valid(&code, &self::client(None), REDIRECT, VERIFIER, 100));
+        assert!(!code_is_valid(&code, &client, REDIRECT, VERIFIER, 101 + CODE_LIFETIME));
+    }
+
+    #[test]
+    fn client_credentials_from_basic_or_form() {
+        let basic:String = format!("Basic {}", base64::encode("my%20client:s3cret"));
+        let headers:HashMap<&str, &str> = HashMap::from([("Authorization", basic.as_str())]);
+        assert_eq!(
+            client_credentials(&headers, &HashMap::new()),
+            Some(("my client".to_string(), Some("s3cret".to_string())))
+        );
+
+        let form:HashMap<String, String> = HashMap::from([
+            ("client_id".to_string(), "public".to_string()),
+        ]);
+        assert_eq!(client_credentials(&HashMap::new(), &form), Some(("public".to_string(), None)));
+        assert_eq!(client_credentials(&HashMap::new(), &HashMap::new()), None);
+    }
+
+    #[test]
+    fn confidential_clients_need_their_secret() {
+        let confidential = client(Some("s3cret"));
+        assert!(secret_matches(&confidential, Some("s3cret")));
+        assert!(!secret_matches(&confidential, Some("wrong")));
+        assert!(!secret_matches(&confidential, None));
+    }
+
+    #[test]
+    fn public_clients_only_name_themselves() {
+        let public = client(None);
+        assert!(secret_matches(&public, None));
+        assert!(secret_matches(&public, Some("anything")));
+    }
+
+    #[test]
+    fn redirect_uris_are_https_or_this_machine() {
+        assert!(valid_redirect_uri("https://app.example.com/callback"));
+        assert!(valid_redirect_uri("https://app.example.com:8443/callback?x=1"));
+        assert!(valid_redirect_uri("http://localhost/cb"));
+        assert!(valid_redirect_uri("http://localhost:3000/cb"));
+        assert!(valid_redirect_uri("http://127.0.0.1:8080"));
+        assert!(valid_redirect_uri("http://[::1]:8080/cb"));
+
+        assert!(!valid_redirect_uri("http://app.example.com/callback"));
+        assert!(!valid_redirect_uri("https://app.example.com/callback#fragment"));
+        assert!(!valid_redirect_uri("app.example.com/callback"));
+        assert!(!valid_redirect_uri("javascript://localhost/%0aalert(1)"));
+        assert!(!valid_redirect_uri("https:///callback"));
+    }
+
+    #[test]
+    fn look_alike_hosts_are_not_this_machine() {
+        assert!(!valid_redirect_uri("http://localhost.evil.com/cb"));
+        assert!(!valid_redirect_uri("http://127.0.0.1.nip.io/cb"));
+        assert!(!valid_redirect_uri("http://localhost@evil.com/cb"));
+        assert!(!valid_redirect_uri("http://localhost:80@evil.com/cb"));
+        assert!(!valid_redirect_uri("http://localhostevil.com/cb"));
+        assert!(!valid_redirect_uri("http://[::1].evil.com/cb"));
+        assert!(!valid_redirect_uri("http://[::1]evil.com/cb"));
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB, see docker-compose.yml"]
+    fn codes_can_only_be_redeemed_once() {
+        let client = client(None);
+        clients().insert_one(&client, None).expect("storing the client");
+        let code:String = issue_code(&client, &generate_suid(), REDIRECT, "openid".to_string(), CHALLENGE.to_string(), None)
+            .expect("issuing a code");
+
+        /*- Requests the code wasn't issued for don't use it up -*/
+        assert!(redeem_code(&code, &self::client(None), REDIRECT, VERIFIER).is_none());
+        assert!(redeem_code(&code, &client, "https://app.example.com/other", VERIFIER).is_none());
+        assert!(redeem_code(&code, &client, REDIRECT, "not-the-verifier-not-the-verifier-not-the-ve").is_none());
+
+        /*- authorize -> token: the first redemption succeeds -*/
+        assert!(redeem_code(&code, &client, REDIRECT, VERIFIER).is_some());
+
+        /*- And every later one fails, even with the right verifier -*/
+        assert!(redeem_code(&code, &client, REDIRECT, VERIFIER).is_none());
+        assert!(redeem_code("not a code", &client, REDIRECT, VERIFIER).is_none());
+
+        codes().delete_many(doc!{ "client_id": &client.client_id }, None).ok();
+        clients().delete_one(doc!{ "client_id": &client.client_id }, None).ok();
+    }
+
+    /*- The server, serving what the code flow goes through.
+        Started once for all tests that need it -*/
+    const TEST_PORT:u16 = 18_081;
+    fn server() -> String {
+        static STARTED:std::sync::Once = std::sync::Once::new();
+        STARTED.call_once(|| {
+            if std::env::var(project::SIGNING_KEY_VAR).is_err() {
+                std::env::set_var(project::SIGNING_KEY_VAR, "test-signing-key");
+            };
+            std::thread::spawn(|| {
+                Server::new()
+                    .address("127.0.0.1")
+                    .port(TEST_PORT)
+                    .threads(2)
+                    .routes(&[
+                        Route::Post("create-account", crate::api::create_account),
+                        Route::Get("login",           crate::api::login),
+                        Route::Stack("oauth", &[
+                            Route::Post("clients/register", register_client),
+                            Route::Get("client",            client_info),
+                            Route::Post("authorize",        authorize),
+                            Route::Post("token",            token),
+                            Route::Get("userinfo",          oidc::userinfo),
+                        ]),
+                    ])
+                    .start()
+                    .unwrap();
+            });
+
+            /*- Wait for it to listen -*/
+            for _ in 0..50 {
+                if std::net::TcpStream::connect(("127.0.0.1", TEST_PORT)).is_ok() { break; };
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            };
+        });
+
+        format!("http://127.0.0.1:{}", TEST_PORT)
+    }
+
+    /*- Send a request, and get the status and JSON body whatever the status -*/
+    fn send(request:ureq::Request, body:Option<&str>) -> (u16, serde_json::Value) {
+        let response = match body {
+            Some(body) => request.send_string(body),
+            None => request.call()
+        };
+        let response:ureq::Response = match response {
+            Ok(e) => e,
+            Err(ureq::Error::Status(_, e)) => e,
+            Err(err) => panic!("request failed: {}", err)
+        };
+        let status:u16 = response.status();
+        (status, serde_json::from_str(&response.into_string().unwrap_or_default()).unwrap_or(serde_json::Value::Null))
+    }
+
+    /*- Sign a new user up and in, and get their bearer token -*/
+    fn signed_in_user(base:&str) -> String {
+        let name:String = format!("flow_{}", &generate_suid()[..8]);
+        let email:String = format!("{}@example.com", name);
+        let (status, _) = send(ureq::post(&format!("{}/create-account", base))
+            .set("username", &name)
+            .set("displayname", &name)
+            .set("password", "correct-horse-battery")
+            .set("email", &email), Some(""));
+        assert_eq!(status, 200);
+
+        let (status, login) = send(ureq::get(&format!("{}/login", base))
+            .set("email", &email)
+            .set("password", "correct-horse-battery"), None);
+        assert_eq!(status, 200);
+        format!("Bearer {}", login["token"].as_str().expect("a token"))
+    }
+
+    /*- Register a client over HTTP, and get its id and secret -*/
+    fn register(base:&str, bearer:&str, confidential:bool) -> (String, Option<String>) {
+        let (status, body) = send(ureq::post(&format!("{}/oauth/clients/register", base))
+            .set("Authorization", bearer)
+            .set("name", "Flow test")
+            .set("redirect-uris", REDIRECT)
+            .set("scopes", "openid profile")
+            .set("confidential", if confidential { "true" } else { "false" }), Some(""));
+        assert_eq!(status, 200);
+        (
+            body["client_id"].as_str().expect("a client id").to_string(),
+            body["client_secret"].as_str().map(String::from)
+        )
+    }
+
+    /*- Approve an authorization request on the consent
+        page, and get the code it redirects back with -*/
+    fn consent(base:&str, bearer:&str, client_id:&str) -> String {
+        let (status, info) = send(ureq::get(&format!("{}/oauth/client", base))
+            .set("client-id", client_id)
+            .set("redirect-uri", REDIRECT), None);
+        assert_eq!(status, 200);
+        assert_eq!(info["name"], "Flow test");
+
+        let (status, body) = send(ureq::post(&format!("{}/oauth/authorize", base))
+            .set("Authorization", bearer)
+            .set("client-id", client_id)
+            .set("redirect-uri", REDIRECT)
+            .set("response-type", "code")
+            .set("scope", "openid profile")
+            .set("state", "xyz")
+            .set("code-challenge", CHALLENGE)
+            .set("code-challenge-method", "S256")
+            .set("decision", "approve"), Some(""));
+        assert_eq!(status, 200);
+
+        let location:&str = body["redirect"].as_str().expect("a redirect");
+        let (target, query) = location.split_once('?').expect("a query");
+        assert_eq!(target, REDIRECT);
+        let params:HashMap<String, String> = utils::parse_form(query);
+        assert_eq!(params.get("state").map(String::as_str), Some("xyz"));
+        params.get("code").expect("a code").to_string()
+    }
+
+    /*- A token request. Confidential clients authenticate with
+        client_secret_basic, public clients only name themselves -*/
+    fn exchange(base:&str, client_id:&str, secret:Option<&str>, code:&str, redirect_uri:&str, verifier:&str) -> (u16, serde_json::Value) {
+        let mut request:ureq::Request = ureq::post(&format!("{}/oauth/token", base))
+            .set("Content-Type", "application/x-www-form-urlencoded");
+        let mut form:Vec<(&str, &str)> = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", verifier),
+        ];
+        match secret {
+            Some(secret) => request = request.set("Authorization", &format!("Basic {}", base64::encode(format!("{}:{}", client_id, secret)))),
+            None => form.push(("client_id", client_id))
+        };
+
+        let body:String = form.iter()
+            .map(|(key, value)| format!("{}={}", key, utils::percent_encode(value)))
+            .collect::<Vec<String>>()
+            .join("&");
+        send(request, Some(&body))
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB, see docker-compose.yml"]
+    fn code_flow_with_a_confidential_client() {
+        let base:String = server();
+        let bearer:String = signed_in_user(&base);
+        let (client_id, secret) = register(&base, &bearer, true);
+        let secret:String = secret.expect("a secret");
+        let code:String = consent(&base, &bearer, &client_id);
+        const WRONG_VERIFIER:&'static str = "not-the-verifier-not-the-verifier-not-the-ve";
+
+        /*- Confidential clients have to authenticate -*/
+        let (status, body) = exchange(&base, &client_id, None, &code, REDIRECT, VERIFIER);
+        assert_eq!((status, body["error"].as_str()), (401, Some("invalid_client")));
+        let (status, _) = exchange(&base, &client_id, Some("wrong"), &code, REDIRECT, VERIFIER);
+        assert_eq!(status, 401);
+
+        /*- Mismatched redirect URIs and verifiers are refused,
+            without using the code up -*/
+        let (status, body) = exchange(&base, &client_id, Some(&secret), &code, "https://app.example.com/other", VERIFIER);
+        assert_eq!((status, body["error"].as_str()), (400, Some("invalid_grant")));
+        let (status, body) = exchange(&base, &client_id, Some(&secret), &code, REDIRECT, WRONG_VERIFIER);
+        assert_eq!((status, body["error"].as_str()), (400, Some("invalid_grant")));
+
+        /*- The right secret, redirect URI and verifier get tokens -*/
+        let (status, body) = exchange(&base, &client_id, Some(&secret), &code, REDIRECT, VERIFIER);
+        assert_eq!(status, 200);
+        assert_eq!(body["token_type"], "Bearer");
+        assert!(body["id_token"].is_string());
+        let access_token:&str = body["access_token"].as_str().expect("an access token");
+
+        /*- The token works for userinfo -*/
+        let (status, userinfo) = send(ureq::get(&format!("{}/oauth/userinfo", base))
+            .set("Authorization", &format!("Bearer {}", access_token)), None);
+        assert_eq!(status, 200);
+        assert!(userinfo["preferred_username"].is_string());
+
+        /*- And the code can't be used again -*/
+        let (status, body) = exchange(&base, &client_id, Some(&secret), &code, REDIRECT, VERIFIER);
+        assert_eq!((status, body["error"].as_str()), (400, Some("invalid_grant")));
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB, see docker-compose.yml"]
+    fn code_flow_with_a_public_client() {
+        let base:String = server();
+        let bearer:String = signed_in_user(&base);
+        let (client_id, secret) = register(&base, &bearer, false);
+        assert!(secret.is_none());
+        let code:String = consent(&base, &bearer, &client_id);
+
+        /*- Public clients only name themselves, PKCE does the rest -*/
+        assert_eq!(exchange(&base, &client_id, None, &code, REDIRECT, "not-the-verifier-not-the-verifier-not-the-ve").0, 400);
+        assert_eq!(exchange(&base, &client_id, None, &code, REDIRECT, VERIFIER).0, 200);
+        assert_eq!(exchange(&base, &client_id, None, &code, REDIRECT, VERIFIER).0, 400);
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB, see docker-compose.yml"]
+    fn look_alike_redirect_uris_cant_be_registered() {
+        let base:String = server();
+        let bearer:String = signed_in_user(&base);
+        for uri in ["http://localhost.evil.com/cb", "http://127.0.0.1.nip.io/cb", "http://app.example.com/cb"] {
+            let (status, _) = send(ureq::post(&format!("{}/oauth/clients/register", base))
+                .set("Authorization", &bearer)
+                .set("name", "Flow test")
+                .set("redirect-uris", uri), Some(""));
+            assert_eq!(status, 400, "{}", uri);
+        };
+    }
+}
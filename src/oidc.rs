@@ -0,0 +1,260 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    api::do_json,
+    dict::DICTIONARY,
+    mail::public_url,
+    oauth::{ self, OAuthClient, SUPPORTED_SCOPES },
+    project::{ self, Project, DEFAULT_PROJECT_ID },
+    profile::Viewer,
+    user::{ User, UserClaims, AuthorizationStatus, authenticate_scoped },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json::{ self, json, Value };
+use base64;
+use jsonwebtoken::{ encode, Header, Algorithm, EncodingKey };
+use rsa::{
+    RsaPrivateKey,
+    traits::PublicKeyParts,
+    pkcs1::DecodeRsaPrivateKey,
+};
+use mongodb::{
+    bson::doc,
+    sync::Collection,
+};
+
+/*- Constants -*/
+const ID_TOKEN_LIFETIME:u64 = 60*60;
+
+/// # IdTokenClaims
+/// Claims of an OpenID Connect ID token. Profile and
+/// email claims are only set if their scope was granted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct IdTokenClaims {
+    pub iss      : String,
+    pub sub      : String,
+    pub aud      : String,
+    pub exp      : u64,
+    pub iat      : u64,
+    pub auth_time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce    : Option<String>,
+
+    #[serde(flatten)]
+    pub profile  : ProfileClaims,
+}
+
+/*- Scope-gated claims, shared by ID tokens and /userinfo -*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct ProfileClaims {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name              : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picture           : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email             : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified    : Option<bool>,
+}
+
+/*- Function implementations -*/
+impl ProfileClaims {
+
+    /*- Fill in whatever the granted scope allows -*/
+    pub fn for_scope(user:&User, scope:&str) -> Self {
+        let granted = |wanted:&str| scope.split(' ').any(|scope| scope == wanted);
+        let mut claims:ProfileClaims = ProfileClaims::default();
+
+        if granted("profile") {
+            claims.preferred_username = Some(user.username.clone());
+            claims.name               = Some(user.displayname.clone());
+            claims.picture            = Some(format!("{}/profile/image/{}", public_url(), user.suid));
+        };
+        if granted("email") {
+            claims.email              = Some(user.email.clone());
+            claims.email_verified     = Some(user.email_verified);
+        };
+
+        claims
+    }
+}
+
+/*- The issuer identifier of a project. The default project
+    is issued by the server itself, other projects by their
+    own path, so that each has its own discovery document -*/
+pub(crate) fn issuer(project:&Project) -> String {
+    match project.id == DEFAULT_PROJECT_ID {
+        true => public_url(),
+        false => format!("{}/projects/{}", public_url(), project.id)
+    }
+}
+
+/*- The projects' RSA key, loaded once. The parsed key
+    for the JWKS, the PEM for signing -*/
+struct SigningKey {
+    key: RsaPrivateKey,
+    pem: String,
+    kid: String,
+}
+
+/*- The projects' RSA key and its key id -*/
+fn signing_key(project:&Project) -> Option<SigningKey> {
+    let pem:String = project::oidc_key(project)?;
+    let key:RsaPrivateKey = RsaPrivateKey::from_pkcs1_pem(&pem).ok()?;
+    let kid:String = utils::hash_bytes(&key.n().to_bytes_be())[..16].to_string();
+
+    Some(SigningKey { key, pem, kid })
+}
+
+/*- Issue an ID token -*/
+pub(crate) fn id_token(user:&User, client:&OAuthClient, project:&Project, scope:&str, nonce:Option<String>, auth_time:u64) -> Result<String, ()> {
+    let SigningKey { pem, kid, .. } = signing_key(project).ok_or(())?;
+    let now:u64 = utils::get_unix_epoch_time();
+
+    let claims = IdTokenClaims {
+        iss      : issuer(project),
+        sub      : user.suid.clone(),
+        aud      : client.client_id.clone(),
+        exp      : now + ID_TOKEN_LIFETIME,
+        iat      : now,
+        auth_time,
+        nonce,
+        profile  : ProfileClaims::for_scope(user, scope),
+    };
+
+    let mut header:Header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid);
+    encode(
+        &header,
+        &claims,
+        &EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(|_| ())?
+    ).map_err(|_| ())
+}
+
+/*- Get the project from the path, or the request -*/
+fn path_project(stream:&mut Stream) -> Option<Project> {
+    match stream.params.get("project").map(|e| e.to_string()) {
+        Some(id) => match project::get(&id) {
+            Some(project) => Some(project),
+            None => {
+                stream.respond(404, do_json(404, DICTIONARY.error.not_found.project));
+                None
+            }
+        },
+        None => project::require(stream)
+    }
+}
+
+/*- Respond with some JSON any origin may read -*/
+fn respond_public_json(stream:&mut Stream, body:&Value) -> () {
+    utils::respond_bytes(
+        stream, "200 OK",
+        &[
+            ("Content-Type", "application/json".to_string()),
+            ("Access-Control-Allow-Origin", "*".to_string()),
+            ("Cache-Control", "public, max-age=3600".to_string()),
+        ],
+        body.to_string().as_bytes()
+    );
+}
+
+/*- /.well-known/openid-configuration -*/
+pub(crate) fn discovery(stream: &mut Stream) -> () {
+    let project:Project = match path_project(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let issuer:String = issuer(&project);
+
+    /*- The consent page needs to know the project -*/
+    let authorization_endpoint:String = match project.id == DEFAULT_PROJECT_ID {
+        true => format!("{}/authorize", public_url()),
+        false => format!("{}/authorize?project={}", public_url(), project.id)
+    };
+
+    respond_public_json(stream, &json!({
+        "issuer": issuer,
+        "authorization_endpoint": authorization_endpoint,
+        "token_endpoint": format!("{}/oauth/token", public_url()),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", public_url()),
+        "jwks_uri": format!("{}/jwks.json", issuer),
+        "scopes_supported": SUPPORTED_SCOPES,
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "introspection_endpoint": format!("{}/oauth/introspect", public_url()),
+        "revocation_endpoint": format!("{}/oauth/revoke", public_url()),
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": [
+            "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce",
+            "preferred_username", "name", "picture", "email", "email_verified"
+        ],
+    }));
+}
+
+/*- The public half of the ID token signing key -*/
+pub(crate) fn jwks(stream: &mut Stream) -> () {
+    let project:Project = match path_project(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let SigningKey { key, kid, .. } = match signing_key(&project) {
+        Some(e) => e,
+        None => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    respond_public_json(stream, &json!({
+        "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": kid,
+            "n": base64::encode_config(key.n().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+            "e": base64::encode_config(key.e().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+        }]
+    }));
+}
+
+/*- /userinfo. The SafeUser plus whatever the tokens' scope allows -*/
+pub(crate) fn userinfo(stream: &mut Stream) -> () {
+    let claims:UserClaims = match authenticate_scoped(stream.headers.clone(), "openid") {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return utils::respond_bytes(
+            stream, "401 Unauthorized",
+            &[("WWW-Authenticate", "Bearer error=\"invalid_token\"".to_string())],
+            &[]
+        )
+    };
+    let user:User = match utils::establish_mclient::<User>("users").find_one(doc!{ "suid": &claims.suid }, None) {
+        Ok(Some(user)) => user,
+        _ => return utils::respond_status(stream, 401)
+    };
+
+    /*- Clients are third parties, never the owner. They see what
+        anyone may see, plus whatever the granted scope allows -*/
+    let scope:String = claims.scope.clone().unwrap_or(SUPPORTED_SCOPES.join(" "));
+    let mut body:Value = serde_json::to_value(User::to_safe_for(
+        user.clone(),
+        &project::get(&user.project_id).map(|project| project.settings.profile_schema).unwrap_or_default(),
+        &Viewer::anonymous()
+    )).unwrap_or(json!({}));
+    let profile:Value = serde_json::to_value(ProfileClaims::for_scope(&user, &scope)).unwrap_or(json!({}));
+    if let (Some(body), Value::Object(profile)) = (body.as_object_mut(), profile) {
+        body.insert("sub".to_string(), Value::String(user.suid.clone()));
+        body.extend(profile);
+    };
+
+    stream.respond(200, Respond::new().json(&body.to_string()));
+}
@@ -1,20 +1,36 @@
 /*- Imports -*/
 use std::{ net::TcpStream, collections::HashMap };
 use responder::Stream;
+use crate::{ utils, project, metrics };
 
 /*- Main -*/
 pub fn origin_control(stream:&Stream) -> Result<(), u16> {
-    
+    let result:Result<(), u16> = check(stream);
+    if let Err(status) = result { metrics::rejected(status); };
+    result
+}
+
+/*- Whether the request may go through, or the status to reject it with -*/
+fn check(stream:&Stream) -> Result<(), u16> {
+
     /*- Request has to have a host -*/
     match stream.headers.get("Host") {
         Some(host) => {
-            if host != &"" { Ok(()) }
-            else {
-                Err(401)
-            }
+            if host == &"" { return Err(401); }
         },
         None => {
-            Err(401)
+            return Err(401);
+        },
+    };
+
+    /*- Browsers send an origin, which has to be
+        allowed by the project the request is for -*/
+    match utils::get_header_ignore_caps(&stream.headers, "Origin") {
+        Some(origin) => match project::resolve(&stream.headers) {
+            Some(project) if project.allows_origin(origin) => Ok(()),
+            Some(_) => Err(403),
+            None => Err(404)
         },
+        None => Ok(())
     }
 }
@@ -0,0 +1,335 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    api::do_json,
+    dict::DICTIONARY,
+    project::{ self, Project },
+    user::{ User, AuthorizationStatus, authenticate_scoped, is_admin },
+    webhook::{ self, WebhookEvent },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json::{ self, Value };
+use mongodb::{
+    bson::{ doc, Document },
+    options::{ FindOptions, FindOneAndUpdateOptions, IndexOptions, ReturnDocument },
+    sync::{ ClientSession, Collection, Database },
+    IndexModel,
+};
+use std::{
+    thread,
+    io::Write,
+    net::TcpStream,
+    time::{ Duration, Instant },
+};
+
+/*- Constants -*/
+const OUTBOX_COLLECTION:&'static str  = "outbox";
+const COUNTER_COLLECTION:&'static str = "outbox_counters";
+const DEFAULT_PAGE_SIZE:i64           = 100;
+const MAX_PAGE_SIZE:i64               = 1000;
+
+/*- Long polls wait at most this many seconds for new
+    changes, and check for them this often (milliseconds) -*/
+const DEFAULT_WAIT:u64                = 20;
+const MAX_WAIT:u64                    = 25;
+const POLL_INTERVAL:u64               = 500;
+
+/*- Event streams are written to at least this often -*/
+const KEEP_ALIVE_INTERVAL:u64         = 15;
+
+/// # Change
+/// One entry of a projects' change feed. `seq` counts up
+/// from 1 per project without gaps, in the order changes
+/// were committed, so it doubles as the consumers' cursor.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Change {
+    pub project_id: String,
+    pub seq       : u64,
+    pub event     : WebhookEvent,
+    pub suid      : String,
+
+    /*- The user as it is after the change -*/
+    pub data      : Value,
+    pub created_at: u64,
+}
+
+/*- Quick way of getting the outbox collection -*/
+fn collection() -> Collection<Change> {
+    utils::establish_mclient::<Change>(OUTBOX_COLLECTION)
+}
+
+/*- Index the outbox by cursor. Unique, so that a sequence
+    number can never be handed out twice -*/
+pub(crate) fn ensure_indexes() -> () {
+    collection().create_index(
+        IndexModel::builder()
+            .keys(doc!{ "project_id": 1, "seq": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ).ok();
+}
+
+/*- Append a change within a transaction, and queue it for the
+    projects' webhooks. Every append to a project writes to the
+    same counter, so transactions appending concurrently conflict
+    and are retried, and sequence numbers end up in commit order -*/
+fn append(db:&Database, session:&mut ClientSession, project_id:&str, event:WebhookEvent, user:&User) -> mongodb::error::Result<()> {
+    let counter:Option<Document> = db.collection::<Document>(COUNTER_COLLECTION).find_one_and_update_with_session(
+        doc!{ "_id": project_id },
+        doc!{ "$inc": { "seq": 1i64 } },
+        FindOneAndUpdateOptions::builder().upsert(true).return_document(ReturnDocument::After).build(),
+        session
+    )?;
+    let seq:u64 = counter.and_then(|counter| counter.get_i64("seq").ok()).unwrap_or(1) as u64;
+
+    let data:Value = webhook::user_data(user);
+    webhook::enqueue(db, session, project_id, event, &data)?;
+    db.collection::<Change>(OUTBOX_COLLECTION).insert_one_with_session(&Change {
+        project_id: project_id.to_string(),
+        seq,
+        event,
+        suid      : user.suid.clone(),
+        data,
+        created_at: utils::get_unix_epoch_time(),
+    }, None, session)?;
+
+    Ok(())
+}
+
+/*- Change a user and append the change to the feed, in one
+    transaction. `change` writes to the users collection it's
+    given, with the session, and returns the user as it is
+    afterwards. If it returns None nothing changed, and nothing
+    is appended. `change` is run again if the transaction is retried -*/
+pub(crate) fn record<F>(project_id:&str, event:WebhookEvent, mut change:F) -> mongodb::error::Result<Option<User>>
+    where F: FnMut(&Collection<User>, &mut ClientSession) -> mongodb::error::Result<Option<User>>
+{
+    utils::transaction(|db, session| {
+        let users:Collection<User> = db.collection::<User>("users");
+        let user:User = match change(&users, session)? {
+            Some(e) => e,
+            None => return Ok(None)
+        };
+        append(db, session, project_id, event, &user)?;
+        Ok(Some(user))
+    })
+}
+
+/*- Changes after a cursor, oldest first -*/
+fn changes_after(project_id:&str, cursor:u64, limit:i64) -> mongodb::error::Result<Vec<Change>> {
+    let options = FindOptions::builder()
+        .sort(doc!{ "seq": 1 })
+        .limit(limit)
+        .build();
+    Ok(collection().find(doc!{ "project_id": project_id, "seq": { "$gt": cursor as i64 } }, options)?
+        .flatten()
+        .collect())
+}
+
+/*- Get the project, if the caller may read its feed: project
+    API keys with the `changes` scope, and the projects' admins -*/
+fn authorize(stream:&mut Stream) -> Option<Project> {
+    let project:Project = project::require(stream)?;
+    match authenticate_scoped(stream.headers.clone(), "changes") {
+        AuthorizationStatus::Authorized(claims) if claims.aud == project.token_audience && (claims.suid.is_empty() || is_admin(&claims.suid)) => Some(project),
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        }
+    }
+}
+
+/*- The last `seq` the consumer has seen, from the `cursor`
+    header, or `Last-Event-ID` when an event stream reconnects.
+    Starts from the beginning if neither is given -*/
+fn cursor(stream:&mut Stream) -> Option<u64> {
+    let value:Option<&str> = stream.headers.get("cursor").copied()
+        .or_else(|| utils::get_header_ignore_caps(&stream.headers, "Last-Event-ID"));
+    match value.map(|e| e.trim().parse::<u64>()) {
+        Some(Ok(e)) => Some(e),
+        Some(Err(_)) => {
+            stream.respond(400, do_json(400, DICTIONARY.error.invalid.cursor));
+            None
+        },
+        None => Some(0)
+    }
+}
+
+/*- Long poll for changes after the cursor. Responds as soon as
+    there are any, or with none after `wait` seconds. Pass the
+    returned cursor with the next poll to continue from there -*/
+pub(crate) fn poll(stream: &mut Stream) -> () {
+    let project:Project = match authorize(stream) { Some(e) => e, None => return };
+    let cursor:u64 = match cursor(stream) { Some(e) => e, None => return };
+    let (_, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+    let wait:u64 = stream.headers.get("wait")
+        .and_then(|e| e.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_WAIT)
+        .min(MAX_WAIT);
+
+    /*- Waiting happens on its own thread, as in `subscribe`,
+        so that long polls don't use up the request pool -*/
+    let mut connection:TcpStream = match stream.get_mut_inner_ref().try_clone() {
+        Ok(e) => e,
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    thread::spawn(move || {
+        let deadline:Instant = Instant::now() + Duration::from_secs(wait);
+        let (status, body):(&str, String) = loop {
+            match changes_after(&project.id, cursor, limit) {
+                Ok(changes) if !changes.is_empty() || Instant::now() >= deadline => {
+                    let next:u64 = changes.last().map_or(cursor, |change| change.seq);
+                    break ("200 OK", serde_json::json!({
+                        "items" : changes,
+                        "cursor": next,
+                    }).to_string());
+                },
+                Ok(_) => thread::sleep(Duration::from_millis(POLL_INTERVAL)),
+                Err(_) => break ("500 Internal Server Error", serde_json::json!({
+                    "status" : 500,
+                    "message": "Internal server error",
+                }).to_string())
+            };
+        };
+
+        utils::write_response(
+            &mut connection, status,
+            &[("Content-Type", "application/json".to_string())],
+            body.as_bytes()
+        );
+    });
+}
+
+/*- Stream changes after the cursor as server-sent events,
+    with `seq` as the event id so that reconnecting clients
+    resume where they left off -*/
+pub(crate) fn subscribe(stream: &mut Stream) -> () {
+    let project:Project = match authorize(stream) { Some(e) => e, None => return };
+    let mut cursor:u64 = match cursor(stream) { Some(e) => e, None => return };
+
+    /*- The connection outlives this request thread, so
+        that long-lived connections don't use up the pool -*/
+    let mut connection:TcpStream = match stream.get_mut_inner_ref().try_clone() {
+        Ok(e) => e,
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    let headers:&str = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: keep-alive\r\n\r\n";
+    if connection.write_all(headers.as_bytes()).is_err() { return; };
+
+    thread::spawn(move || {
+        let mut last_write:Instant = Instant::now();
+        loop {
+            let changes:Vec<Change> = match changes_after(&project.id, cursor, MAX_PAGE_SIZE) {
+                Ok(e) => e,
+                Err(_) => break
+            };
+
+            let mut message:String = String::new();
+            for change in &changes {
+                message.push_str(&format!(
+                    "id: {}\nevent: {}\ndata: {}\n\n",
+                    change.seq, change.event.name(), serde_json::to_string(change).unwrap_or_default()
+                ));
+            };
+            if message.is_empty() && last_write.elapsed() >= Duration::from_secs(KEEP_ALIVE_INTERVAL) {
+                message.push_str(": keep-alive\n\n");
+            };
+            if !message.is_empty() {
+                if connection.write_all(message.as_bytes()).is_err() { break; };
+                last_write = Instant::now();
+            };
+
+            match changes.last() {
+                Some(change) => cursor = change.seq,
+                None => thread::sleep(Duration::from_millis(POLL_INTERVAL))
+            };
+        };
+
+        connection.shutdown(std::net::Shutdown::Both).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{ generate_suid, generate_uuid };
+
+    /*- Record a new user in a project, through the outbox -*/
+    fn create_user(project_id:&str) -> User {
+        let user = User {
+            username  : format!("outbox_{}", &generate_suid()[..12]),
+            uid       : generate_uuid(),
+            suid      : generate_suid(),
+            project_id: project_id.to_string(),
+            ..User::default()
+        };
+        record(project_id, WebhookEvent::AccountCreated, |users, session| {
+            users.insert_one_with_session(&user, None, session).map(|_| Some(user.clone()))
+        }).expect("recording a change").expect("a changed user")
+    }
+
+    /*- Remove everything a test wrote -*/
+    fn clean_up(project_id:&str) -> () {
+        utils::establish_mclient::<User>("users").delete_many(doc!{ "project_id": project_id }, None).ok();
+        collection().delete_many(doc!{ "project_id": project_id }, None).ok();
+        utils::establish_mclient::<Document>(COUNTER_COLLECTION).delete_one(doc!{ "_id": project_id }, None).ok();
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB running as a replica set, see docker-compose.yml"]
+    fn sequence_numbers_have_no_gaps_and_resumed_cursors_repeat_nothing() {
+        let project_id:String = format!("outbox-test-{}", generate_suid());
+        ensure_indexes();
+        let suids:Vec<String> = (0..5).map(|_| create_user(&project_id).suid).collect();
+
+        /*- Every change, in order, numbered 1, 2, 3... -*/
+        let changes:Vec<Change> = changes_after(&project_id, 0, MAX_PAGE_SIZE).expect("reading the feed");
+        let seqs:Vec<u64> = changes.iter().map(|change| change.seq).collect();
+        assert_eq!(seqs, (1..=5).collect::<Vec<u64>>());
+        assert_eq!(changes.iter().map(|change| change.suid.clone()).collect::<Vec<String>>(), suids);
+
+        /*- Paging through with the returned cursor sees each change once -*/
+        let mut cursor:u64 = 0;
+        let mut seen:Vec<u64> = Vec::new();
+        loop {
+            let page:Vec<Change> = changes_after(&project_id, cursor, 2).expect("reading the feed");
+            match page.last() {
+                Some(change) => cursor = change.seq,
+                None => break
+            };
+            seen.extend(page.iter().map(|change| change.seq));
+        };
+        assert_eq!(seen, seqs);
+
+        /*- Resuming from the last cursor returns nothing, until
+            there's a new change, which is the only one returned -*/
+        assert!(changes_after(&project_id, cursor, MAX_PAGE_SIZE).expect("reading the feed").is_empty());
+        create_user(&project_id);
+        let resumed:Vec<u64> = changes_after(&project_id, cursor, MAX_PAGE_SIZE).expect("reading the feed")
+            .iter().map(|change| change.seq).collect();
+        assert_eq!(resumed, vec![6]);
+
+        clean_up(&project_id);
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB running as a replica set, see docker-compose.yml"]
+    fn unchanged_users_append_nothing() {
+        let project_id:String = format!("outbox-test-{}", generate_suid());
+        let user:Option<User> = record(&project_id, WebhookEvent::AccountUpdated, |_, _| Ok(None)).expect("recording a change");
+        assert!(user.is_none());
+        assert!(changes_after(&project_id, 0, MAX_PAGE_SIZE).expect("reading the feed").is_empty());
+
+        clean_up(&project_id);
+    }
+}
@@ -0,0 +1,409 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, social, block, session,
+    api::do_json,
+    dict::DICTIONARY,
+    project::{ self, Project },
+    user::{ User, UserClaims },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use std::{
+    thread,
+    io::Write,
+    net::TcpStream,
+    time::{ Duration, Instant },
+    collections::{ HashMap, HashSet },
+    sync::{ Mutex, MutexGuard, OnceLock, mpsc::{ self, Sender, Receiver, RecvTimeoutError } },
+};
+
+/*- Constants -*/
+
+/*- Clients must send a heartbeat at least this often (seconds),
+    or they are considered gone -*/
+const HEARTBEAT_TIMEOUT:u64     = 60;
+
+/*- How often connections are written to when there are no
+    events, so that dead connections are noticed -*/
+const KEEP_ALIVE_INTERVAL:u64   = 15;
+const EXPIRE_INTERVAL:u64       = 10;
+const MAX_ACTIVITY_LEN:usize    = 128;
+
+/*- Each connection has its own thread, so they're limited
+    per user and in total -*/
+const MAX_USER_CONNECTIONS:usize = 5;
+const MAX_CONNECTIONS:usize      = 10_000;
+
+/*- How often (seconds) open connections are checked for
+    sessions which were revoked since they connected -*/
+const SESSION_CHECK_INTERVAL:u64 = 60;
+
+/*- What a user is doing -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    Online,
+    Away,
+    InGame,
+    Offline,
+}
+
+/// # Presence
+/// A connected users' status. Presence is kept in memory
+/// and only exists while the user has an open connection
+/// and keeps sending heartbeats.
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct Presence {
+    pub suid          : String,
+    pub status        : Status,
+
+    /*- Free text, as in the name of the game being played -*/
+    pub activity      : Option<String>,
+    pub since         : u64,
+    #[serde(skip)]
+    pub last_heartbeat: u64,
+}
+
+/*- An open event stream -*/
+struct Connection {
+    project_id: String,
+    suid      : String,
+    sid       : String,
+    sender    : Sender<String>,
+}
+
+/*- Everything that's online -*/
+#[derive(Default)]
+struct Hub {
+    next_id    : u64,
+    connections: HashMap<u64, Connection>,
+
+    /*- Keyed by project id and suid -*/
+    presence   : HashMap<(String, String), Presence>,
+}
+
+/*- The hub is shared by all request threads -*/
+fn hub() -> MutexGuard<'static, Hub> {
+    static HUB:OnceLock<Mutex<Hub>> = OnceLock::new();
+    HUB.get_or_init(|| Mutex::new(Hub::default()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/*- Format a presence as a server-sent event -*/
+fn event(presence:&Presence) -> String {
+    format!(
+        "event: presence\ndata: {}\n\n",
+        serde_json::to_string(presence).unwrap_or_default()
+    )
+}
+
+/*- Users who get a users' presence events: their friends
+    and followers, except those who muted them -*/
+fn audience(project_id:&str, suid:&str) -> HashSet<String> {
+    let mut audience:HashSet<String> = social::friend_suids(project_id, suid).into_iter().collect();
+    audience.extend(social::follower_suids(project_id, suid));
+    for muter in block::muters_of(project_id, suid) {
+        audience.remove(&muter);
+    };
+
+    audience
+}
+
+/*- Users whose presence a user can see -*/
+fn visible_to(project_id:&str, suid:&str) -> HashSet<String> {
+    let mut visible:HashSet<String> = social::friend_suids(project_id, suid).into_iter().collect();
+    visible.extend(social::followee_suids(project_id, suid));
+    for muted in block::muted_by(project_id, suid) {
+        visible.remove(&muted);
+    };
+
+    visible
+}
+
+/*- Send a presence change to everyone in its audience -*/
+fn broadcast(project_id:&str, presence:&Presence) -> () {
+    let audience:HashSet<String> = audience(project_id, &presence.suid);
+    let message:String = event(presence);
+
+    let hub = hub();
+    for connection in hub.connections.values() {
+        if connection.project_id == project_id && audience.contains(&connection.suid) {
+            connection.sender.send(message.clone()).ok();
+        };
+    };
+}
+
+/*- Set a users' status, and tell their audience if it changed -*/
+fn update(project_id:&str, suid:&str, status:Status, activity:Option<String>) -> Presence {
+    let now:u64 = utils::get_unix_epoch_time();
+    let (presence, changed) = {
+        let mut hub = hub();
+        let key = (project_id.to_string(), suid.to_string());
+        match hub.presence.get_mut(&key) {
+            Some(presence) => {
+                let changed:bool = presence.status != status || presence.activity != activity;
+                if changed {
+                    presence.status = status;
+                    presence.activity = activity;
+                    presence.since = now;
+                };
+                presence.last_heartbeat = now;
+                (presence.clone(), changed)
+            },
+            None => {
+                let presence = Presence { suid: suid.to_string(), status, activity, since: now, last_heartbeat: now };
+                hub.presence.insert(key, presence.clone());
+                (presence, true)
+            }
+        }
+    };
+
+    if changed { broadcast(project_id, &presence); };
+    presence
+}
+
+/*- Take a user offline, closing their connections -*/
+fn go_offline(project_id:&str, suid:&str) -> () {
+    let removed:bool = {
+        let mut hub = hub();
+        hub.connections.retain(|_, connection| !(connection.project_id == project_id && connection.suid == suid));
+        hub.presence.remove(&(project_id.to_string(), suid.to_string())).is_some()
+    };
+
+    if removed {
+        broadcast(project_id, &Presence {
+            suid          : suid.to_string(),
+            status        : Status::Offline,
+            activity      : None,
+            since         : utils::get_unix_epoch_time(),
+            last_heartbeat: 0,
+        });
+    };
+}
+
+/*- A users' current status, if they're online -*/
+pub(crate) fn status_of(project_id:&str, suid:&str) -> Option<Presence> {
+    hub().presence.get(&(project_id.to_string(), suid.to_string())).cloned()
+}
+
+/*- Get the project and validate the token the same way
+    `check_jws_token` does. The token may be passed in the
+    `token` header, as a bearer token, or in the URL-params
+    for clients which can't set headers (as in EventSource) -*/
+fn authorize(stream:&mut Stream) -> Option<(Project, UserClaims)> {
+    let project:Project = project::require(stream)?;
+    let token:Option<String> = stream.headers.get("token").map(|e| e.to_string())
+        .or_else(|| utils::get_header_ignore_caps(&stream.headers, "Authorization").map(|e| e.replace("Bearer ", "")))
+        .or_else(|| stream.params.get("token").map(|e| e.to_string()));
+
+    match token.and_then(|token| User::validate_JWT_token(&token, &project).ok()) {
+        Some(claims) => Some((project, claims)),
+        None => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        }
+    }
+}
+
+/*- Open an event stream. The user is online until the
+    connection closes or heartbeats stop. Presence of the
+    users they can see is sent right away, and changes to
+    it as they happen -*/
+pub(crate) fn connect(stream: &mut Stream) -> () {
+    let (project, claims) = match authorize(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    /*- Register, unless the user or the server
+        already has as many connections as allowed -*/
+    let (sender, receiver):(Sender<String>, Receiver<String>) = mpsc::channel();
+    let id:Option<u64> = {
+        let mut hub = hub();
+        let open:usize = hub.connections.values()
+            .filter(|connection| connection.project_id == project.id && connection.suid == claims.suid)
+            .count();
+        match open < MAX_USER_CONNECTIONS && hub.connections.len() < MAX_CONNECTIONS {
+            true => {
+                hub.next_id += 1;
+                let id:u64 = hub.next_id;
+                hub.connections.insert(id, Connection {
+                    project_id: project.id.clone(),
+                    suid      : claims.suid.clone(),
+                    sid       : claims.sid.clone(),
+                    sender    : sender.clone(),
+                });
+                Some(id)
+            },
+            false => None
+        }
+    };
+    let id:u64 = match id {
+        Some(e) => e,
+        None => return stream.respond(429, do_json(429, DICTIONARY.error.too_many_connections))
+    };
+
+    /*- The connection outlives this request thread, so
+        that long-lived connections don't use up the pool -*/
+    let mut connection:TcpStream = match stream.get_mut_inner_ref().try_clone() {
+        Ok(e) => e,
+        Err(_) => {
+            hub().connections.remove(&id);
+            return stream.respond(500, do_json(500, "Internal server error"));
+        }
+    };
+    let headers:&str = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: keep-alive\r\n\r\n";
+    if connection.write_all(headers.as_bytes()).is_err() {
+        hub().connections.remove(&id);
+        return;
+    };
+
+    /*- Connecting keeps any earlier status -*/
+    let (status, activity) = match status_of(&project.id, &claims.suid) {
+        Some(presence) => (presence.status, presence.activity),
+        None => (Status::Online, None)
+    };
+    update(&project.id, &claims.suid, status, activity);
+
+    /*- Snapshot -*/
+    for suid in visible_to(&project.id, &claims.suid) {
+        if let Some(presence) = status_of(&project.id, &suid) {
+            sender.send(event(&presence)).ok();
+        };
+    };
+    drop(sender);
+
+    let project_id:String = project.id.clone();
+    let suid:String = claims.suid.clone();
+    thread::spawn(move || {
+        loop {
+            let message:String = match receiver.recv_timeout(Duration::from_secs(KEEP_ALIVE_INTERVAL)) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_string(),
+
+                /*- Removed from the hub -*/
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+            if connection.write_all(message.as_bytes()).is_err() { break; };
+        };
+
+        /*- Offline once the users' last connection closes -*/
+        let last:bool = {
+            let mut hub = hub();
+            hub.connections.remove(&id);
+            !hub.connections.values().any(|connection| connection.project_id == project_id && connection.suid == suid)
+        };
+        if last { go_offline(&project_id, &suid); };
+        connection.shutdown(std::net::Shutdown::Both).ok();
+    });
+}
+
+/*- Set the callers' status. Also counts as a heartbeat -*/
+pub(crate) fn set_status(stream: &mut Stream) -> () {
+    let (project, claims) = match authorize(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let status:Status = match stream.headers.get("status").copied() {
+        Some("online") => Status::Online,
+        Some("away") => Status::Away,
+        Some("in_game") => Status::InGame,
+        _ => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.presence_status))
+    };
+    let activity:Option<String> = stream.headers.get("activity")
+        .map(|e| e.chars().take(MAX_ACTIVITY_LEN).collect());
+
+    if status_of(&project.id, &claims.suid).is_none() {
+        return stream.respond(409, do_json(409, DICTIONARY.error.not_connected));
+    };
+    let presence:Presence = update(&project.id, &claims.suid, status, activity);
+    stream.respond(200, Respond::new().json(&serde_json::to_string(&presence).unwrap_or_default()));
+}
+
+/*- Keep the caller online -*/
+pub(crate) fn heartbeat(stream: &mut Stream) -> () {
+    let (project, claims) = match authorize(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    let mut hub = hub();
+    match hub.presence.get_mut(&(project.id.clone(), claims.suid.clone())) {
+        Some(presence) => {
+            presence.last_heartbeat = utils::get_unix_epoch_time();
+            drop(hub);
+            stream.respond(200, do_json(200, "Success!"));
+        },
+        None => {
+            drop(hub);
+            stream.respond(409, do_json(409, DICTIONARY.error.not_connected));
+        }
+    };
+}
+
+/*- Presence of the users the caller can see. Users who
+    aren't online are left out -*/
+pub(crate) fn list(stream: &mut Stream) -> () {
+    let (project, claims) = match authorize(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    let online:Vec<Presence> = visible_to(&project.id, &claims.suid)
+        .into_iter()
+        .filter_map(|suid| status_of(&project.id, &suid))
+        .collect();
+    stream.respond(200, Respond::new().json(&serde_json::to_string(&online).unwrap_or_default()));
+}
+
+/*- Close connections whose session was revoked or has
+    expired since they connected. Purged accounts have no
+    sessions left. Their threads take the users offline -*/
+fn close_revoked() -> () {
+    let sessions:HashSet<(String, String)> = hub().connections
+        .values()
+        .map(|connection| (connection.sid.clone(), connection.suid.clone()))
+        .collect();
+    let revoked:HashSet<(String, String)> = sessions
+        .into_iter()
+        .filter(|(sid, suid)| !session::is_active(sid, suid))
+        .collect();
+    if revoked.is_empty() { return; };
+
+    hub().connections.retain(|_, connection| !revoked.contains(&(connection.sid.clone(), connection.suid.clone())));
+}
+
+/*- Take users who stopped sending heartbeats offline, and
+    close connections of revoked sessions. Runs forever, so
+    call it from its own thread -*/
+pub(crate) fn expire_loop() -> () {
+    let mut last_session_check:Instant = Instant::now();
+    loop {
+        let cutoff:u64 = utils::get_unix_epoch_time().saturating_sub(HEARTBEAT_TIMEOUT);
+        let expired:Vec<(String, String)> = hub().presence
+            .iter()
+            .filter(|(_, presence)| presence.last_heartbeat < cutoff)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for (project_id, suid) in expired {
+            go_offline(&project_id, &suid);
+        };
+
+        if last_session_check.elapsed() >= Duration::from_secs(SESSION_CHECK_INTERVAL) {
+            close_revoked();
+            last_session_check = Instant::now();
+        };
+
+        thread::sleep(Duration::from_secs(EXPIRE_INTERVAL));
+    }
+}
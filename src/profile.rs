@@ -0,0 +1,508 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, search, social,
+    api::do_json,
+    dict::DICTIONARY,
+    project::{ self, Project },
+    user::{ User, UserClaims, AuthorizationStatus, authenticate, is_admin },
+    webhook::WebhookEvent,
+    outbox,
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json::{ self, Value };
+use regex::Regex;
+use mongodb::{
+    bson::{ self, doc, Document },
+    options::{ FindOneAndUpdateOptions, ReturnDocument },
+    sync::Collection,
+};
+use std::collections::{ BTreeMap, HashSet };
+
+/*- Constants -*/
+const MAX_FIELDS:usize           = 32;
+const MAX_STRING_LEN:usize       = 1024;
+const MAX_DISPLAYNAME_LEN:usize  = 64;
+const FIELD_NAME_PATTERN:&'static str = r"^[a-z][a-z0-9_]{0,31}$";
+
+/*- Names SafeUser already uses, which fields can't shadow -*/
+const RESERVED_NAMES:&'static [&'static str] = &[
+    "username", "displayname", "suid", "uid", "email", "profile", "relationship",
+    "avatar", "ratings",
+];
+
+/*- Parts of every profile which users can set the visibility
+    of, besides the public fields of the projects' schema -*/
+pub(crate) const BUILT_IN_PARTS:&'static [&'static str] = &[ "displayname", "avatar", "ratings" ];
+
+/*- Who can see a part of a users' profile -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Visibility {
+    #[default]
+    Everyone,
+    Friends,
+    OnlyMe,
+}
+
+/// # Viewer
+/// Whoever is looking at profiles. Anonymous viewers
+/// only see parts which are visible to everyone.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Viewer {
+    pub suid   : Option<String>,
+    pub friends: HashSet<String>,
+}
+
+/// # Profile field
+/// One field of a projects' profile schema. Values
+/// of fields which aren't `public` are only ever
+/// returned to the user they belong to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct ProfileField {
+    pub name  : String,
+    #[serde(flatten)]
+    pub kind  : FieldKind,
+    #[serde(default)]
+    pub public: bool,
+}
+
+/*- What values a field takes -*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum FieldKind {
+    String  { max_len: usize },
+    Integer { #[serde(default)] min: Option<i64>, #[serde(default)] max: Option<i64> },
+    Boolean,
+    Enum    { options: Vec<String> },
+}
+
+/*- Function implementations -*/
+impl ProfileField {
+
+    /*- Check a value against the field. Null is always
+        allowed, and removes the value -*/
+    pub fn validate(&self, value:&Value) -> Result<(), String> {
+        let wrong_type = |expected:&str| DICTIONARY.error.profile.wrong_type
+            .replacen("{}", &self.name, 1)
+            .replacen("{}", expected, 1);
+
+        match (&self.kind, value) {
+            (_, Value::Null) => Ok(()),
+            (FieldKind::String { max_len }, Value::String(string)) => match string.chars().count() > *max_len {
+                true => Err(DICTIONARY.error.profile.too_long
+                    .replacen("{}", &self.name, 1)
+                    .replacen("{}", &max_len.to_string(), 1)),
+                false => Ok(())
+            },
+            (FieldKind::String { .. }, _) => Err(wrong_type("string")),
+            (FieldKind::Integer { min, max }, Value::Number(number)) => match number.as_i64() {
+                Some(number) if min.map_or(true, |min| number >= min) && max.map_or(true, |max| number <= max) => Ok(()),
+                Some(_) => Err(DICTIONARY.error.profile.out_of_range
+                    .replacen("{}", &self.name, 1)
+                    .replacen("{}", &min.map_or("-".to_string(), |e| e.to_string()), 1)
+                    .replacen("{}", &max.map_or("-".to_string(), |e| e.to_string()), 1)),
+                None => Err(wrong_type("integer"))
+            },
+            (FieldKind::Integer { .. }, _) => Err(wrong_type("integer")),
+            (FieldKind::Boolean, Value::Bool(_)) => Ok(()),
+            (FieldKind::Boolean, _) => Err(wrong_type("boolean")),
+            (FieldKind::Enum { options }, Value::String(string)) => match options.contains(string) {
+                true => Ok(()),
+                false => Err(DICTIONARY.error.profile.not_an_option
+                    .replacen("{}", &self.name, 1)
+                    .replacen("{}", &options.join(", "), 1))
+            },
+            (FieldKind::Enum { .. }, _) => Err(wrong_type("string")),
+        }
+    }
+}
+
+impl Viewer {
+
+    /*- Someone who didn't supply a token -*/
+    pub fn anonymous() -> Self {
+        Viewer::default()
+    }
+
+    /*- A user looking at their own profile -*/
+    pub fn owner(suid:&str) -> Self {
+        Viewer { suid: Some(suid.to_string()), friends: HashSet::new() }
+    }
+
+    /*- A user of a project, or nobody. Friends are looked up
+        once, so that checking many profiles stays cheap -*/
+    pub fn of(project_id:&str, suid:Option<String>) -> Self {
+        match suid {
+            Some(suid) => Viewer {
+                friends: social::friend_suids(project_id, &suid).into_iter().collect(),
+                suid   : Some(suid),
+            },
+            None => Viewer::anonymous()
+        }
+    }
+
+    /*- Whoever supplied the token in the headers, if anyone -*/
+    pub fn from_headers(project_id:&str, headers:&std::collections::HashMap<&str, &str>) -> Self {
+        Viewer::of(project_id, social::caller_suid(headers))
+    }
+
+    /*- If the viewer may see a part of a users' profile -*/
+    pub fn can_see(&self, user:&User, part:&str) -> bool {
+        if self.suid.as_deref() == Some(user.suid.as_str()) { return true; };
+        match user.privacy.get(part).copied().unwrap_or_default() {
+            Visibility::Everyone => true,
+            Visibility::Friends => self.friends.contains(&user.suid),
+            Visibility::OnlyMe => false,
+        }
+    }
+}
+
+/*- Check a schema before storing it -*/
+fn validate_schema(schema:&[ProfileField]) -> Result<(), String> {
+    if schema.len() > MAX_FIELDS {
+        return Err(DICTIONARY.error.profile.too_many_fields.replace("{}", &MAX_FIELDS.to_string()));
+    };
+
+    let pattern:Regex = Regex::new(FIELD_NAME_PATTERN).unwrap();
+    let mut names:HashSet<&str> = HashSet::new();
+    for field in schema {
+        if !pattern.is_match(&field.name) || RESERVED_NAMES.contains(&field.name.as_str()) || !names.insert(&field.name) {
+            return Err(DICTIONARY.error.profile.field_name.replace("{}", &field.name));
+        };
+        let valid:bool = match &field.kind {
+            FieldKind::String { max_len } => *max_len > 0 && *max_len <= MAX_STRING_LEN,
+            FieldKind::Integer { min: Some(min), max: Some(max) } => min <= max,
+            FieldKind::Enum { options } => !options.is_empty(),
+            _ => true
+        };
+        if !valid {
+            return Err(DICTIONARY.error.profile.field_definition.replace("{}", &field.name));
+        };
+    };
+
+    Ok(())
+}
+
+/*- The fields of a profile a viewer may see. Fields which
+    aren't public in the schema are only seen by their owner,
+    whatever the users' own settings are. Values of fields no
+    longer in the schema are left out -*/
+pub(crate) fn visible_fields(user:&User, schema:&[ProfileField], viewer:&Viewer) -> BTreeMap<String, Value> {
+    let owner:bool = viewer.suid.as_deref() == Some(user.suid.as_str());
+    schema.iter()
+        .filter(|field| owner || (field.public && viewer.can_see(user, &field.name)))
+        .filter_map(|field| user.profile.get(&field.name).map(|value| (field.name.clone(), value.clone())))
+        .collect()
+}
+
+/*- The displayname search may match on. Displaynames which
+    not everyone can see mustn't be findable by searching -*/
+pub(crate) fn searchable_displayname<'a>(privacy:&BTreeMap<String, Visibility>, displayname:&'a str) -> &'a str {
+    match privacy.get("displayname").copied().unwrap_or_default() {
+        Visibility::Everyone => displayname,
+        _ => ""
+    }
+}
+
+/*- Every field of a profile which is still in the schema -*/
+pub(crate) fn own_fields(profile:&BTreeMap<String, Value>, schema:&[ProfileField]) -> BTreeMap<String, Value> {
+    schema.iter()
+        .filter_map(|field| profile.get(&field.name).map(|value| (field.name.clone(), value.clone())))
+        .collect()
+}
+
+/*- The projects' schema, so that clients can build forms -*/
+pub(crate) fn schema(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    stream.respond(200, Respond::new().json(
+        &serde_json::to_string(&project.settings.profile_schema).unwrap_or_default()
+    ));
+}
+
+/*- Replace the projects' schema. The body is a JSON array of
+    fields. Admins of the project only. Values of removed
+    fields are kept, but no longer returned -*/
+pub(crate) fn set_schema(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if claims.aud == project.token_audience && is_admin(&claims.suid) => (),
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let schema:Vec<ProfileField> = match serde_json::from_str(&stream.body) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(400, do_json(400, DICTIONARY.error.profile.invalid_body))
+    };
+    if let Err(message) = validate_schema(&schema) {
+        return stream.respond(400, do_json(400, &message));
+    };
+
+    let schema_bson = match bson::to_bson(&schema) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    match project::save_settings(&project.id, doc!{ "settings.profile_schema": schema_bson }) {
+        true => stream.respond(200, do_json(200, "Success!")),
+        false => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- The callers' own profile, private fields included -*/
+pub(crate) fn own_profile(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let user:User = match utils::establish_mclient::<User>("users").find_one(doc!{ "suid": &claims.suid, "project_id": &project.id }, None) {
+        Ok(Some(e)) => e,
+        Ok(None) => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "username"   : user.username,
+        "displayname": user.displayname,
+        "suid"       : user.suid,
+        "profile"    : own_fields(&user.profile, &project.settings.profile_schema),
+        "privacy"    : user.privacy,
+    }).to_string()));
+}
+
+/*- Update the callers' profile. The body is a JSON object of
+    field names and values, null removes a value. `displayname`
+    can be changed the same way. Nothing is changed unless every
+    field is valid -*/
+pub(crate) fn update(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let changes:serde_json::Map<String, Value> = match serde_json::from_str(&stream.body) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(400, do_json(400, DICTIONARY.error.profile.invalid_body))
+    };
+
+    let mut set:Document = doc!{};
+    let mut unset:Document = doc!{};
+    let mut displayname:Option<String> = None;
+    for (name, value) in changes {
+        if name == "displayname" {
+            match value.as_str().map(str::trim) {
+                Some(new) if !new.is_empty() && new.chars().count() <= MAX_DISPLAYNAME_LEN => displayname = Some(new.to_string()),
+                _ => return stream.respond(400, do_json(400, &DICTIONARY.error.profile.too_long
+                    .replacen("{}", "displayname", 1)
+                    .replacen("{}", &MAX_DISPLAYNAME_LEN.to_string(), 1)))
+            };
+            continue;
+        };
+
+        let field:&ProfileField = match project.settings.profile_schema.iter().find(|field| field.name == name) {
+            Some(e) => e,
+            None => return stream.respond(400, do_json(400, &DICTIONARY.error.profile.unknown_field.replace("{}", &name)))
+        };
+        if let Err(message) = field.validate(&value) {
+            return stream.respond(400, do_json(400, &message));
+        };
+        match value {
+            Value::Null => { unset.insert(format!("profile.{}", name), ""); },
+            value => { set.insert(format!("profile.{}", name), bson::to_bson(&value).unwrap_or_default()); }
+        };
+    };
+
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    if let Some(displayname) = displayname {
+        let user:User = match collection.find_one(doc!{ "suid": &claims.suid, "project_id": &project.id }, None) {
+            Ok(Some(e)) => e,
+            _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+        };
+        set.insert("displayname", &displayname);
+        set.extend(search::index_doc(&user.username, searchable_displayname(&user.privacy, &displayname)));
+    };
+
+    let mut update:Document = doc!{};
+    if !set.is_empty() { update.insert("$set", set); };
+    if !unset.is_empty() { update.insert("$unset", unset); };
+    if update.is_empty() { return stream.respond(200, do_json(200, "Success!")); };
+
+    match outbox::record(&project.id, WebhookEvent::AccountUpdated, |users, session| {
+        users.find_one_and_update_with_session(
+            doc!{ "suid": &claims.suid, "project_id": &project.id },
+            update.clone(),
+            FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            session
+        )
+    }) {
+        Ok(Some(_)) => stream.respond(200, do_json(200, "Success!")),
+        Ok(None) => stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Set who can see parts of the callers' profile. The body is a
+    JSON object of parts (`displayname`, `avatar`, `ratings` or a
+    public field of the schema) and "everyone", "friends" or
+    "only_me". Parts left out keep their visibility -*/
+pub(crate) fn set_privacy(stream: &mut Stream) -> () {
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let claims:UserClaims = match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => claims,
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let changes:BTreeMap<String, Value> = match serde_json::from_str(&stream.body) {
+        Ok(e) => e,
+        Err(_) => return stream.respond(400, do_json(400, DICTIONARY.error.profile.invalid_body))
+    };
+
+    let mut set:Document = doc!{};
+    let mut displayname:Option<Visibility> = None;
+    for (part, value) in changes {
+        let known:bool = BUILT_IN_PARTS.contains(&part.as_str())
+            || project.settings.profile_schema.iter().any(|field| field.public && field.name == part);
+        if !known {
+            return stream.respond(400, do_json(400, &DICTIONARY.error.profile.unknown_field.replace("{}", &part)));
+        };
+        let visibility:Visibility = match serde_json::from_value(value) {
+            Ok(e) => e,
+            Err(_) => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.visibility))
+        };
+        if part == "displayname" { displayname = Some(visibility); };
+        set.insert(format!("privacy.{}", part), bson::to_bson(&visibility).unwrap_or_default());
+    };
+    if set.is_empty() { return stream.respond(200, do_json(200, "Success!")); };
+
+    /*- Hiding or showing the displayname changes what search matches -*/
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    if let Some(visibility) = displayname {
+        let user:User = match collection.find_one(doc!{ "suid": &claims.suid, "project_id": &project.id }, None) {
+            Ok(Some(e)) => e,
+            _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+        };
+        let privacy:BTreeMap<String, Visibility> = BTreeMap::from([( "displayname".to_string(), visibility )]);
+        set.extend(search::index_doc(&user.username, searchable_displayname(&privacy, &user.displayname)));
+    };
+
+    match collection.update_one(doc!{ "suid": &claims.suid, "project_id": &project.id }, doc!{ "$set": set }, None) {
+        Ok(result) if result.matched_count == 1 => stream.respond(200, do_json(200, "Success!")),
+        Ok(_) => stream.respond(401, do_json(401, DICTIONARY.error.unauthorized)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(name:&str, kind:FieldKind) -> ProfileField {
+        ProfileField { name: name.to_string(), kind, public: false }
+    }
+
+    #[test]
+    fn values_are_checked_against_their_field() {
+        let bio = field("bio", FieldKind::String { max_len: 5 });
+        assert_eq!(bio.validate(&json!("hello")), Ok(()));
+        assert_eq!(bio.validate(&json!("héllo")), Ok(()));
+        assert_eq!(bio.validate(&json!("hello!")), Err("Profile field bio must be at most 5 characters long".to_string()));
+        assert_eq!(bio.validate(&json!(5)), Err("Profile field bio must be a string".to_string()));
+
+        let level = field("level", FieldKind::Integer { min: Some(1), max: Some(99) });
+        assert_eq!(level.validate(&json!(1)), Ok(()));
+        assert_eq!(level.validate(&json!(99)), Ok(()));
+        assert_eq!(level.validate(&json!(100)), Err("Profile field level must be between 1 and 99".to_string()));
+        assert!(level.validate(&json!(1.5)).is_err());
+        assert!(level.validate(&json!("1")).is_err());
+        let unbounded = field("score", FieldKind::Integer { min: None, max: Some(0) });
+        assert_eq!(unbounded.validate(&json!(i64::MIN)), Ok(()));
+        assert_eq!(unbounded.validate(&json!(1)), Err("Profile field score must be between - and 0".to_string()));
+
+        let public = field("public", FieldKind::Boolean);
+        assert_eq!(public.validate(&json!(true)), Ok(()));
+        assert_eq!(public.validate(&json!("true")), Err("Profile field public must be a boolean".to_string()));
+
+        let class = field("class", FieldKind::Enum { options: vec!["mage".to_string(), "rogue".to_string()] });
+        assert_eq!(class.validate(&json!("rogue")), Ok(()));
+        assert_eq!(class.validate(&json!("Rogue")), Err("Profile field class must be one of mage, rogue".to_string()));
+        assert_eq!(class.validate(&json!(["mage"])), Err("Profile field class must be a string".to_string()));
+    }
+
+    #[test]
+    fn null_removes_any_value() {
+        for kind in [
+            FieldKind::String { max_len: 1 },
+            FieldKind::Integer { min: Some(1), max: Some(1) },
+            FieldKind::Boolean,
+            FieldKind::Enum { options: vec!["a".to_string()] },
+        ] {
+            assert_eq!(field("field", kind).validate(&Value::Null), Ok(()));
+        };
+    }
+
+    #[test]
+    fn schemas_with_valid_fields_are_accepted() {
+        assert_eq!(validate_schema(&[]), Ok(()));
+        assert_eq!(validate_schema(&[
+            field("bio", FieldKind::String { max_len: MAX_STRING_LEN }),
+            field("level", FieldKind::Integer { min: Some(3), max: Some(3) }),
+            field("rank_2", FieldKind::Integer { min: None, max: None }),
+            field("public", FieldKind::Boolean),
+            field("class", FieldKind::Enum { options: vec!["mage".to_string()] }),
+        ]), Ok(()));
+
+        let most:Vec<ProfileField> = (0..MAX_FIELDS).map(|i| field(&format!("field_{}", i), FieldKind::Boolean)).collect();
+        assert_eq!(validate_schema(&most), Ok(()));
+    }
+
+    #[test]
+    fn schemas_with_invalid_fields_are_rejected() {
+        let too_many:Vec<ProfileField> = (0..=MAX_FIELDS).map(|i| field(&format!("field_{}", i), FieldKind::Boolean)).collect();
+        assert_eq!(validate_schema(&too_many), Err(format!("A profile schema can have at most {} fields", MAX_FIELDS)));
+
+        /*- Names which don't match the pattern, shadow
+            SafeUser or are used twice -*/
+        for name in ["", "Bio", "2fa", "_bio", "bio-text", &"a".repeat(33), "username", "avatar"] {
+            assert_eq!(
+                validate_schema(&[field(name, FieldKind::Boolean)]),
+                Err(format!("Field name {} is invalid, reserved or used twice", name)),
+                "{}", name
+            );
+        };
+        assert_eq!(
+            validate_schema(&[field("bio", FieldKind::Boolean), field("bio", FieldKind::Boolean)]),
+            Err("Field name bio is invalid, reserved or used twice".to_string())
+        );
+
+        for kind in [
+            FieldKind::String { max_len: 0 },
+            FieldKind::String { max_len: MAX_STRING_LEN + 1 },
+            FieldKind::Integer { min: Some(2), max: Some(1) },
+            FieldKind::Enum { options: vec![] },
+        ] {
+            assert_eq!(validate_schema(&[field("bio", kind)]), Err("Field bio has an invalid definition".to_string()));
+        };
+    }
+}
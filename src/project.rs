@@ -0,0 +1,342 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, api_key,
+    api::do_json,
+    dict::DICTIONARY,
+    user::{ User, AuthorizationStatus, authenticate, is_admin, generate_suid },
+    profile::ProfileField,
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use std::{ env, collections::HashMap, sync::OnceLock };
+use rand;
+use jsonwebtoken::decode_header;
+use rsa::{
+    RsaPrivateKey,
+    pkcs1::{ EncodeRsaPrivateKey, LineEnding },
+};
+use mongodb::{
+    bson::{ doc, Document },
+    sync::Collection,
+};
+
+/*- Constants -*/
+pub(crate) const DEFAULT_PROJECT_ID:&'static str = "default";
+pub(crate) const PROJECT_HEADER:&'static str     = "X-Project";
+const PROJECT_COLLECTION:&'static str            = "projects";
+
+/*- Where the signing key of the default project, which all
+    tokens were signed with before projects existed, is read from -*/
+pub(crate) const SIGNING_KEY_VAR:&'static str               = "DEFAULT_SIGNING_KEY";
+
+/*- Projects are subdomains of this domain, if it's set
+    (as in "accounts.example.com" for "mygame.accounts.example.com") -*/
+const BASE_DOMAIN_VAR:&'static str               = "PROJECT_BASE_DOMAIN";
+
+/// # Project
+/// A tenant. Every user belongs to exactly one project,
+/// usernames and emails are unique per project, and each
+/// project signs its tokens with its own key and audience.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Project {
+    pub id            : String,
+    pub name          : String,
+    pub token_audience: String,
+    pub signing_key   : String,
+    pub settings      : ProjectSettings,
+
+    /*- PKCS#1 PEM of the RSA key ID tokens are signed
+        with. Generated on first use, see `oidc_key` -*/
+    #[serde(default)]
+    pub oidc_key_pem  : Option<String>,
+}
+
+/*- Per-project settings -*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ProjectSettings {
+    pub password_min_len: usize,
+    pub password_max_len: usize,
+
+    /*- Origins allowed to call the api from a browser.
+        Empty means any origin is allowed -*/
+    pub allowed_origins : Vec<String>,
+
+    /*- Extra profile fields users of the project have -*/
+    #[serde(default)]
+    pub profile_schema  : Vec<ProfileField>,
+}
+
+/*- Function implementations -*/
+impl Default for ProjectSettings {
+    fn default() -> Self {
+        ProjectSettings {
+            password_min_len: 8,
+            password_max_len: 128,
+            allowed_origins : Vec::new(),
+            profile_schema  : Vec::new(),
+        }
+    }
+}
+impl Project {
+
+    /*- The project every request without a project
+        belongs to, so that single-app deployments
+        keep working without any setup -*/
+    pub fn default_project() -> Self {
+        Project {
+            id            : DEFAULT_PROJECT_ID.to_string(),
+            name          : DEFAULT_PROJECT_ID.to_string(),
+            token_audience: DEFAULT_PROJECT_ID.to_string(),
+            signing_key   : default_signing_key().to_string(),
+            settings      : ProjectSettings::default(),
+            oidc_key_pem  : None,
+        }
+    }
+
+    /*- Check a password against the projects' password policy -*/
+    pub fn check_password(&self, password:&str) -> Result<(), String> {
+        let len:usize = password.chars().count();
+        if len < self.settings.password_min_len {
+            return Err(DICTIONARY.error.password.len_min.replace("{}", &self.settings.password_min_len.to_string()));
+        };
+        if len > self.settings.password_max_len {
+            return Err(DICTIONARY.error.password.len_max.replace("{}", &self.settings.password_max_len.to_string()));
+        };
+
+        Ok(())
+    }
+
+    /*- If a browser origin may call the api -*/
+    pub fn allows_origin(&self, origin:&str) -> bool {
+        self.settings.allowed_origins.is_empty()
+            || self.settings.allowed_origins.iter().any(|allowed| allowed == origin || allowed == "*")
+    }
+}
+
+/*- Quick way of getting the project collection -*/
+fn collection() -> Collection<Project> {
+    utils::establish_mclient::<Project>(PROJECT_COLLECTION)
+}
+
+/*- The project a token says it was issued by (its `kid`). Only
+    a hint until the token has been validated with that projects' key -*/
+pub(crate) fn id_of_token(token:&str) -> Option<String> {
+    decode_header(token).ok().and_then(|header| header.kid)
+}
+
+/*- The signing key of the default project. The server refuses
+    to start without one, see `main` -*/
+pub(crate) fn default_signing_key() -> &'static str {
+    static KEY:OnceLock<String> = OnceLock::new();
+    KEY.get_or_init(|| env::var(SIGNING_KEY_VAR).ok()
+        .filter(|key| !key.trim().is_empty())
+        .unwrap_or_else(|| panic!("{} has to be set", SIGNING_KEY_VAR)))
+}
+
+/*- If the default projects' signing key is set -*/
+pub(crate) fn has_default_signing_key() -> bool {
+    env::var(SIGNING_KEY_VAR).map_or(false, |key| !key.trim().is_empty())
+}
+
+/*- The project a subdomain of the base domain is for. Only
+    one label deep, "a.b.accounts.example.com" is no project -*/
+fn subdomain_of(host:&str, base_domain:&str) -> Option<String> {
+    let host:String = host.split(':').next().unwrap_or(host).trim_end_matches('.').to_ascii_lowercase();
+    let base_domain:String = base_domain.trim().trim_matches('.').to_ascii_lowercase();
+    if base_domain.is_empty() { return None; };
+
+    match host.strip_suffix(&base_domain).and_then(|rest| rest.strip_suffix('.')) {
+        Some(label) if !label.is_empty() && !label.contains('.') => Some(label.to_string()),
+        _ => None
+    }
+}
+
+/*- Which project a request claims to be for. Taken from the
+    X-Project header, or else the subdomain of PROJECT_BASE_DOMAIN
+    the Host is (as in "mygame.accounts.example.com"), or else
+    the project the bearer token names. Authentication and the
+    handlers both go through here, so that a token is always
+    checked against the project the handler then acts on -*/
+pub(crate) fn requested_id(headers:&HashMap<&str, &str>) -> Option<String> {
+    if let Some(id) = utils::get_header_ignore_caps(headers, PROJECT_HEADER) {
+        return Some(id.trim().to_string());
+    };
+
+    /*- Subdomain -*/
+    if let (Some(host), Ok(base_domain)) = (utils::get_header_ignore_caps(headers, "Host"), env::var(BASE_DOMAIN_VAR)) {
+        if let Some(id) = subdomain_of(host, &base_domain) { return Some(id); };
+    };
+
+    /*- Requests which don't name a project (as in ones from
+        OIDC client libraries) are for the project the token names -*/
+    let token:&str = utils::get_header_ignore_caps(headers, "Authorization")?;
+    let token:&str = token.strip_prefix("Bearer ").unwrap_or(token).trim();
+    match token.starts_with(api_key::KEY_PREFIX) {
+        true => None,
+        false => id_of_token(token)
+    }
+}
+
+/*- Get a project by id -*/
+pub(crate) fn get(id:&str) -> Option<Project> {
+    if id == DEFAULT_PROJECT_ID {
+        /*- The key always comes from the environment, so that it can be rotated -*/
+        let mut project:Project = collection().find_one(doc!{ "id": id }, None).ok().flatten().unwrap_or(Project::default_project());
+        project.signing_key = default_signing_key().to_string();
+        return Some(project);
+    };
+
+    collection().find_one(doc!{ "id": id }, None).ok().flatten()
+}
+
+/*- Resolve the project a request is for. Requests which
+    don't name a project belong to the default project -*/
+pub(crate) fn resolve(headers:&HashMap<&str, &str>) -> Option<Project> {
+    match requested_id(headers) {
+        Some(id) => get(&id),
+        None => get(DEFAULT_PROJECT_ID)
+    }
+}
+
+/*- Resolve the project, or respond 404 -*/
+pub(crate) fn require(stream:&mut Stream) -> Option<Project> {
+    match resolve(&stream.headers) {
+        Some(project) => Some(project),
+        None => {
+            stream.respond(404, do_json(404, DICTIONARY.error.not_found.project));
+            None
+        }
+    }
+}
+
+/*- The default project only exists in the database once it
+    has to. Its signing key comes from the environment and
+    is never written to the database -*/
+fn store(project:&Project) -> bool {
+    match collection().find_one(doc!{ "id": &project.id }, None) {
+        Ok(Some(_)) => true,
+        Ok(None) => {
+            let mut project:Project = project.clone();
+            if project.id == DEFAULT_PROJECT_ID { project.signing_key = String::new(); };
+            collection().insert_one(&project, None).is_ok()
+        },
+        Err(_) => false
+    }
+}
+
+/*- Get the projects' ID token signing key, generating
+    and storing one the first time it's needed -*/
+pub(crate) fn oidc_key(project:&Project) -> Option<String> {
+    if let Some(pem) = &project.oidc_key_pem { return Some(pem.clone()); };
+
+    if !store(project) { return None; };
+
+    /*- Generate. If another instance got there first its
+        key is kept, since the update only matches when no
+        key is set -*/
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).ok()?;
+    let pem:String = key.to_pkcs1_pem(LineEnding::LF).ok()?.to_string();
+    collection().update_one(
+        doc!{ "id": &project.id, "oidc_key_pem": null },
+        doc!{ "$set": { "oidc_key_pem": pem } },
+        None
+    ).ok()?;
+
+    get(&project.id)?.oidc_key_pem
+}
+
+/*- Update some of a projects' settings, as in
+    `doc!{ "settings.profile_schema": ... }` -*/
+pub(crate) fn save_settings(id:&str, settings:Document) -> bool {
+    let project:Project = match get(id) {
+        Some(e) => e,
+        None => return false
+    };
+
+    if !store(&project) { return false; };
+
+    collection().update_one(doc!{ "id": id }, doc!{ "$set": settings }, None).is_ok()
+}
+
+/*- Users stored before projects existed have no project_id,
+    which queries by project wouldn't match. Move them
+    into the default project -*/
+pub(crate) fn migrate_legacy_users() -> () {
+    utils::establish_mclient::<User>("users").update_many(
+        doc!{ "project_id": { "$exists": false } },
+        doc!{ "$set": { "project_id": DEFAULT_PROJECT_ID } },
+        None
+    ).ok();
+}
+
+/*- Create a new project. Admins of the default project only -*/
+pub(crate) fn create(stream: &mut Stream) -> () {
+    match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if claims.aud == DEFAULT_PROJECT_ID && is_admin(&claims.suid) => (),
+        _ => return stream.respond(401, do_json(401, DICTIONARY.error.unauthorized))
+    };
+    let (id, name) = match utils::get_headers_checked(&stream.headers, &["id", "name"]) {
+        Some(values) => (values[0].to_string(), values[1].to_string()),
+        None => return stream.respond(400, do_json(400, "Invalid headers"))
+    };
+
+    /*- Project ids are used as subdomains -*/
+    if id.is_empty() || id.len() > 63 || !id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return stream.respond(400, do_json(400, DICTIONARY.error.invalid.project_id));
+    };
+    if get(&id).is_some() {
+        return stream.respond(409, do_json(409, DICTIONARY.error.in_use.project_id));
+    };
+
+    /*- Settings -*/
+    let mut settings:ProjectSettings = ProjectSettings::default();
+    if let Some(min) = stream.headers.get("password-min-len").and_then(|e| e.parse().ok()) {
+        settings.password_min_len = min;
+    };
+    if let Some(max) = stream.headers.get("password-max-len").and_then(|e| e.parse().ok()) {
+        settings.password_max_len = max;
+    };
+    if let Some(origins) = stream.headers.get("allowed-origins") {
+        settings.allowed_origins = origins.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect();
+    };
+
+    let project = Project {
+        token_audience: id.clone(),
+        signing_key   : format!("{}{}", generate_suid(), generate_suid()),
+        id,
+        name,
+        settings,
+        oidc_key_pem  : None,
+    };
+    match collection().insert_one(&project, None) {
+        Ok(_) => stream.respond(200, Respond::new().json(&format!(
+            "{{\"status\": 200, \"id\": \"{}\", \"token_audience\": \"{}\"}}",
+            project.id, project.token_audience
+        ))),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_subdomains_of_the_base_domain_are_projects() {
+        assert_eq!(subdomain_of("mygame.accounts.example.com", "accounts.example.com"), Some("mygame".to_string()));
+        assert_eq!(subdomain_of("MyGame.Accounts.example.com:8081", "accounts.example.com"), Some("mygame".to_string()));
+        assert_eq!(subdomain_of("api.example.com", "accounts.example.com"), None);
+        assert_eq!(subdomain_of("accounts.example.com", "accounts.example.com"), None);
+        assert_eq!(subdomain_of("a.b.accounts.example.com", "accounts.example.com"), None);
+        assert_eq!(subdomain_of("evilaccounts.example.com", "accounts.example.com"), None);
+        assert_eq!(subdomain_of("mygame.accounts.example.com", ""), None);
+    }
+}
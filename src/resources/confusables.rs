@@ -0,0 +1,25 @@
+/*- A subset of the Unicode confusables table (UTS #39),
+    mapping lowercase look-alike characters to the latin
+    prototype they are most often mistaken for -*/
+pub(crate) const CONFUSABLES:&'static [(char, &'static str)] = &[
+    /*- Cyrillic -*/
+    ('а', "a"), ('в', "b"), ('с', "c"), ('ԁ', "d"), ('е', "e"),
+    ('ё', "e"), ('һ', "h"), ('і', "i"), ('ї', "i"), ('ј', "j"),
+    ('к', "k"), ('ӏ', "l"), ('м', "m"), ('н', "h"), ('о', "o"),
+    ('р', "p"), ('ԛ', "q"), ('г', "r"), ('ѕ', "s"), ('т', "t"),
+    ('ц', "u"), ('ѵ', "v"), ('ԝ', "w"), ('х', "x"), ('у', "y"),
+    ('ѡ', "w"), ('ь', "b"), ('ы', "bl"),
+
+    /*- Greek -*/
+    ('α', "a"), ('β', "b"), ('ε', "e"), ('η', "n"), ('ι', "i"),
+    ('κ', "k"), ('ν', "v"), ('ο', "o"), ('ρ', "p"), ('τ', "t"),
+    ('υ', "u"), ('χ', "x"), ('γ', "y"), ('ω', "w"),
+
+    /*- Latin look-alikes -*/
+    ('ı', "i"), ('ɩ', "i"), ('ł', "l"), ('ƚ', "l"), ('ɑ', "a"),
+    ('ɡ', "g"), ('ʀ', "r"), ('ꜱ', "s"), ('ᴜ', "u"),
+
+    /*- Digits and symbols -*/
+    ('0', "o"), ('1', "l"), ('|', "l"), ('５', "5"),
+    ('０', "o"), ('１', "l"), ('＿', "_"), ('－', "-"), ('．', "."),
+];
@@ -4,16 +4,46 @@ use std::{collections::HashMap, hash::Hash};
 /*- A dictionary of phrases that are ex
     responded with inside of this project -*/
 pub struct Dictionary<'lf> {
-    pub error:Error<'lf>
+    pub error:Error<'lf>,
+    pub info:Info<'lf>,
+    pub mail:MailText<'lf>,
 }
 
 /*- (ERR) Error messages -*/
 pub struct Error<'lf> {
     pub in_use: InUse<'lf>,
     pub password: Password<'lf>,
+    pub username: Username<'lf>,
+    pub image: Image<'lf>,
     pub invalid: Invalid<'lf>,
+    pub not_found: NotFound<'lf>,
+    pub oauth: OAuth<'lf>,
+    pub social: Social<'lf>,
+    pub profile: Profile<'lf>,
     pub login:&'lf str,
     pub unauthorized:&'lf str,
+    pub password_reset_required:&'lf str,
+    pub magic_link:&'lf str,
+    pub not_guest:&'lf str,
+    pub not_connected:&'lf str,
+    pub too_many_connections:&'lf str,
+    pub too_many_webhooks:&'lf str,
+    pub guest_deletion:&'lf str,
+}
+
+/*- (INFO) Non-error messages -*/
+pub struct Info<'lf> {
+    pub account_secured:&'lf str,
+    pub magic_link_sent:&'lf str,
+}
+
+/*- (MAIL) Mail subjects and bodies. Bodies
+    are filled in by replacing each {} in order -*/
+pub struct MailText<'lf> {
+    pub new_device_subject:&'lf str,
+    pub new_device_body:&'lf str,
+    pub magic_link_subject:&'lf str,
+    pub magic_link_body:&'lf str,
 }
 
 /*- (ERR) When something with the password has gone wrong -*/
@@ -22,16 +52,82 @@ pub struct Password<'lf> {
     pub len_max: &'lf str,
 }
 
+/*- (ERR) When the username breaks the username policy -*/
+pub struct Username<'lf> {
+    pub len_min: &'lf str,
+    pub len_max: &'lf str,
+    pub reserved: &'lf str,
+}
+
+/*- (ERR) When an uploaded image can't be used -*/
+pub struct Image<'lf> {
+    pub empty: &'lf str,
+    pub too_large: &'lf str,
+    pub unsupported_format: &'lf str,
+    pub dimensions: &'lf str,
+    pub undecodable: &'lf str,
+}
+
 /*- (ERR) When some parameters are already in use -*/
 pub struct InUse<'lf> {
     pub email:&'lf str,
-    pub username:&'lf str
+    pub username:&'lf str,
+    pub project_id:&'lf str
 }
 
 /*- (ERR) When some parameters are invalid -*/
 pub struct Invalid<'lf> {
     pub email:&'lf str,
-    pub username:&'lf str
+    pub username:&'lf str,
+    pub image_size:&'lf str,
+    pub project_id:&'lf str,
+    pub api_key_scope:&'lf str,
+    pub presence_status:&'lf str,
+    pub search_query:&'lf str,
+    pub visibility:&'lf str,
+    pub webhook_url:&'lf str,
+    pub webhook_events:&'lf str,
+    pub cursor:&'lf str
+}
+
+/*- (ERR) When something requested doesn't exist -*/
+pub struct NotFound<'lf> {
+    pub session:&'lf str,
+    pub project:&'lf str,
+    pub api_key:&'lf str,
+    pub user:&'lf str,
+    pub friend_request:&'lf str,
+    pub block:&'lf str,
+    pub webhook:&'lf str,
+    pub delivery:&'lf str
+}
+
+/*- (ERR) When an OAuth request is invalid -*/
+pub struct OAuth<'lf> {
+    pub redirect_uri:&'lf str,
+    pub scope:&'lf str
+}
+
+/*- (ERR) When a friend request or follow can't be made -*/
+pub struct Social<'lf> {
+    pub self_target:&'lf str,
+    pub already_friends:&'lf str,
+    pub request_exists:&'lf str,
+    pub not_friends:&'lf str
+}
+
+/*- (ERR) When a profile or profile schema is invalid.
+    Filled in by replacing each {} in order -*/
+pub struct Profile<'lf> {
+    pub invalid_body:&'lf str,
+    pub unknown_field:&'lf str,
+    pub wrong_type:&'lf str,
+    pub too_long:&'lf str,
+    pub out_of_range:&'lf str,
+    pub not_an_option:&'lf str,
+    pub too_many_fields:&'lf str,
+    pub field_name:&'lf str,
+    pub field_definition:&'lf str
 }
 
 /*- Create the dictionary -*/
@@ -39,17 +135,87 @@ pub(crate) const DICTIONARY:Dictionary = Dictionary {
     error: Error { 
         in_use: InUse {
             email: "Email is already in use",
-            username: "Username is already in use"
+            username: "Username is already in use",
+            project_id: "Project id is already in use"
         },
         password: Password {
             len_min: "Password must be atleast {} characters long",
             len_max: "Password must be less than {} characters long",
         },
+        username: Username {
+            len_min: "Username must be atleast {} characters long",
+            len_max: "Username must be at most {} characters long",
+            reserved: "Username is reserved",
+        },
+        image: Image {
+            empty: "No image was uploaded",
+            too_large: "Image must be smaller than {} MB",
+            unsupported_format: "Image format is not supported, use PNG, JPEG, GIF, WebP or BMP",
+            dimensions: "Image can't be wider or taller than {} pixels",
+            undecodable: "Image could not be decoded",
+        },
         invalid: Invalid {
             email: "Email is invalid",
-            username: "Username is invalid"
+            username: "Username is invalid",
+            image_size: "Image size must be one of 32, 64, 128 or 256",
+            project_id: "Project id may only contain lowercase letters, digits and dashes",
+            api_key_scope: "Scopes must be one or more of *, openid, profile, email, scores or changes. Project keys can't use *",
+            presence_status: "Status must be one of online, away or in_game",
+            search_query: "Search query must be between 1 and 64 characters long",
+            visibility: "Visibility must be one of everyone, friends or only_me",
+            webhook_url: "Webhook URL must be an http or https URL of a public address",
+            webhook_events: "Events must be one or more of account.created, account.verified, account.updated, account.deleted, password.changed or avatar.uploaded",
+            cursor: "Cursor must be the seq of a change"
+        },
+        not_found: NotFound {
+            session: "Session not found",
+            project: "Project not found",
+            api_key: "API key not found",
+            user: "User not found",
+            friend_request: "Friend request not found",
+            block: "User is not blocked or muted",
+            webhook: "Webhook not found",
+            delivery: "Webhook delivery not found"
+        },
+        oauth: OAuth {
+            redirect_uri: "Redirect URI is invalid or not registered",
+            scope: "Requested scope is invalid"
+        },
+        social: Social {
+            self_target: "You can't do that to yourself",
+            already_friends: "You are already friends",
+            request_exists: "A friend request was already sent",
+            not_friends: "You are not friends"
+        },
+        profile: Profile {
+            invalid_body: "Body must be valid JSON",
+            unknown_field: "Unknown profile field {}",
+            wrong_type: "Profile field {} must be a {}",
+            too_long: "Profile field {} must be at most {} characters long",
+            out_of_range: "Profile field {} must be between {} and {}",
+            not_an_option: "Profile field {} must be one of {}",
+            too_many_fields: "A profile schema can have at most {} fields",
+            field_name: "Field name {} is invalid, reserved or used twice",
+            field_definition: "Field {} has an invalid definition"
         },
         login: "Email or password is incorrect.",
-        unauthorized: "Unauthorized."
+        unauthorized: "Unauthorized.",
+        password_reset_required: "Password must be reset before logging in.",
+        magic_link: "Sign-in link is invalid, expired or already used.",
+        not_guest: "Account is not a guest account.",
+        not_connected: "Connect to presence/connect first.",
+        too_many_connections: "Too many presence connections are open, close one first.",
+        too_many_webhooks: "The project already has the most webhooks it can have.",
+        guest_deletion: "Confirm by sending the guest username in the confirm header."
+    },
+    info: Info {
+        account_secured: "The session was signed out. Set a new password using the reset token.",
+        magic_link_sent: "If an account uses that email, a sign-in link was sent to it."
+    },
+    mail: MailText {
+        new_device_subject: "New sign-in to your account",
+        new_device_body: "Hi {},\n\nYour account was just signed in to from a device or network we haven't seen before.\n\nDevice: {}\nIP address: {}\n\nIf this was you, you can ignore this mail. If it wasn't, open the link below to sign that session out and set a new password:\n\n{}\n",
+        magic_link_subject: "Your sign-in link",
+        magic_link_body: "Hi {},\n\nOpen the link below to sign in. It works once, and only for the next 15 minutes.\n\n{}\n\nIf you didn't ask for this, you can ignore this mail.\n"
     }
 };
\ No newline at end of file
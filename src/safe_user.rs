@@ -1,5 +1,5 @@
 /*- Imports -*/
-use std::fmt;
+use std::{ fmt, collections::BTreeMap };
 use serde::{ Serialize, Deserialize };
 use crate::user::User;
 
@@ -14,13 +14,13 @@ pub(crate) struct SafeUser {
     pub username    : String,
     pub displayname : String,
     pub suid        : String,
+
+    /*- Public fields of the projects' profile schema -*/
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profile     : BTreeMap<String, serde_json::Value>,
 }
 
 /*- Convert user to SafeUser -*/
 pub(crate) fn convert_user(user: User) -> SafeUser {
-    SafeUser {
-        username    : user.username,
-        displayname : user.displayname,
-        suid        : user.suid,
-    }
+    User::to_safe(user)
 }
@@ -0,0 +1,328 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, social, block,
+    api::do_json,
+    profile::{ Viewer, searchable_displayname },
+    dict::DICTIONARY,
+    project::{ self, Project },
+    safe_user::SafeUser,
+    user::User,
+    username::skeleton,
+};
+use responder::prelude::*;
+use serde_json;
+use regex;
+use mongodb::{
+    bson::{ self, doc, Document },
+    options::{ FindOptions, IndexOptions },
+    sync::Collection,
+    IndexModel,
+};
+use std::collections::HashSet;
+
+/*- Constants -*/
+const MAX_QUERY_LEN:usize     = 64;
+const DEFAULT_PAGE_SIZE:i64   = 20;
+const MAX_PAGE_SIZE:i64       = 100;
+
+/*- Upper bounds on how many users are ranked per search.
+    Results past these aren't worth paging to anyway -*/
+const PREFIX_CANDIDATES:i64   = 500;
+const FUZZY_CANDIDATES:i64    = 500;
+
+/*- Queries shorter than this are only prefix matched -*/
+const MIN_FUZZY_LEN:usize     = 3;
+
+/*- Search keys of a user, stored on the user as `search_terms`
+    (for prefix matches) and `search_trigrams` (for finding
+    fuzzy match candidates). Both are multikey indexed -*/
+pub(crate) fn terms(username:&str, displayname:&str) -> Vec<String> {
+    let mut terms:Vec<String> = vec![skeleton(username)];
+    let displayname:String = skeleton(displayname.trim());
+    terms.extend(displayname.split_whitespace().map(String::from));
+    terms.push(displayname);
+
+    let mut seen:HashSet<String> = HashSet::new();
+    terms.into_iter()
+        .filter(|term| !term.is_empty() && seen.insert(term.clone()))
+        .collect()
+}
+
+/*- Trigrams of a term, padded so that the start of
+    a term weighs more than the rest of it -*/
+fn term_trigrams(term:&str) -> Vec<String> {
+    let padded:Vec<char> = format!("  {} ", term).chars().collect();
+    padded.windows(3).map(|window| window.iter().collect()).collect()
+}
+
+/*- Trigrams of a query, without the padding. Padded trigrams
+    like "  j" are shared by every user whose term starts with
+    the same letter, so looking them up finds nearly everyone -*/
+fn query_trigrams(query:&str) -> Vec<String> {
+    let chars:Vec<char> = query.chars().collect();
+    let mut seen:HashSet<String> = HashSet::new();
+    chars.windows(3)
+        .map(|window| window.iter().collect::<String>())
+        .filter(|trigram| seen.insert(trigram.clone()))
+        .collect()
+}
+pub(crate) fn trigrams(terms:&[String]) -> Vec<String> {
+    let mut seen:HashSet<String> = HashSet::new();
+    terms.iter()
+        .flat_map(|term| term_trigrams(term))
+        .filter(|trigram| seen.insert(trigram.clone()))
+        .collect()
+}
+
+/*- The `$set` document keeping a users' search keys in sync
+    with their username and displayname. Use whenever either, or
+    who can see the displayname, changes. Pass the displayname
+    through `searchable_displayname` first -*/
+pub(crate) fn index_doc(username:&str, displayname:&str) -> Document {
+    let terms:Vec<String> = terms(username, displayname);
+    doc!{
+        "search_trigrams": trigrams(&terms),
+        "search_terms"   : terms,
+    }
+}
+
+/*- Fill in the search keys of a user about to be inserted -*/
+pub(crate) fn index(user:&mut User) -> () {
+    user.search_terms = terms(&user.username, searchable_displayname(&user.privacy, &user.displayname));
+    user.search_trigrams = trigrams(&user.search_terms);
+}
+
+/*- Create the search indexes, and fill in the search keys
+    of users from before search existed -*/
+pub(crate) fn ensure_indexes() -> () {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    for keys in [
+        doc!{ "project_id": 1, "search_terms": 1 },
+        doc!{ "project_id": 1, "search_trigrams": 1 },
+    ] {
+        collection.create_index(IndexModel::builder().keys(keys).build(), None).ok();
+    };
+
+    if let Ok(cursor) = collection.find(doc!{ "search_terms": { "$exists": false } }, None) {
+        for user in cursor.flatten() {
+            collection.update_one(
+                doc!{ "suid": &user.suid },
+                doc!{ "$set": index_doc(&user.username, searchable_displayname(&user.privacy, &user.displayname)) },
+                None
+            ).ok();
+        };
+    };
+}
+
+/*- Edit distance allowing swapped neighbours
+    (optimal string alignment distance) -*/
+fn distance(a:&[char], b:&[char]) -> usize {
+    let mut rows:Vec<Vec<usize>> = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() { rows[i][0] = i; };
+    for j in 0..=b.len() { rows[0][j] = j; };
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost:usize = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            rows[i][j] = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                rows[i][j] = rows[i][j].min(rows[i - 2][j - 2] + 1);
+            };
+        };
+    };
+
+    rows[a.len()][b.len()]
+}
+
+/*- How well a user matches a query. Lower is better, and
+    None means no match. Exact usernames come first, then
+    username prefixes, then displayname prefixes, then typos -*/
+fn rank(query:&str, user:&User) -> Option<(u8, usize)> {
+    let username:String = skeleton(&user.username);
+    if username == query { return Some((0, 0)); };
+    if username.starts_with(query) { return Some((1, username.len() - query.len())); };
+
+    let terms:Vec<String> = terms(&user.username, searchable_displayname(&user.privacy, &user.displayname));
+    if terms.iter().any(|term| term.starts_with(query)) { return Some((2, 0)); };
+
+    /*- Typos. Compare against whole terms, and against the
+        start of terms, so that "jonh" finds "johnathan" -*/
+    let query_chars:Vec<char> = query.chars().collect();
+    if query_chars.len() < MIN_FUZZY_LEN { return None; };
+    let max_distance:usize = (query_chars.len() / 4).max(1);
+    terms.iter()
+        .filter_map(|term| {
+            let term_chars:Vec<char> = term.chars().collect();
+            let prefix:&[char] = &term_chars[..term_chars.len().min(query_chars.len())];
+            match (distance(&query_chars, &term_chars), distance(&query_chars, prefix)) {
+                (whole, _) if whole <= max_distance => Some(whole * 2),
+                (_, prefix) if prefix <= max_distance => Some(prefix * 2 + 1),
+                _ => None
+            }
+        })
+        .min()
+        .map(|distance| (3, distance))
+}
+
+/*- Search users by username and displayname. The query is
+    in `?q=`, or in the `q` header. Responds with ranked
+    SafeUsers, paginated with the `offset` and `limit` headers -*/
+pub(crate) fn search(stream: &mut Stream) -> () {
+    /*- The route param is the rest of the path, including the
+        query string, as in "search?q=abc" -*/
+    let param:String = stream.params.get("search").map(|e| e.to_string()).unwrap_or_default();
+    let (path, query) = utils::split_query(&param);
+    if path != "search" { return utils::respond_status(stream, 404); };
+    let q:String = match query.get("q").map(|e| utils::percent_decode(e)).or_else(|| stream.headers.get("q").map(|e| e.to_string())) {
+        Some(q) if !q.trim().is_empty() && q.len() <= MAX_QUERY_LEN => skeleton(q.trim()),
+        _ => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.search_query))
+    };
+
+    let project:Project = match project::require(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+
+    /*- Never show guests, or users the caller is blocked with -*/
+    let viewer:Viewer = Viewer::from_headers(&project.id, &stream.headers);
+    let mut base:Document = doc!{ "project_id": &project.id, "guest": { "$ne": true } };
+    if let Some(caller) = &viewer.suid {
+        base.insert("suid", doc!{ "$nin": block::hidden_from(&project.id, caller) });
+    };
+
+    /*- Exact matches, so that they can't be crowded
+        out of the prefix candidates by longer terms -*/
+    let mut exact_filter:Document = base.clone();
+    exact_filter.insert("search_terms", &q);
+    let mut candidates:Vec<User> = match collection.find(exact_filter, FindOptions::builder().limit(PREFIX_CANDIDATES).build()) {
+        Ok(cursor) => cursor.flatten().collect(),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- Prefix candidates, shortest matching term first. An anchored,
+        case sensitive regex on the lowercased terms is a range scan
+        of the index, and the sort only keeps the first few -*/
+    let mut prefix_filter:Document = base.clone();
+    prefix_filter.insert("search_terms", doc!{ "$regex": format!("^{}", regex::escape(&q)) });
+    let pipeline:Vec<Document> = vec![
+        doc!{ "$match": prefix_filter },
+        doc!{ "$addFields": { "search_match_len": { "$min": { "$map": {
+            "input": { "$filter": {
+                "input": "$search_terms",
+                "as"   : "term",
+                "cond" : { "$eq": [ { "$indexOfCP": [ "$$term", &q ] }, 0 ] }
+            } },
+            "as": "term",
+            "in": { "$strLenCP": "$$term" }
+        } } } } },
+        doc!{ "$sort": { "search_match_len": 1 } },
+        doc!{ "$limit": PREFIX_CANDIDATES },
+    ];
+    match collection.aggregate(pipeline, None) {
+        Ok(cursor) => candidates.extend(cursor.flatten().filter_map(|document| bson::from_document::<User>(document).ok())),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    /*- Fuzzy candidates, the users sharing the most trigrams with
+        the query. Looked up by the unpadded trigrams, and scored
+        by the padded ones, so that matching starts weigh more -*/
+    if q.chars().count() >= MIN_FUZZY_LEN {
+        let query_trigrams:Vec<String> = trigrams(&[q.clone()]);
+        let mut fuzzy_filter:Document = base.clone();
+        fuzzy_filter.insert("search_trigrams", doc!{ "$in": self::query_trigrams(&q) });
+        let pipeline:Vec<Document> = vec![
+            doc!{ "$match": fuzzy_filter },
+            doc!{ "$addFields": { "search_overlap": { "$size": { "$setIntersection": [ "$search_trigrams", query_trigrams.clone() ] } } } },
+            doc!{ "$sort": { "search_overlap": -1 } },
+            doc!{ "$limit": FUZZY_CANDIDATES },
+        ];
+        if let Ok(cursor) = collection.aggregate(pipeline, None) {
+            candidates.extend(cursor.flatten().filter_map(|document| bson::from_document::<User>(document).ok()));
+        };
+    };
+
+    /*- Rank -*/
+    let mut seen:HashSet<String> = HashSet::new();
+    let mut ranked:Vec<((u8, usize), User)> = candidates.into_iter()
+        .filter(|user| seen.insert(user.suid.clone()))
+        .filter_map(|user| rank(&q, &user).map(|rank| (rank, user)))
+        .collect();
+    ranked.sort_by(|(a_rank, a), (b_rank, b)| {
+        a_rank.cmp(b_rank)
+            .then(a.username.len().cmp(&b.username.len()))
+            .then(a.username.cmp(&b.username))
+    });
+
+    let total:usize = ranked.len();
+    let items:Vec<SafeUser> = ranked.into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|(_, user)| User::to_safe_for(user, &project.settings.profile_schema, &viewer))
+        .collect();
+    let next:Option<u64> = match (offset + limit as u64) < total as u64 {
+        true => Some(offset + limit as u64),
+        false => None
+    };
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "items" : items,
+        "offset": offset,
+        "limit" : limit,
+        "next"  : next,
+    }).to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(value:&str) -> Vec<char> { value.chars().collect() }
+    fn user(username:&str, displayname:&str) -> User {
+        User { username: username.to_string(), displayname: displayname.to_string(), ..User::default() }
+    }
+
+    #[test]
+    fn distance_counts_edits_and_swaps() {
+        assert_eq!(distance(&chars("john"), &chars("john")), 0);
+        assert_eq!(distance(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(distance(&chars("jonh"), &chars("john")), 1);
+        assert_eq!(distance(&chars(""), &chars("abc")), 3);
+        assert_eq!(distance(&chars("abc"), &chars("")), 3);
+    }
+
+    #[test]
+    fn exact_usernames_rank_first() {
+        let exact = rank("jo", &user("jo", "Someone")).unwrap();
+        let prefix = rank("jo", &user("john", "Someone")).unwrap();
+        let longer = rank("jo", &user("johnathan", "Someone")).unwrap();
+        let displayname = rank("jo", &user("someone", "Jo Smith")).unwrap();
+        assert_eq!(exact, (0, 0));
+        assert!(exact < prefix && prefix < longer && longer < displayname);
+    }
+
+    #[test]
+    fn typos_match_long_enough_queries_only() {
+        assert_eq!(rank("jonh", &user("john", "")).map(|(tier, _)| tier), Some(3));
+        assert_eq!(rank("jonh", &user("johnathan", "")).map(|(tier, _)| tier), Some(3));
+        assert_eq!(rank("xz", &user("xy", "")), None);
+        assert_eq!(rank("qwerty", &user("john", "")), None);
+    }
+
+    #[test]
+    fn query_trigrams_leave_padding_out() {
+        assert_eq!(query_trigrams("john"), vec!["joh".to_string(), "ohn".to_string()]);
+        assert!(query_trigrams("jo").is_empty());
+        assert!(trigrams(&["john".to_string()]).contains(&"  j".to_string()));
+    }
+}
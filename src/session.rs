@@ -0,0 +1,269 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    api::do_json,
+    audit::{ self, AuditEvent, Outcome, client_ip },
+    dict::DICTIONARY,
+    user::{ UserClaims, AuthorizationStatus, authenticate, generate_suid, TOKEN_LIFETIME },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use mongodb::{
+    bson::{ doc, DateTime },
+    options::{ FindOptions, IndexOptions },
+    sync::Collection,
+    IndexModel,
+};
+use std::time::Duration;
+
+/*- Constants -*/
+pub(crate) const SESSION_COLLECTION:&'static str = "sessions";
+
+/*- Only write last_seen once per this many seconds,
+    so that validating a token isn't always a write -*/
+const LAST_SEEN_RESOLUTION:u64        = 60;
+
+/// # Session
+/// One issued token. Tokens carry the sessions'
+/// `sid` as a claim, and stop being valid once
+/// their session is revoked.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Session {
+    pub sid        : String,
+    pub suid       : String,
+    pub created_at : u64,
+    pub last_seen  : u64,
+    pub ip         : Option<String>,
+    pub user_agent : Option<String>,
+    pub device     : DeviceInfo,
+    pub device_name: Option<String>,
+    pub revoked_at : Option<u64>,
+
+    /*- When the sessions' token expires. A date rather than
+        unix time, so that the TTL index can remove it -*/
+    pub expires_at : DateTime,
+}
+
+/*- What we could make out of the user agent -*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct DeviceInfo {
+    pub browser    : String,
+    pub os         : String,
+    pub device_type: String,
+}
+
+/*- Quick way of getting the session collection -*/
+fn collection() -> Collection<Session> {
+    utils::establish_mclient::<Session>(SESSION_COLLECTION)
+}
+
+/*- Parse a user agent string. This isn't meant to be
+    exhaustive, only good enough for users to recognize
+    their own devices in a list -*/
+pub(crate) fn parse_user_agent(user_agent:&str) -> DeviceInfo {
+    let ua:String = user_agent.to_lowercase();
+    let first_match = |candidates:&[(&str, &str)]| -> String {
+        candidates.iter()
+            .find(|(needle, _)| ua.contains(needle))
+            .map(|(_, name)| name.to_string())
+            .unwrap_or("Unknown".to_string())
+    };
+
+    /*- Order matters, as in Edge claiming to be Chrome
+        and Chrome claiming to be Safari -*/
+    DeviceInfo {
+        browser: first_match(&[
+            ("edg/", "Edge"), ("opr/", "Opera"), ("firefox/", "Firefox"),
+            ("chrome/", "Chrome"), ("safari/", "Safari"), ("curl/", "curl"),
+        ]),
+        os: first_match(&[
+            ("windows", "Windows"), ("android", "Android"), ("iphone", "iOS"),
+            ("ipad", "iOS"), ("mac os", "macOS"), ("cros", "ChromeOS"), ("linux", "Linux"),
+        ]),
+        device_type: first_match(&[
+            ("ipad", "Tablet"), ("tablet", "Tablet"), ("mobile", "Mobile"),
+            ("android", "Mobile"), ("iphone", "Mobile"), ("mozilla", "Desktop"),
+        ]),
+    }
+}
+
+/*- Sessions end with their tokens. Fill in the expiry of
+    sessions from before it was stored, and let MongoDB
+    remove sessions once they've expired -*/
+pub(crate) fn ensure_indexes() -> () {
+    collection().update_many(
+        doc!{ "expires_at": { "$exists": false } },
+        vec![doc!{ "$set": { "expires_at": { "$toDate": {
+            "$multiply": [ { "$add": [ "$created_at", TOKEN_LIFETIME as i64 ] }, 1000i64 ]
+        } } } }],
+        None
+    ).ok();
+
+    if let Err(err) = collection().create_index(
+        IndexModel::builder()
+            .keys(doc!{ "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+            .build(),
+        None
+    ) {
+        eprintln!("Couldn't create the session expiry index: {}", err);
+    };
+
+    /*- Sessions are looked up by user, and stale guests by
+        when their sessions were last seen -*/
+    collection().create_index(
+        IndexModel::builder().keys(doc!{ "suid": 1, "last_seen": -1 }).build(),
+        None
+    ).ok();
+}
+
+/*- Create a session for a token about to be issued -*/
+pub(crate) fn create(stream:&mut Stream, suid:&str, device_name:Option<String>) -> Option<Session> {
+    let now:u64 = utils::get_unix_epoch_time();
+    let user_agent:Option<String> = utils::get_header_ignore_caps(&stream.headers, "User-Agent").map(String::from);
+    let session = Session {
+        sid        : generate_suid(),
+        suid       : suid.to_string(),
+        created_at : now,
+        last_seen  : now,
+        ip         : client_ip(stream),
+        device     : parse_user_agent(user_agent.as_deref().unwrap_or("")),
+        user_agent,
+        device_name,
+        revoked_at : None,
+        expires_at : DateTime::from_millis(((now + TOKEN_LIFETIME) * 1000) as i64),
+    };
+
+    match collection().insert_one(&session, None) {
+        Ok(_) => Some(session),
+        Err(_) => None
+    }
+}
+
+/*- Check if a session is active, and mark it as seen -*/
+pub(crate) fn is_active(sid:&str, suid:&str) -> bool {
+    let collection:Collection<Session> = collection();
+    let active = matches!(
+        collection.find_one(doc!{ "sid": sid, "suid": suid, "revoked_at": null, "expires_at": { "$gt": DateTime::now() } }, None),
+        Ok(Some(_))
+    );
+
+    /*- Mark as seen -*/
+    if active {
+        let now:u64 = utils::get_unix_epoch_time();
+        collection.update_one(
+            doc!{ "sid": sid, "last_seen": { "$lt": (now - LAST_SEEN_RESOLUTION) as i64 } },
+            doc!{ "$set": { "last_seen": now as i64 } },
+            None
+        ).ok();
+    };
+
+    active
+}
+
+/*- Revoke a single session -*/
+pub(crate) fn revoke(sid:&str, suid:&str) -> bool {
+    match collection().update_one(
+        doc!{ "sid": sid, "suid": suid, "revoked_at": null },
+        doc!{ "$set": { "revoked_at": utils::get_unix_epoch_time() as i64 } },
+        None
+    ) {
+        Ok(result) => result.modified_count == 1,
+        Err(_) => false
+    }
+}
+
+/*- Revoke every session of a user -*/
+pub(crate) fn revoke_all(suid:&str) -> () {
+    collection().update_many(
+        doc!{ "suid": suid, "revoked_at": null },
+        doc!{ "$set": { "revoked_at": utils::get_unix_epoch_time() as i64 } },
+        None
+    ).ok();
+}
+
+/*- Every session of a user, revoked or not. Used by exports -*/
+pub(crate) fn all_of(suid:&str) -> Option<Vec<Session>> {
+    utils::find_all(&collection(), doc!{ "suid": suid })
+}
+
+/*- Remove every session of a user. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    collection().delete_many(doc!{ "suid": suid }, None).ok();
+}
+
+/*- Get the callers' claims, or respond 401 -*/
+fn authorized_claims(stream:&mut Stream) -> Option<UserClaims> {
+    match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) => Some(claims),
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        }
+    }
+}
+
+/*- List the callers' active sessions -*/
+pub(crate) fn list(stream: &mut Stream) -> () {
+    let claims:UserClaims = match authorized_claims(stream) {
+        Some(e) => e,
+        None => return
+    };
+
+    /*- Most recently used first -*/
+    let options = FindOptions::builder().sort(doc!{ "last_seen": -1 }).build();
+    let sessions:Vec<serde_json::Value> = match collection().find(doc!{ "suid": &claims.suid, "revoked_at": null, "expires_at": { "$gt": DateTime::now() } }, options) {
+        Ok(cursor) => cursor
+            .flatten()
+            .map(|session| serde_json::json!({
+                "sid"        : session.sid,
+                "created_at" : session.created_at,
+                "last_seen"  : session.last_seen,
+                "expires_at" : session.expires_at.timestamp_millis() / 1000,
+                "ip"         : session.ip,
+                "device"     : session.device,
+                "device_name": session.device_name,
+                "current"    : session.sid == claims.sid,
+            }))
+            .collect(),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+
+    stream.respond(
+        200u16,
+        Respond::new()
+            .json(
+                &serde_json::to_string(
+                    &sessions
+                ).unwrap_or(String::new())
+            )
+    );
+}
+
+/*- Revoke one of the callers' sessions -*/
+pub(crate) fn revoke_session(stream: &mut Stream) -> () {
+    let claims:UserClaims = match authorized_claims(stream) {
+        Some(e) => e,
+        None => return
+    };
+    let sid:String = match stream.params.get("sid") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+
+    if revoke(&sid, &claims.suid) {
+        audit::record(stream, AuditEvent::SessionRevoked, Outcome::Success, Some(&claims.suid), Some(&sid));
+        stream.respond(200, do_json(200, "Success!"));
+    } else {
+        audit::record(stream, AuditEvent::SessionRevoked, Outcome::Failure, Some(&claims.suid), Some(&sid));
+        stream.respond(404, do_json(404, DICTIONARY.error.not_found.session));
+    };
+}
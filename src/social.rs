@@ -0,0 +1,565 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils, block,
+    api::do_json,
+    dict::DICTIONARY,
+    project::{ self, Project },
+    safe_user::SafeUser,
+    profile::{ ProfileField, Viewer },
+    user::{ User, UserClaims, AuthorizationStatus, authenticate },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json;
+use mongodb::{
+    bson::{ doc, Bson, Document },
+    options::{ FindOptions, IndexOptions, UpdateOptions },
+    sync::Collection,
+    IndexModel,
+};
+use std::collections::HashMap;
+
+/*- Constants -*/
+const FRIEND_COLLECTION:&'static str  = "friends";
+const REQUEST_COLLECTION:&'static str = "friend_requests";
+const FOLLOW_COLLECTION:&'static str  = "follows";
+const DEFAULT_PAGE_SIZE:i64           = 50;
+const MAX_PAGE_SIZE:i64               = 200;
+
+/// # Friendship
+/// One direction of a friendship. Accepting a request
+/// stores one for each of the two users, so that either
+/// users' friends are a single indexed query.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Friendship {
+    pub project_id: String,
+    pub suid      : String,
+    pub friend    : String,
+    pub since     : u64,
+}
+
+/*- A friend request waiting for an answer -*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct FriendRequest {
+    pub project_id: String,
+    pub from      : String,
+    pub to        : String,
+    pub created_at: u64,
+}
+
+/*- A one-way follow -*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Follow {
+    pub project_id: String,
+    pub follower  : String,
+    pub followee  : String,
+    pub since     : u64,
+}
+
+/// # Relationship
+/// How a user relates to the caller, included in
+/// profile responses when a token is supplied.
+#[derive(Serialize, Clone, Debug, Default)]
+pub(crate) struct Relationship {
+    pub friends    : bool,
+
+    /*- "incoming" or "outgoing" if a friend request is pending -*/
+    pub request    : Option<&'static str>,
+    pub following  : bool,
+    pub followed_by: bool,
+}
+
+/*- Quick ways of getting the collections -*/
+fn friends() -> Collection<Friendship> {
+    utils::establish_mclient::<Friendship>(FRIEND_COLLECTION)
+}
+fn requests() -> Collection<FriendRequest> {
+    utils::establish_mclient::<FriendRequest>(REQUEST_COLLECTION)
+}
+fn follows() -> Collection<Follow> {
+    utils::establish_mclient::<Follow>(FOLLOW_COLLECTION)
+}
+
+/*- Remove all but one of each set of documents which
+    are the same in the keys of an index to be made unique -*/
+fn remove_duplicates(collection:&Collection<Document>, keys:&Document) -> () {
+    let group:Document = keys.keys()
+        .map(|key| (key.clone(), Bson::String(format!("${}", key))))
+        .collect();
+    let duplicates = collection.aggregate(vec![
+        doc!{ "$group": { "_id": group, "ids": { "$push": "$_id" }, "count": { "$sum": 1 } } },
+        doc!{ "$match": { "count": { "$gt": 1 } } },
+    ], None);
+
+    if let Ok(cursor) = duplicates {
+        for duplicate in cursor.flatten() {
+            if let Ok(ids) = duplicate.get_array("ids") {
+                collection.delete_many(doc!{ "_id": { "$in": ids[1..].to_vec() } }, None).ok();
+            };
+        };
+    };
+}
+
+/*- Make friendships, friend requests and follows unique, so
+    that requests racing each other can't store one twice.
+    Ones stored twice before this are removed first -*/
+pub(crate) fn ensure_indexes() -> () {
+    for (name, keys) in [
+        (FRIEND_COLLECTION, doc!{ "project_id": 1, "suid": 1, "friend": 1 }),
+        (REQUEST_COLLECTION, doc!{ "project_id": 1, "from": 1, "to": 1 }),
+        (FOLLOW_COLLECTION, doc!{ "project_id": 1, "follower": 1, "followee": 1 }),
+    ] {
+        let collection:Collection<Document> = utils::establish_mclient::<Document>(name);
+        remove_duplicates(&collection, &keys);
+        if let Err(err) = collection.create_index(
+            IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None
+        ) {
+            eprintln!("Couldn't make {} unique: {}", name, err);
+        };
+    };
+}
+
+/*- If a document matching the filter exists -*/
+fn exists<T>(collection:Collection<T>, filter:Document) -> bool
+    where T: serde::de::DeserializeOwned + Unpin + Send + Sync
+{
+    matches!(collection.find_one(filter, None), Ok(Some(_)))
+}
+
+/*- Suids of a users' friends -*/
+pub(crate) fn friend_suids(project_id:&str, suid:&str) -> Vec<String> {
+    match friends().find(doc!{ "project_id": project_id, "suid": suid }, None) {
+        Ok(cursor) => cursor.flatten().map(|friendship| friendship.friend).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/*- Suids of who follows a user -*/
+pub(crate) fn follower_suids(project_id:&str, suid:&str) -> Vec<String> {
+    match follows().find(doc!{ "project_id": project_id, "followee": suid }, None) {
+        Ok(cursor) => cursor.flatten().map(|follow| follow.follower).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/*- Suids of who a user follows -*/
+pub(crate) fn followee_suids(project_id:&str, suid:&str) -> Vec<String> {
+    match follows().find(doc!{ "project_id": project_id, "follower": suid }, None) {
+        Ok(cursor) => cursor.flatten().map(|follow| follow.followee).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/*- How `target` relates to `suid` -*/
+pub(crate) fn relationship(project_id:&str, suid:&str, target:&str) -> Relationship {
+    let request:Option<&'static str> = match requests().find_one(doc!{
+        "project_id": project_id,
+        "$or": [
+            { "from": suid, "to": target },
+            { "from": target, "to": suid },
+        ]
+    }, None) {
+        Ok(Some(request)) if request.from == suid => Some("outgoing"),
+        Ok(Some(_)) => Some("incoming"),
+        _ => None
+    };
+
+    Relationship {
+        friends    : exists(friends(), doc!{ "project_id": project_id, "suid": suid, "friend": target }),
+        request,
+        following  : exists(follows(), doc!{ "project_id": project_id, "follower": suid, "followee": target }),
+        followed_by: exists(follows(), doc!{ "project_id": project_id, "follower": target, "followee": suid }),
+    }
+}
+
+/*- The suid of whoever is asking, on endpoints where
+    supplying a token is optional -*/
+pub(crate) fn caller_suid(headers:&HashMap<&str, &str>) -> Option<String> {
+    match authenticate(headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if !claims.suid.is_empty() => Some(claims.suid),
+        _ => None
+    }
+}
+
+/*- Remove friendships, friend requests and follows
+    between two users, as when one blocks the other -*/
+pub(crate) fn sever(project_id:&str, a:&str, b:&str) -> () {
+    friends().delete_many(doc!{ "project_id": project_id, "$or": [
+        { "suid": a, "friend": b },
+        { "suid": b, "friend": a },
+    ] }, None).ok();
+    requests().delete_many(doc!{ "project_id": project_id, "$or": [
+        { "from": a, "to": b },
+        { "from": b, "to": a },
+    ] }, None).ok();
+    follows().delete_many(doc!{ "project_id": project_id, "$or": [
+        { "follower": a, "followee": b },
+        { "follower": b, "followee": a },
+    ] }, None).ok();
+}
+
+/*- Users of a project, in the order of `suids` -*/
+pub(crate) fn users(project_id:&str, suids:&[String]) -> Vec<User> {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    let mut users:HashMap<String, User> = match collection.find(doc!{ "project_id": project_id, "suid": { "$in": suids.to_vec() } }, None) {
+        Ok(cursor) => cursor.flatten().map(|user| (user.suid.clone(), user)).collect(),
+        Err(_) => HashMap::new()
+    };
+
+    suids.iter()
+        .filter_map(|suid| users.remove(suid))
+        .collect()
+}
+
+/*- Safe versions of users as a viewer sees them, in the order of `suids` -*/
+pub(crate) fn safe_users(project_id:&str, suids:&[String], viewer:&Viewer) -> Vec<SafeUser> {
+    let schema:Vec<ProfileField> = project::get(project_id)
+        .map(|project| project.settings.profile_schema)
+        .unwrap_or_default();
+
+    users(project_id, suids)
+        .into_iter()
+        .map(|user| User::to_safe_for(user, &schema, viewer))
+        .collect()
+}
+
+/*- Get the callers' project and claims, or respond -*/
+pub(crate) fn caller(stream:&mut Stream) -> Option<(Project, UserClaims)> {
+    let project:Project = project::require(stream)?;
+    match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if !claims.suid.is_empty() => Some((project, claims)),
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        }
+    }
+}
+
+/*- Get the user the request is about from the URL-params.
+    It must exist in the project and not be the caller -*/
+pub(crate) fn target_user(stream:&mut Stream, project:&Project, claims:&UserClaims) -> Option<String> {
+    let suid:String = match stream.params.get("suid") {
+        Some(e) => e.to_string(),
+        None => {
+            utils::respond_status(stream, 410);
+            return None;
+        }
+    };
+    if suid == claims.suid {
+        stream.respond(400, do_json(400, DICTIONARY.error.social.self_target));
+        return None;
+    };
+    let users:Collection<User> = utils::establish_mclient::<User>("users");
+    match users.find_one(doc!{ "project_id": &project.id, "suid": &suid }, None) {
+        Ok(Some(_)) => Some(suid),
+        _ => {
+            stream.respond(404, do_json(404, DICTIONARY.error.not_found.user));
+            None
+        }
+    }
+}
+
+/*- Like `target_user`, but users who have blocked the caller,
+    or were blocked by them, are treated as not existing -*/
+fn target(stream:&mut Stream, project:&Project, claims:&UserClaims) -> Option<String> {
+    let suid:String = target_user(stream, project, claims)?;
+    match block::is_blocked(&project.id, &claims.suid, &suid) {
+        true => {
+            stream.respond(404, do_json(404, DICTIONARY.error.not_found.user));
+            None
+        },
+        false => Some(suid)
+    }
+}
+
+/*- Respond with a page of users, as `caller` sees them. `entries` is
+    one more than the page size if there are more pages, see `find_page` -*/
+pub(crate) fn respond_page(stream:&mut Stream, project_id:&str, caller:&str, mut entries:Vec<(String, u64)>, offset:u64, limit:i64) -> () {
+    let next:Option<u64> = match entries.len() as i64 > limit {
+        true => Some(offset + limit as u64),
+        false => None
+    };
+    entries.truncate(limit as usize);
+
+    let since:HashMap<String, u64> = entries.iter().cloned().collect();
+    let suids:Vec<String> = entries.into_iter().map(|(suid, _)| suid).collect();
+    let viewer:Viewer = Viewer::of(project_id, Some(caller.to_string()));
+    let items:Vec<serde_json::Value> = safe_users(project_id, &suids, &viewer)
+        .into_iter()
+        .map(|user| serde_json::json!({
+            "since": since.get(&user.suid),
+            "user" : user,
+        }))
+        .collect();
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "items" : items,
+        "offset": offset,
+        "limit" : limit,
+        "next"  : next,
+    }).to_string()));
+}
+
+/*- Fetch one page (plus one entry, to know if there's a
+    next page) of a collection, newest first -*/
+fn find_page<T>(collection:Collection<T>, filter:Document, sort_key:&str, offset:u64, limit:i64) -> Option<Vec<T>>
+    where T: serde::de::DeserializeOwned + Unpin + Send + Sync
+{
+    let options = FindOptions::builder()
+        .sort(doc!{ sort_key: -1 })
+        .skip(offset)
+        .limit(limit + 1)
+        .build();
+    collection.find(filter, options).ok().map(|cursor| cursor.flatten().collect())
+}
+
+/*- Make two users friends. Upserts, so that a direction
+    which is already stored is left as it is -*/
+fn befriend(project_id:&str, a:&str, b:&str) -> bool {
+    let since:u64 = utils::get_unix_epoch_time();
+    [(a, b), (b, a)].into_iter().all(|(suid, friend)| friends().update_one(
+        doc!{ "project_id": project_id, "suid": suid, "friend": friend },
+        doc!{ "$setOnInsert": { "since": since as i64 } },
+        UpdateOptions::builder().upsert(true).build()
+    ).is_ok())
+}
+
+/*- Send a friend request. If the other user already sent
+    one to the caller, this accepts theirs instead -*/
+pub(crate) fn send_request(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    if exists(friends(), doc!{ "project_id": &project.id, "suid": &claims.suid, "friend": &target }) {
+        return stream.respond(409, do_json(409, DICTIONARY.error.social.already_friends));
+    };
+    if exists(requests(), doc!{ "project_id": &project.id, "from": &claims.suid, "to": &target }) {
+        return stream.respond(409, do_json(409, DICTIONARY.error.social.request_exists));
+    };
+
+    /*- Crossed requests -*/
+    if let Ok(result) = requests().delete_one(doc!{ "project_id": &project.id, "from": &target, "to": &claims.suid }, None) {
+        if result.deleted_count == 1 {
+            return match befriend(&project.id, &claims.suid, &target) {
+                true => stream.respond(200, do_json(200, "Success!")),
+                false => stream.respond(500, do_json(500, "Internal server error"))
+            };
+        };
+    };
+
+    match requests().insert_one(FriendRequest {
+        project_id: project.id.clone(),
+        from      : claims.suid.clone(),
+        to        : target,
+        created_at: utils::get_unix_epoch_time(),
+    }, None) {
+        Ok(_) => stream.respond(200, do_json(200, "Success!")),
+
+        /*- Sent twice at once -*/
+        Err(err) if utils::is_duplicate_key(&err) => stream.respond(409, do_json(409, DICTIONARY.error.social.request_exists)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Answer or withdraw a friend request. Returns whether
+    a matching request was found and removed -*/
+fn remove_request(project_id:&str, from:&str, to:&str) -> bool {
+    match requests().delete_one(doc!{ "project_id": project_id, "from": from, "to": to }, None) {
+        Ok(result) => result.deleted_count == 1,
+        Err(_) => false
+    }
+}
+
+/*- Accept a friend request sent to the caller -*/
+pub(crate) fn accept_request(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    if !remove_request(&project.id, &target, &claims.suid) {
+        return stream.respond(404, do_json(404, DICTIONARY.error.not_found.friend_request));
+    };
+    match befriend(&project.id, &claims.suid, &target) {
+        true => stream.respond(200, do_json(200, "Success!")),
+        false => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Decline a friend request sent to the caller -*/
+pub(crate) fn decline_request(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    match remove_request(&project.id, &target, &claims.suid) {
+        true => stream.respond(200, do_json(200, "Success!")),
+        false => stream.respond(404, do_json(404, DICTIONARY.error.not_found.friend_request))
+    };
+}
+
+/*- Cancel a friend request the caller sent -*/
+pub(crate) fn cancel_request(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    match remove_request(&project.id, &claims.suid, &target) {
+        true => stream.respond(200, do_json(200, "Success!")),
+        false => stream.respond(404, do_json(404, DICTIONARY.error.not_found.friend_request))
+    };
+}
+
+/*- Stop being friends -*/
+pub(crate) fn remove_friend(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    match friends().delete_many(doc!{
+        "project_id": &project.id,
+        "$or": [
+            { "suid": &claims.suid, "friend": &target },
+            { "suid": &target, "friend": &claims.suid },
+        ]
+    }, None) {
+        Ok(result) if result.deleted_count > 0 => stream.respond(200, do_json(200, "Success!")),
+        Ok(_) => stream.respond(404, do_json(404, DICTIONARY.error.social.not_friends)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- The callers' friends, most recent first -*/
+pub(crate) fn list_friends(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    match find_page(friends(), doc!{ "project_id": &project.id, "suid": &claims.suid }, "since", offset, limit) {
+        Some(page) => {
+            let entries = page.into_iter().map(|friendship| (friendship.friend, friendship.since)).collect();
+            respond_page(stream, &project.id, &claims.suid, entries, offset, limit)
+        },
+        None => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Pending friend requests. The `direction` header picks
+    "incoming" (the default) or "outgoing" -*/
+pub(crate) fn list_requests(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+    let outgoing:bool = matches!(stream.headers.get("direction"), Some(&"outgoing"));
+
+    /*- Requests from muted users are kept, but not shown -*/
+    let filter:Document = match outgoing {
+        true => doc!{ "project_id": &project.id, "from": &claims.suid },
+        false => doc!{
+            "project_id": &project.id,
+            "to": &claims.suid,
+            "from": { "$nin": block::muted_by(&project.id, &claims.suid) }
+        },
+    };
+    match find_page(requests(), filter, "created_at", offset, limit) {
+        Some(page) => {
+            let entries = page.into_iter()
+                .map(|request| (if outgoing { request.to } else { request.from }, request.created_at))
+                .collect();
+            respond_page(stream, &project.id, &claims.suid, entries, offset, limit)
+        },
+        None => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Friends the caller and another user have in common -*/
+pub(crate) fn mutual_friends(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    let own:Vec<String> = friend_suids(&project.id, &claims.suid);
+    match find_page(friends(), doc!{ "project_id": &project.id, "suid": &target, "friend": { "$in": own } }, "since", offset, limit) {
+        Some(page) => {
+            let entries = page.into_iter().map(|friendship| (friendship.friend, friendship.since)).collect();
+            respond_page(stream, &project.id, &claims.suid, entries, offset, limit)
+        },
+        None => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Follow another user -*/
+pub(crate) fn follow(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    /*- Following someone already followed changes nothing -*/
+    match follows().update_one(
+        doc!{ "project_id": &project.id, "follower": &claims.suid, "followee": &target },
+        doc!{ "$setOnInsert": { "since": utils::get_unix_epoch_time() as i64 } },
+        UpdateOptions::builder().upsert(true).build()
+    ) {
+        Ok(_) => stream.respond(200, do_json(200, "Success!")),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Stop following another user -*/
+pub(crate) fn unfollow(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let target:String = match target(stream, &project, &claims) { Some(e) => e, None => return };
+
+    match follows().delete_one(doc!{ "project_id": &project.id, "follower": &claims.suid, "followee": &target }, None) {
+        Ok(_) => stream.respond(200, do_json(200, "Success!")),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Who follows the caller -*/
+pub(crate) fn followers(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    match find_page(follows(), doc!{ "project_id": &project.id, "followee": &claims.suid }, "since", offset, limit) {
+        Some(page) => {
+            let entries = page.into_iter().map(|follow| (follow.follower, follow.since)).collect();
+            respond_page(stream, &project.id, &claims.suid, entries, offset, limit)
+        },
+        None => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Who the caller follows -*/
+pub(crate) fn following(stream: &mut Stream) -> () {
+    let (project, claims) = match caller(stream) { Some(e) => e, None => return };
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+
+    match find_page(follows(), doc!{ "project_id": &project.id, "follower": &claims.suid }, "since", offset, limit) {
+        Some(page) => {
+            let entries = page.into_iter().map(|follow| (follow.followee, follow.since)).collect();
+            respond_page(stream, &project.id, &claims.suid, entries, offset, limit)
+        },
+        None => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- A users' friendships, friend requests and follows,
+    both ways. Used by exports -*/
+pub(crate) fn all_of(suid:&str) -> Option<(Vec<Friendship>, Vec<FriendRequest>, Vec<Follow>)> {
+    Some((
+        utils::find_all(&friends(), doc!{ "suid": suid })?,
+        utils::find_all(&requests(), doc!{ "$or": [ { "from": suid }, { "to": suid } ] })?,
+        utils::find_all(&follows(), doc!{ "$or": [ { "follower": suid }, { "followee": suid } ] })?,
+    ))
+}
+
+/*- Remove everything a user is part of. Used when purging accounts -*/
+pub(crate) fn forget(suid:&str) -> () {
+    friends().delete_many(doc!{ "$or": [ { "suid": suid }, { "friend": suid } ] }, None).ok();
+    requests().delete_many(doc!{ "$or": [ { "from": suid }, { "to": suid } ] }, None).ok();
+    follows().delete_many(doc!{ "$or": [ { "follower": suid }, { "followee": suid } ] }, None).ok();
+}
@@ -0,0 +1,563 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{ utils, metrics, avatar, user::User };
+use serde::{ Serialize, Deserialize };
+use regex::Regex;
+use hmac::{ Hmac, Mac };
+use sha2::{ Digest, Sha256 };
+use ureq;
+use std::{
+    env, fs, thread,
+    io::Read,
+    path::{ Path, PathBuf },
+    collections::HashSet,
+    time::{ Duration, Instant, UNIX_EPOCH },
+};
+use mongodb::{
+    bson::doc,
+    sync::Collection,
+};
+
+/*- Constants -*/
+pub(crate) const AVATAR_PREFIX:&'static str    = "avatars/";
+pub(crate) const IDENTICON_PREFIX:&'static str = "identicons/";
+const DEFAULT_LOCAL_ROOT:&'static str          = "uploads";
+const GC_INTERVAL:u64                          = 60*60;
+
+/*- Unreferenced blobs younger than this are left alone, so that
+    a blob which was just written but not yet referenced by the
+    user record isn't collected -*/
+const GC_MIN_AGE:u64                           = 60*60;
+
+/// # BlobStore
+/// Somewhere to put uploaded files. Keys are slash separated
+/// paths like "avatars/<hash>.png". Which backend is used is
+/// decided by the `BLOB_STORE` environment variable, see `store()`.
+pub(crate) trait BlobStore {
+    fn put(&self, key:&str, bytes:&[u8], content_type:&str) -> Result<(), StorageError>;
+    fn get(&self, key:&str) -> Result<Option<Blob>, StorageError>;
+    fn delete(&self, key:&str) -> Result<(), StorageError>;
+    fn list(&self, prefix:&str) -> Result<Vec<BlobMeta>, StorageError>;
+}
+
+/*- A stored file -*/
+#[derive(Clone, Debug)]
+pub(crate) struct Blob {
+    pub bytes   : Vec<u8>,
+    pub modified: u64,
+}
+
+/*- A stored files' metadata -*/
+#[derive(Clone, Debug)]
+pub(crate) struct BlobMeta {
+    pub key     : String,
+    pub modified: u64,
+}
+
+/*- Storage errors -*/
+#[derive(Debug)]
+pub(crate) enum StorageError {
+    Io(std::io::Error),
+    Http(String),
+    InvalidKey,
+
+    /*- Users couldn't be read, so which blobs are referenced is unknown -*/
+    Database(String),
+}
+
+/// # AvatarRef
+/// Which blobs make up a users' current avatar.
+/// Stored on the user record.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AvatarRef {
+    pub key        : String,
+    pub thumbnails : Vec<Thumbnail>,
+    pub uploaded_at: u64,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Thumbnail {
+    pub size: u32,
+    pub key : String,
+}
+
+/*- Function implementations -*/
+impl AvatarRef {
+
+    /*- Get the key of a rendition, `None` being full size -*/
+    pub fn key_for(&self, size:Option<u32>) -> Option<&str> {
+        match size {
+            None => Some(&self.key),
+            Some(size) => self.thumbnails.iter()
+                .find(|thumbnail| thumbnail.size == size)
+                .map(|thumbnail| thumbnail.key.as_str())
+        }
+    }
+
+    /*- Every key referenced -*/
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.key.as_str())
+            .chain(self.thumbnails.iter().map(|thumbnail| thumbnail.key.as_str()))
+    }
+}
+
+/*- Content-addressed key of some bytes -*/
+pub(crate) fn content_key(prefix:&str, bytes:&[u8], extension:&str) -> String {
+    format!("{}{}.{}", prefix, utils::hash_bytes(bytes), extension)
+}
+
+/*- Quick way of getting the configured blob store -*/
+pub(crate) fn store() -> Box<dyn BlobStore> {
+    match env::var("BLOB_STORE").as_deref() {
+        Ok("s3") => Box::new(Metered { backend: "s3", inner: Box::new(S3Store::from_env()) }),
+        _ => Box::new(Metered { backend: "local", inner: Box::new(LocalStore::new(
+            env::var("BLOB_STORE_DIR").unwrap_or(DEFAULT_LOCAL_ROOT.to_string())
+        )) }),
+    }
+}
+
+/// # Metered
+/// Wraps a BlobStore, counting and timing its operations
+struct Metered {
+    backend: &'static str,
+    inner  : Box<dyn BlobStore>,
+}
+impl Metered {
+    fn time<T>(&self, operation:&'static str, call:impl FnOnce(&dyn BlobStore) -> Result<T, StorageError>) -> Result<T, StorageError> {
+        let started:Instant = Instant::now();
+        let result:Result<T, StorageError> = call(self.inner.as_ref());
+        metrics::storage_operation(self.backend, operation, result.is_ok(), started.elapsed().as_secs_f64());
+        result
+    }
+}
+impl BlobStore for Metered {
+    fn put(&self, key:&str, bytes:&[u8], content_type:&str) -> Result<(), StorageError> {
+        self.time("put", |store| store.put(key, bytes, content_type))
+    }
+    fn get(&self, key:&str) -> Result<Option<Blob>, StorageError> {
+        self.time("get", |store| store.get(key))
+    }
+    fn delete(&self, key:&str) -> Result<(), StorageError> {
+        self.time("delete", |store| store.delete(key))
+    }
+    fn list(&self, prefix:&str) -> Result<Vec<BlobMeta>, StorageError> {
+        self.time("list", |store| store.list(prefix))
+    }
+}
+
+/*- Every avatar blob key users reference, and the identicon
+    stems (see `avatar::identicon_stem`) of every name their
+    identicons can be made from. Fails rather than skipping
+    users, a skipped users' blobs would look unreferenced -*/
+fn referenced() -> Result<(HashSet<String>, HashSet<String>), StorageError> {
+    let cursor = utils::establish_mclient::<User>("users").find(doc!{}, None)
+        .map_err(|e| StorageError::Database(e.to_string()))?;
+
+    let mut avatars:HashSet<String> = HashSet::new();
+    let mut identicons:HashSet<String> = HashSet::new();
+    for user in cursor {
+        let user:User = user.map_err(|e| StorageError::Database(e.to_string()))?;
+        if let Some(avatar) = &user.avatar {
+            avatars.extend(avatar.keys().map(String::from));
+        };
+        identicons.insert(avatar::identicon_stem(&user.suid, &user.displayname));
+        identicons.insert(avatar::identicon_stem(&user.suid, &user.username));
+    };
+
+    Ok((avatars, identicons))
+}
+
+/*- Delete unreferenced avatar and identicon blobs
+    which were last written before `cutoff` -*/
+fn sweep(store:&dyn BlobStore, avatars:&HashSet<String>, identicons:&HashSet<String>, cutoff:u64) -> Result<usize, StorageError> {
+    let mut deleted:usize = 0;
+    for blob in store.list(AVATAR_PREFIX)? {
+        if !avatars.contains(&blob.key) && blob.modified < cutoff {
+            store.delete(&blob.key)?;
+            deleted += 1;
+        };
+    };
+    for blob in store.list(IDENTICON_PREFIX)? {
+        let stem:&str = blob.key.rsplit_once('_').map_or(blob.key.as_str(), |(stem, _)| stem);
+        if !identicons.contains(stem) && blob.modified < cutoff {
+            store.delete(&blob.key)?;
+            deleted += 1;
+        };
+    };
+
+    Ok(deleted)
+}
+
+/*- Remove every avatar and identicon blob which no user
+    references. Nothing is removed if users can't all be read -*/
+pub(crate) fn collect_garbage(store:&dyn BlobStore) -> Result<usize, StorageError> {
+    let (avatars, identicons) = referenced()?;
+    sweep(store, &avatars, &identicons, utils::get_unix_epoch_time().saturating_sub(GC_MIN_AGE))
+}
+
+pub(crate) fn gc_loop() -> () {
+    loop {
+        collect_garbage(store().as_ref()).ok();
+        thread::sleep(Duration::from_secs(GC_INTERVAL));
+    }
+}
+
+/*- Keys may never escape the store -*/
+fn check_key(key:&str) -> Result<(), StorageError> {
+    if key.is_empty() || key.starts_with('/') || key.split('/').any(|part| part == ".." || part == ".") {
+        return Err(StorageError::InvalidKey);
+    };
+    Ok(())
+}
+
+/// # LocalStore
+/// Stores blobs as files under a root directory.
+pub(crate) struct LocalStore {
+    root: PathBuf,
+}
+impl LocalStore {
+    pub fn new(root:impl Into<PathBuf>) -> Self {
+        LocalStore { root: root.into() }
+    }
+    fn path(&self, key:&str) -> Result<PathBuf, StorageError> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+impl BlobStore for LocalStore {
+    fn put(&self, key:&str, bytes:&[u8], content_type:&str) -> Result<(), StorageError> {
+        let path:PathBuf = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(StorageError::Io)?;
+        };
+
+        /*- Write to a temporary file first so readers never see half a file -*/
+        let temporary:PathBuf = path.with_extension(format!("tmp-{}", crate::user::generate_suid()));
+        fs::write(&temporary, bytes).map_err(StorageError::Io)?;
+        fs::rename(&temporary, &path).map_err(StorageError::Io)
+    }
+    fn get(&self, key:&str) -> Result<Option<Blob>, StorageError> {
+        let path:PathBuf = self.path(key)?;
+        let bytes:Vec<u8> = match fs::read(&path) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StorageError::Io(e))
+        };
+
+        Ok(Some(Blob { bytes, modified: modified_time(&path) }))
+    }
+    fn delete(&self, key:&str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(StorageError::Io(e))
+        }
+    }
+    fn list(&self, prefix:&str) -> Result<Vec<BlobMeta>, StorageError> {
+        /*- Prefixes are directories in this backend -*/
+        let directory:PathBuf = self.path(prefix.trim_end_matches('/'))?;
+        let entries = match fs::read_dir(&directory) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(StorageError::Io(e))
+        };
+
+        Ok(entries
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| BlobMeta {
+                key     : format!("{}{}", prefix, entry.file_name().to_string_lossy()),
+                modified: modified_time(&entry.path()),
+            })
+            .collect())
+    }
+}
+
+/*- Files' modified time as unix time -*/
+fn modified_time(path:&Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// # S3Store
+/// Stores blobs in an S3-compatible bucket, using path-style
+/// requests signed with AWS Signature Version 4 so that it works
+/// against MinIO as well as AWS. Configured with `S3_ENDPOINT`,
+/// `S3_BUCKET`, `S3_REGION`, `S3_ACCESS_KEY` and `S3_SECRET_KEY`.
+pub(crate) struct S3Store {
+    endpoint  : String,
+    bucket    : String,
+    region    : String,
+    access_key: String,
+    secret_key: String,
+}
+impl S3Store {
+    pub fn from_env() -> Self {
+        S3Store {
+            endpoint  : env::var("S3_ENDPOINT").unwrap_or("http://localhost:9000".to_string()).trim_end_matches('/').to_string(),
+            bucket    : env::var("S3_BUCKET").unwrap_or("uploads".to_string()),
+            region    : env::var("S3_REGION").unwrap_or("us-east-1".to_string()),
+            access_key: env::var("S3_ACCESS_KEY").unwrap_or_default(),
+            secret_key: env::var("S3_SECRET_KEY").unwrap_or_default(),
+        }
+    }
+
+    /*- Send a signed request -*/
+    fn request(&self, method:&str, key:&str, query:&[(&str, &str)], body:&[u8], content_type:Option<&str>) -> Result<ureq::Response, ureq::Error> {
+        let host:&str = self.endpoint.splitn(2, "://").last().unwrap_or(&self.endpoint);
+        let path:String = format!("/{}/{}", self.bucket, uri_encode(key, false));
+
+        /*- Canonical query string, sorted by key -*/
+        let mut query:Vec<(String, String)> = query.iter()
+            .map(|(key, value)| (uri_encode(key, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query:String = query.iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        /*- Dates -*/
+        let now:u64 = utils::get_unix_epoch_time();
+        let amz_date:String = utils::iso_basic_date(now);
+        let date:&str = &amz_date[..8];
+        let payload_hash:String = hex(&Sha256::digest(body));
+
+        /*- Canonical request -*/
+        let signed_headers:&str = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request:String = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+
+        /*- String to sign -*/
+        let scope:String = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign:String = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        /*- Signature -*/
+        let mut key:Vec<u8> = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        };
+        let signature:String = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        /*- Send -*/
+        let url:String = match query.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query)
+        };
+        let mut request = ureq::request(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("Authorization", &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            ));
+        if let Some(content_type) = content_type {
+            request = request.set("Content-Type", content_type);
+        };
+
+        request.send_bytes(body)
+    }
+}
+impl BlobStore for S3Store {
+    fn put(&self, key:&str, bytes:&[u8], content_type:&str) -> Result<(), StorageError> {
+        check_key(key)?;
+        match self.request("PUT", key, &[], bytes, Some(content_type)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(StorageError::Http(e.to_string()))
+        }
+    }
+    fn get(&self, key:&str) -> Result<Option<Blob>, StorageError> {
+        check_key(key)?;
+        let response = match self.request("GET", key, &[], &[], None) {
+            Ok(e) => e,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(StorageError::Http(e.to_string()))
+        };
+
+        let modified:u64 = response.header("Last-Modified")
+            .and_then(utils::parse_http_date)
+            .unwrap_or(0);
+        let mut bytes:Vec<u8> = Vec::new();
+        response.into_reader().read_to_end(&mut bytes).map_err(StorageError::Io)?;
+
+        Ok(Some(Blob { bytes, modified }))
+    }
+    fn delete(&self, key:&str) -> Result<(), StorageError> {
+        check_key(key)?;
+        match self.request("DELETE", key, &[], &[], None) {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(e) => Err(StorageError::Http(e.to_string()))
+        }
+    }
+    fn list(&self, prefix:&str) -> Result<Vec<BlobMeta>, StorageError> {
+        let contents = Regex::new(r"(?s)<Contents>.*?<Key>(.*?)</Key>.*?<LastModified>(.*?)</LastModified>.*?</Contents>").unwrap();
+        let next_token = Regex::new(r"<NextContinuationToken>(.*?)</NextContinuationToken>").unwrap();
+        let mut blobs:Vec<BlobMeta> = Vec::new();
+        let mut token:Option<String> = None;
+
+        /*- ListObjectsV2 returns at most 1000 keys per page -*/
+        loop {
+            let mut query:Vec<(&str, &str)> = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &token { query.push(("continuation-token", token)); };
+
+            let body:String = match self.request("GET", "", &query, &[], None) {
+                Ok(response) => response.into_string().map_err(StorageError::Io)?,
+                Err(e) => return Err(StorageError::Http(e.to_string()))
+            };
+
+            for capture in contents.captures_iter(&body) {
+                blobs.push(BlobMeta {
+                    key     : xml_unescape(&capture[1]),
+                    modified: utils::parse_iso_date(&capture[2]).unwrap_or(0),
+                });
+            };
+
+            token = next_token.captures(&body).map(|capture| xml_unescape(&capture[1]));
+            if token.is_none() { break; };
+        };
+
+        Ok(blobs)
+    }
+}
+
+/*- SigV4 helpers -*/
+pub(crate) fn hmac_sha256(key:&[u8], data:&[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+pub(crate) fn hex(bytes:&[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+fn uri_encode(value:&str, encode_slash:bool) -> String {
+    let mut encoded:String = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        };
+    };
+    encoded
+}
+fn xml_unescape(value:&str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::generate_suid;
+
+    /*- A bucket of its own on the MinIO from docker-compose.yml -*/
+    fn minio() -> S3Store {
+        let store = S3Store {
+            endpoint  : env::var("S3_ENDPOINT").unwrap_or("http://localhost:9000".to_string()),
+            bucket    : format!("test-{}", &generate_suid()[..16]),
+            region    : "us-east-1".to_string(),
+            access_key: "minioadmin".to_string(),
+            secret_key: "minioadmin".to_string(),
+        };
+        store.request("PUT", "", &[], &[], None).expect("creating the bucket");
+        store
+    }
+    fn remove_bucket(store:&S3Store) -> () {
+        for prefix in [AVATAR_PREFIX, IDENTICON_PREFIX] {
+            for blob in store.list(prefix).unwrap_or_default() {
+                store.delete(&blob.key).ok();
+            };
+        };
+        store.request("DELETE", "", &[], &[], None).ok();
+    }
+
+    /*- Blobs of a referenced and an unreferenced avatar and identicon -*/
+    fn fill(store:&dyn BlobStore) -> (HashSet<String>, HashSet<String>) {
+        for key in [
+            "avatars/kept.png", "avatars/gone.png",
+            "identicons/kept_64.png", "identicons/kept_128.png", "identicons/gone_64.png",
+        ] {
+            store.put(key, b"png", "image/png").expect("putting a blob");
+        };
+        (
+            HashSet::from(["avatars/kept.png".to_string()]),
+            HashSet::from(["identicons/kept".to_string()]),
+        )
+    }
+    fn keys(store:&dyn BlobStore) -> Vec<String> {
+        let mut keys:Vec<String> = [AVATAR_PREFIX, IDENTICON_PREFIX].iter()
+            .flat_map(|prefix| store.list(prefix).expect("listing blobs"))
+            .map(|blob| blob.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+    fn check_sweep(store:&dyn BlobStore) -> () {
+        let (avatars, identicons) = fill(store);
+
+        /*- Blobs younger than the cutoff are left alone -*/
+        assert_eq!(sweep(store, &avatars, &identicons, 0).expect("sweeping"), 0);
+
+        assert_eq!(sweep(store, &avatars, &identicons, u64::MAX).expect("sweeping"), 2);
+        assert_eq!(keys(store), vec!["avatars/kept.png", "identicons/kept_128.png", "identicons/kept_64.png"]);
+    }
+
+    #[test]
+    fn sweep_keeps_referenced_avatars_and_identicons() {
+        let root:PathBuf = env::temp_dir().join(format!("blob-store-test-{}", generate_suid()));
+        check_sweep(&LocalStore::new(root.clone()));
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn keys_may_not_escape_the_store() {
+        for key in ["", "/etc/passwd", "avatars/../../x", "avatars/./x"] {
+            assert!(matches!(check_key(key), Err(StorageError::InvalidKey)), "{}", key);
+        };
+    }
+
+    #[test]
+    #[ignore = "needs MinIO, see docker-compose.yml"]
+    fn s3_round_trip() {
+        let store:S3Store = minio();
+        let key:String = content_key(AVATAR_PREFIX, b"image bytes", "png");
+
+        store.put(&key, b"image bytes", "image/png").expect("putting a blob");
+        let blob:Blob = store.get(&key).expect("getting a blob").expect("the blob");
+        assert_eq!(blob.bytes, b"image bytes");
+        assert!(blob.modified > 0);
+        assert_eq!(store.list(AVATAR_PREFIX).expect("listing blobs").into_iter().map(|blob| blob.key).collect::<Vec<String>>(), vec![key.clone()]);
+
+        store.delete(&key).expect("deleting a blob");
+        assert!(store.get(&key).expect("getting a blob").is_none());
+        store.delete(&key).expect("deleting a missing blob");
+
+        remove_bucket(&store);
+    }
+
+    #[test]
+    #[ignore = "needs MinIO, see docker-compose.yml"]
+    fn s3_sweep_keeps_referenced_avatars_and_identicons() {
+        let store:S3Store = minio();
+        check_sweep(&store);
+        remove_bucket(&store);
+    }
+}
@@ -5,10 +5,22 @@
 use regex;
 use uuid::Uuid;
 use responder;
-use crate::safe_user::SafeUser;
+use crate::{
+    utils, session, api_key, metrics,
+    profile::{ self, ProfileField, Viewer, Visibility },
+    safe_user::SafeUser,
+    storage::AvatarRef,
+    project::{ self, Project, DEFAULT_PROJECT_ID },
+};
+use mongodb::{
+    bson::doc,
+    options::IndexOptions,
+    sync::Collection,
+    IndexModel,
+};
 use std::{
     time, thread, fmt,
-    collections::HashMap,
+    collections::{ HashMap, BTreeMap },
     error::Error
 };
 use serde::{
@@ -23,7 +35,10 @@ use jsonwebtoken::{
 };
 
 /*- Constants -*/
-const SECRET_KEY:&str = "Secret123";
+pub(crate) const TOKEN_LIFETIME:u64 = 60*60*24*30;
+
+/*- The index keeping emails unique within a project -*/
+pub(crate) const EMAIL_INDEX:&'static str = "project_id_email_unique";
 
 /*- Structs -*/
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -34,6 +49,56 @@ pub(crate) struct User {
     pub email       : String, 
     pub uid         : String,
     pub suid        : String,
+
+    /*- The project (tenant) the user belongs to -*/
+    #[serde(default = "default_project_id")]
+    pub project_id  : String,
+
+    /*- Lowercased confusable skeleton of the username,
+        used for case- and look-alike-insensitive uniqueness -*/
+    #[serde(default)]
+    pub username_skeleton: String,
+
+    /*- Unix time at which the account will be purged,
+        if the user has requested deletion -*/
+    #[serde(default)]
+    pub deletion_scheduled_at: Option<u64>,
+
+    /*- The users' current avatar in the blob store -*/
+    #[serde(default)]
+    pub avatar: Option<AvatarRef>,
+
+    /*- Admins can query the audit log -*/
+    #[serde(default)]
+    pub admin: bool,
+
+    /*- If the user has proven they own the email -*/
+    #[serde(default)]
+    pub email_verified: bool,
+
+    /*- Set when the user has reported a login as not
+        theirs. Login is refused until the password is reset -*/
+    #[serde(default)]
+    pub password_reset_required: bool,
+
+    /*- Guests have no email or password until upgraded -*/
+    #[serde(default)]
+    pub guest: bool,
+
+    /*- Values of the projects' profile fields, see `profile` -*/
+    #[serde(default)]
+    pub profile: BTreeMap<String, serde_json::Value>,
+
+    /*- Who can see which parts of the profile. Parts
+        which aren't in here are visible to everyone -*/
+    #[serde(default)]
+    pub privacy: BTreeMap<String, Visibility>,
+
+    /*- Search keys, see `search::index_doc` -*/
+    #[serde(default)]
+    pub search_terms: Vec<String>,
+    #[serde(default)]
+    pub search_trigrams: Vec<String>,
 }
 
 /*- The default users claims -*/
@@ -43,9 +108,54 @@ pub(crate) struct UserClaims {
     pub uid     : String,
     pub suid    : String,
     pub exp     : usize,
+    #[serde(default)]
+    pub iat     : usize,
+
+    /*- The session this token belongs to -*/
+    #[serde(default)]
+    pub sid     : String,
+
+    /*- The projects' token audience -*/
+    #[serde(default)]
+    pub aud     : String,
+
+    /*- Set on tokens issued to OAuth clients. Tokens
+        from `login` have full access and neither -*/
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope   : Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+
+    /*- Set when authenticated with an API key instead of a
+        token, to the keys' id. Never part of a JWT -*/
+    #[serde(skip)]
+    pub api_key : Option<String>,
+}
+
+/*- Claims of single-purpose tokens, as in the ones
+    sent in links by mail. `purpose` keeps a token for
+    one thing from being usable for another -*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PurposeClaims {
+    pub suid    : String,
+    pub aud     : String,
+    pub sid     : Option<String>,
+    pub purpose : String,
+    pub exp     : usize,
 }
 
 /*- Fcuntion implementations -*/
+impl UserClaims {
+
+    /*- If the token may be used for a scope. Tokens
+        without scopes have full access -*/
+    pub fn allows(&self, scope:&str) -> bool {
+        match &self.scope {
+            Some(scopes) => scopes.split(' ').any(|granted| granted == scope),
+            None => true
+        }
+    }
+}
 impl Default for User {
     fn default() -> Self {
         User {
@@ -55,26 +165,51 @@ impl Default for User {
             email       : String::new(),
             uid         : String::new(),
             suid        : String::new(),
+            project_id  : DEFAULT_PROJECT_ID.to_string(),
+            username_skeleton: String::new(),
+            deletion_scheduled_at: None,
+            avatar: None,
+            admin: false,
+            email_verified: false,
+            password_reset_required: false,
+            guest       : false,
+            search_terms: Vec::new(),
+            search_trigrams: Vec::new(),
+            profile     : BTreeMap::new(),
+            privacy     : BTreeMap::new(),
         }
     }
 }
 impl User {
 
-    /*- Create a JWT token -*/
-    pub fn generate_JWT(user:User) -> Result<String, ()> {
+    /*- Create a JWT token for a session. The projects' id
+        goes in the header as `kid`, so that tokens found
+        outside of a request (as in links) can be verified -*/
+    pub fn generate_JWT(user:User, sid:&str, project:&Project) -> Result<String, ()> {
+        User::generate_scoped_JWT(user, sid, project, None, None)
+    }
+
+    /*- Create a JWT token for an OAuth client, limited to some scopes -*/
+    pub fn generate_scoped_JWT(user:User, sid:&str, project:&Project, scope:Option<&str>, client_id:Option<&str>) -> Result<String, ()> {
         /*- Get the claims -*/
         let user_claims = UserClaims {
             username: user.username.clone(),
             uid     : user.uid.clone(),
             suid    : user.suid.clone(),
             exp     : get_expiration_time(),
+            iat     : utils::get_unix_epoch_time() as usize,
+            sid     : sid.to_string(),
+            aud     : project.token_audience.clone(),
+            scope   : scope.map(String::from),
+            client_id: client_id.map(String::from),
+            api_key : None,
         };
 
         /*- Encode the claims -*/
         let token = match encode(
-            &Header::default(),
+            &project_header(project),
             &user_claims,
-            &EncodingKey::from_secret(SECRET_KEY.as_ref())
+            &EncodingKey::from_secret(project.signing_key.as_ref())
         ) {
             Ok(e) => e,
             Err(e) => return Err(()),
@@ -85,14 +220,14 @@ impl User {
     }
 
     /*- Decode a JWT token -*/
-    pub fn decode__JWT__token(token:&str) -> Result<UserClaims, ()> {
+    pub fn decode__JWT__token(token:&str, project:&Project) -> Result<UserClaims, ()> {
         /*- Decode the token -*/
         let token = decode::<UserClaims>(
             &token,
             &DecodingKey::from_secret(
-                SECRET_KEY.as_ref()
+                project.signing_key.as_ref()
             ),
-            &Validation::default()
+            &project_validation(project)
         );
 
         /*- Check token decode status and return the token claims / data -*/
@@ -102,16 +237,119 @@ impl User {
         };
     }
 
+    /*- Create a single-purpose token -*/
+    pub fn generate_purpose_token(suid:&str, sid:Option<&str>, purpose:&str, lifetime:u64, project:&Project) -> Result<String, ()> {
+        let claims = PurposeClaims {
+            suid    : suid.to_string(),
+            aud     : project.token_audience.clone(),
+            sid     : sid.map(String::from),
+            purpose : purpose.to_string(),
+            exp     : (crate::utils::get_unix_epoch_time() + lifetime) as usize,
+        };
+
+        encode(
+            &project_header(project),
+            &claims,
+            &EncodingKey::from_secret(project.signing_key.as_ref())
+        ).map_err(|_| ())
+    }
+
+    /*- Decode a single-purpose token, and check its purpose.
+        The project is looked up from the tokens' header -*/
+    pub fn decode_purpose_token(token:&str, purpose:&str) -> Result<(PurposeClaims, Project), ()> {
+        let project:Project = match project::id_of_token(token) {
+            Some(id) => project::get(&id).ok_or(())?,
+            None => return Err(())
+        };
+        let claims:PurposeClaims = match decode::<PurposeClaims>(
+            token,
+            &DecodingKey::from_secret(project.signing_key.as_ref()),
+            &project_validation(&project)
+        ) {
+            Ok(token) => token.claims,
+            Err(_) => return Err(())
+        };
+
+        match claims.purpose == purpose {
+            true => Ok((claims, project)),
+            false => Err(())
+        }
+    }
+
+    /*- Decode a JWT token and check that its account
+        still exists and its session hasn't been revoked -*/
+    pub fn validate_JWT_token(token:&str, project:&Project) -> Result<UserClaims, ()> {
+        let validation:Result<UserClaims, ()> = User::decode__JWT__token(token, project).and_then(|claims| {
+            /*- Tokens are stateless, so tokens belonging to
+                purged accounts or revoked sessions are
+                rejected by looking them up -*/
+            match account_exists(&claims.suid) && session::is_active(&claims.sid, &claims.suid) {
+                true => Ok(claims),
+                false => Err(())
+            }
+        });
+
+        metrics::token_validation(validation.is_ok());
+        validation
+    }
+
     /*- Convert to SafeUser -*/
     pub fn to_safe(user:User) -> SafeUser {
-        return SafeUser {
+        let schema:Vec<ProfileField> = project::get(&user.project_id)
+            .map(|project| project.settings.profile_schema)
+            .unwrap_or_default();
+        User::to_safe_with(user, &schema)
+    }
+
+    /*- Convert to a SafeUser with the public fields of a schema.
+        Saves looking up the project when converting many users -*/
+    pub fn to_safe_with(user:User, schema:&[ProfileField]) -> SafeUser {
+        User::to_safe_for(user, schema, &Viewer::anonymous())
+    }
+
+    /*- Convert to a SafeUser with what a viewer may see. Hidden
+        displaynames are replaced by the username -*/
+    pub fn to_safe_for(user:User, schema:&[ProfileField], viewer:&Viewer) -> SafeUser {
+        let profile = profile::visible_fields(&user, schema, viewer);
+        let displayname:String = match viewer.can_see(&user, "displayname") {
+            true => user.displayname,
+            false => user.username.clone()
+        };
+        SafeUser {
+            profile,
             username    : user.username,
-            displayname : user.displayname,
+            displayname,
             suid        : user.suid,
         }
     }
 }
 
+/*- The project a token says it was issued by. Only a hint
+    until the token has been validated with that projects' key -*/
+pub(crate) fn project_of_token(token:&str) -> Option<Project> {
+    match project::id_of_token(token) {
+        Some(id) => project::get(&id),
+        None => project::get(DEFAULT_PROJECT_ID)
+    }
+}
+
+/*- Header and validation for a projects' tokens -*/
+fn project_header(project:&Project) -> Header {
+    let mut header:Header = Header::default();
+    header.kid = Some(project.id.clone());
+    header
+}
+fn project_validation(project:&Project) -> Validation {
+    let mut validation:Validation = Validation::default();
+    validation.set_audience(&[&project.token_audience]);
+    validation
+}
+
+/*- Users stored before projects existed belong to the default project -*/
+fn default_project_id() -> String {
+    DEFAULT_PROJECT_ID.to_string()
+}
+
 /*- Utility functions -*/
 pub fn generate_uuid() -> String {
     Uuid::new_v4().as_hyphenated().to_string()
@@ -131,6 +369,26 @@ pub fn check_email(email:&str) -> bool {
     email_regex.is_match(email.trim())
 }
 
+/*- Make emails unique within each project, since signing in
+    and magic links find users by them. Guests have no email,
+    so empty ones are left out of the index -*/
+pub(crate) fn ensure_indexes() -> () {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    if let Err(err) = collection.create_index(
+        IndexModel::builder()
+            .keys(doc!{ "project_id": 1, "email": 1 })
+            .options(IndexOptions::builder()
+                .name(EMAIL_INDEX.to_string())
+                .unique(true)
+                .partial_filter_expression(doc!{ "email": { "$gt": "" } })
+                .build())
+            .build(),
+        None
+    ) {
+        eprintln!("Couldn't make emails unique, some existing ones are used twice: {}", err);
+    };
+}
+
 /*- Get the expiration time -*/
 pub fn get_expiration_time() -> usize {
 
@@ -138,7 +396,7 @@ pub fn get_expiration_time() -> usize {
     let now = time::SystemTime::now();
 
     /*- Get the expiration time -*/
-    let expiration_time = now + time::Duration::from_secs(60*60*24*30);
+    let expiration_time = now + time::Duration::from_secs(TOKEN_LIFETIME);
 
     /*- Convert the expiration time to unix time -*/
     expiration_time.duration_since(time::UNIX_EPOCH).unwrap().as_secs() as usize
@@ -146,11 +404,38 @@ pub fn get_expiration_time() -> usize {
 }
 
 /*- Fully check if user is authorized, and
-    return a bool dependent on if they are -*/
+    return a bool dependent on if they are.
+    Only accepts full access tokens, see
+    `authenticate_scoped` for OAuth tokens -*/
 pub(crate) fn authenticate(headers:HashMap<&str, &str>) -> AuthorizationStatus {
+    match authenticate_any(headers) {
+        AuthorizationStatus::Authorized(claims) if claims.scope.is_some() => AuthorizationStatus::Unauthorized,
+        status => status
+    }
+}
+
+/*- Like `authenticate`, but also accepts OAuth
+    tokens which were granted `scope` -*/
+pub(crate) fn authenticate_scoped(headers:HashMap<&str, &str>, scope:&str) -> AuthorizationStatus {
+    match authenticate_any(headers) {
+        AuthorizationStatus::Authorized(claims) if !claims.allows(scope) => AuthorizationStatus::Unauthorized,
+        status => status
+    }
+}
+
+/*- Check a bearer token or API key of any kind -*/
+fn authenticate_any(headers:HashMap<&str, &str>) -> AuthorizationStatus {
     /*- Initialize the user -*/
     let token:String;
 
+    /*- API keys may also be passed in their own header -*/
+    if let Some(key) = utils::get_header_ignore_caps(&headers, "X-Api-Key") {
+        return match project::resolve(&headers) {
+            Some(project) => api_key::authenticate_key(key, &project),
+            None => AuthorizationStatus::Unauthorized
+        };
+    };
+
     /*- Get the values -*/
     token = match headers.get("authorization") {
         Some(token) => token,
@@ -162,8 +447,23 @@ pub(crate) fn authenticate(headers:HashMap<&str, &str>) -> AuthorizationStatus {
         }
     }.to_string().replace("Bearer ", "");
 
+    /*- API keys are for the project the request is for -*/
+    if token.starts_with(api_key::KEY_PREFIX) {
+        return match project::resolve(&headers) {
+            Some(project) => api_key::authenticate_key(&token, &project),
+            None => AuthorizationStatus::Unauthorized
+        };
+    };
+
+    /*- Tokens are only valid for the project the request is for,
+        resolved the same way handlers resolve it (`project::require`) -*/
+    let project:Project = match project::resolve(&headers) {
+        Some(e) => e,
+        None => return AuthorizationStatus::Unauthorized
+    };
+
     /*- Decode the token -*/
-    let user_claims = User::decode__JWT__token(&token);
+    let user_claims = User::validate_JWT_token(&token, &project);
 
     /*- Return -*/
     match user_claims {
@@ -172,9 +472,58 @@ pub(crate) fn authenticate(headers:HashMap<&str, &str>) -> AuthorizationStatus {
     }
 }
 
+/*- If an account with the suid exists -*/
+pub(crate) fn account_exists(suid:&str) -> bool {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    matches!(collection.find_one(doc!{ "suid": suid }, None), Ok(Some(_)))
+}
+
+/*- If the user has admin rights -*/
+pub(crate) fn is_admin(suid:&str) -> bool {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    matches!(collection.find_one(doc!{ "suid": suid, "admin": true }, None), Ok(Some(_)))
+}
+
 #[derive(Debug)]
 pub(crate) enum AuthorizationStatus{
     Authorized(UserClaims),
     Unauthorized,
     Err,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs MongoDB, see docker-compose.yml"]
+    fn emails_are_unique_within_a_project() {
+        ensure_indexes();
+        let collection:Collection<User> = utils::establish_mclient::<User>("users");
+        let project_id:String = format!("email-test-{}", generate_suid());
+        let user = |email:&str, project_id:&str| {
+            let suid:String = generate_suid();
+            User {
+                username         : suid.clone(),
+                username_skeleton: suid.clone(),
+                email            : email.to_string(),
+                uid              : generate_uuid(),
+                suid,
+                project_id       : project_id.to_string(),
+                ..User::default()
+            }
+        };
+
+        collection.insert_one(user("someone@example.com", &project_id), None).expect("inserting a user");
+        let err = collection.insert_one(user("someone@example.com", &project_id), None).expect_err("reusing an email");
+        assert!(utils::is_duplicate_key_on(&err, EMAIL_INDEX));
+
+        /*- Other projects, and guests without emails, aren't affected -*/
+        let other_project:String = format!("{}-other", project_id);
+        collection.insert_one(user("someone@example.com", &other_project), None).expect("same email in another project");
+        collection.insert_one(user("", &project_id), None).expect("a guest");
+        collection.insert_one(user("", &project_id), None).expect("another guest");
+
+        collection.delete_many(doc!{ "project_id": { "$in": [&project_id, &other_project] } }, None).ok();
+    }
+}
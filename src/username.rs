@@ -0,0 +1,220 @@
+/*- Imports -*/
+use regex::Regex;
+use crate::{ utils, user::User };
+use crate::dict::DICTIONARY;
+use crate::confusables::CONFUSABLES;
+use mongodb::{
+    bson::doc,
+    options::IndexOptions,
+    sync::Collection,
+    IndexModel,
+};
+use std::{ env, sync::OnceLock };
+
+/*- Constants -*/
+const DEFAULT_MIN_LEN:usize = 3;
+const DEFAULT_MAX_LEN:usize = 24;
+const DEFAULT_ALLOWED_CHARS:&'static str = r"^[\p{L}\p{N}_.\-]+$";
+const DEFAULT_RESERVED:&'static [&'static str] = &[
+    "admin", "administrator", "root", "system", "debug", "api",
+    "support", "moderator", "mod", "staff", "null", "undefined",
+    "login", "profile", "account", "accounts", "me", "self",
+];
+
+/// # UsernamePolicy
+/// Describes which usernames are allowed to be registered.
+/// `allowed_chars` is a regex which the whole username has to match,
+/// and `reserved` is compared against the username's skeleton so that
+/// "Admin" or "аdmin" (Cyrillic а) are rejected the same way "admin" is.
+#[derive(Clone, Debug)]
+pub(crate) struct UsernamePolicy {
+    pub min_len      : usize,
+    pub max_len      : usize,
+    pub allowed_chars: Regex,
+
+    /*- Skeletons of the reserved words -*/
+    pub reserved     : Vec<String>,
+}
+
+/*- Why a username was rejected -*/
+#[derive(Debug, PartialEq)]
+pub(crate) enum UsernameError {
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacters,
+    Reserved,
+}
+
+/*- Function implementations -*/
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            min_len      : DEFAULT_MIN_LEN,
+            max_len      : DEFAULT_MAX_LEN,
+            allowed_chars: Regex::new(DEFAULT_ALLOWED_CHARS).unwrap(),
+            reserved     : DEFAULT_RESERVED.iter().map(|word| skeleton(word)).collect(),
+        }
+    }
+}
+impl UsernamePolicy {
+
+    /*- The policy set in the environment, loaded and compiled once.
+        Checked at startup (see `main`), so this never fails later -*/
+    pub fn configured() -> &'static UsernamePolicy {
+        static POLICY:OnceLock<UsernamePolicy> = OnceLock::new();
+        POLICY.get_or_init(|| UsernamePolicy::from_env().unwrap_or_else(|err| panic!("{}", err)))
+    }
+
+    /*- Read the policy from USERNAME_MIN_LEN, USERNAME_MAX_LEN,
+        USERNAME_ALLOWED_CHARS (a regex) and USERNAME_RESERVED (comma
+        separated, replaces the default list). Unset ones keep their defaults -*/
+    pub fn from_env() -> Result<Self, String> {
+        UsernamePolicy::from_vars(|name| env::var(name).ok().filter(|value| !value.trim().is_empty()))
+    }
+    fn from_vars(var:impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut policy:UsernamePolicy = UsernamePolicy::default();
+        let length = |name:&str, default:usize| -> Result<usize, String> {
+            match var(name) {
+                Some(value) => value.trim().parse::<usize>().map_err(|_| format!("{} has to be a number", name)),
+                None => Ok(default)
+            }
+        };
+
+        policy.min_len = length("USERNAME_MIN_LEN", DEFAULT_MIN_LEN)?;
+        policy.max_len = length("USERNAME_MAX_LEN", DEFAULT_MAX_LEN)?;
+        if policy.min_len == 0 || policy.min_len > policy.max_len {
+            return Err("USERNAME_MIN_LEN has to be at least 1 and at most USERNAME_MAX_LEN".to_string());
+        };
+        if let Some(pattern) = var("USERNAME_ALLOWED_CHARS") {
+            policy.allowed_chars = Regex::new(&pattern).map_err(|err| format!("USERNAME_ALLOWED_CHARS isn't a valid regex: {}", err))?;
+        };
+        if let Some(reserved) = var("USERNAME_RESERVED") {
+            policy.reserved = reserved.split(',').map(str::trim).filter(|word| !word.is_empty()).map(skeleton).collect();
+        };
+
+        Ok(policy)
+    }
+
+    /*- Check a username against the policy -*/
+    pub fn validate(&self, username:&str) -> Result<(), UsernameError> {
+        /*- Length is counted in characters, not bytes -*/
+        let len = username.chars().count();
+        if len < self.min_len { return Err(UsernameError::TooShort(self.min_len)); };
+        if len > self.max_len { return Err(UsernameError::TooLong(self.max_len)); };
+
+        /*- Allowed characters -*/
+        if !self.allowed_chars.is_match(username) {
+            return Err(UsernameError::InvalidCharacters);
+        };
+
+        /*- Reserved words, compared by skeleton -*/
+        let username_skeleton = skeleton(username);
+        if self.reserved.iter().any(|word| word == &username_skeleton) {
+            return Err(UsernameError::Reserved);
+        };
+
+        Ok(())
+    }
+}
+impl UsernameError {
+
+    /*- The message we respond with -*/
+    pub fn message(&self) -> String {
+        match self {
+            UsernameError::TooShort(n)       => DICTIONARY.error.username.len_min.replace("{}", &n.to_string()),
+            UsernameError::TooLong(n)        => DICTIONARY.error.username.len_max.replace("{}", &n.to_string()),
+            UsernameError::InvalidCharacters => DICTIONARY.error.invalid.username.to_string(),
+            UsernameError::Reserved          => DICTIONARY.error.username.reserved.to_string(),
+        }
+    }
+}
+
+/*- Get the confusable skeleton of a string. Two usernames
+    with the same skeleton look alike and can't both exist.
+    Loosely follows UTS #39: lowercase, then map every
+    character to its prototype in the confusables table -*/
+pub(crate) fn skeleton(value:&str) -> String {
+    let mut skeleton = String::with_capacity(value.len());
+
+    /*- Map each character -*/
+    for character in value.chars().flat_map(|c| c.to_lowercase()) {
+        match CONFUSABLES.iter().find(|(from, _)| *from == character) {
+            Some((_, to)) => skeleton.push_str(to),
+            None => skeleton.push(character)
+        };
+    };
+
+    skeleton
+}
+
+/*- Fill in the skeletons of users from before skeletons existed,
+    and make skeletons unique per project, so that two signups at
+    once can't both pass the look-alike check -*/
+pub(crate) fn ensure_indexes() -> () {
+    let collection:Collection<User> = utils::establish_mclient::<User>("users");
+    if let Ok(cursor) = collection.find(doc!{ "$or": [
+        { "username_skeleton": { "$exists": false } },
+        { "username_skeleton": "" },
+    ] }, None) {
+        for user in cursor.flatten() {
+            collection.update_one(
+                doc!{ "suid": &user.suid },
+                doc!{ "$set": { "username_skeleton": skeleton(&user.username) } },
+                None
+            ).ok();
+        };
+    };
+
+    if let Err(err) = collection.create_index(
+        IndexModel::builder()
+            .keys(doc!{ "project_id": 1, "username_skeleton": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    ) {
+        eprintln!("Couldn't make usernames unique, some existing ones look alike: {}", err);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn policy(vars:&[(&str, &str)]) -> Result<UsernamePolicy, String> {
+        let vars:HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        UsernamePolicy::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn defaults() {
+        let policy = policy(&[]).unwrap();
+        assert_eq!(policy.validate("artur"), Ok(()));
+        assert_eq!(policy.validate("ab"), Err(UsernameError::TooShort(DEFAULT_MIN_LEN)));
+        assert_eq!(policy.validate("has space"), Err(UsernameError::InvalidCharacters));
+        assert_eq!(policy.validate("ADMIN"), Err(UsernameError::Reserved));
+    }
+
+    #[test]
+    fn configured_from_the_environment() {
+        let policy = policy(&[
+            ("USERNAME_MIN_LEN", "5"),
+            ("USERNAME_MAX_LEN", "8"),
+            ("USERNAME_ALLOWED_CHARS", "^[a-z]+$"),
+            ("USERNAME_RESERVED", "owner, artur"),
+        ]).unwrap();
+        assert_eq!(policy.validate("abcd"), Err(UsernameError::TooShort(5)));
+        assert_eq!(policy.validate("abcdefghi"), Err(UsernameError::TooLong(8)));
+        assert_eq!(policy.validate("abc_de"), Err(UsernameError::InvalidCharacters));
+        assert_eq!(policy.validate("artur"), Err(UsernameError::Reserved));
+        assert_eq!(policy.validate("admin"), Ok(()));
+    }
+
+    #[test]
+    fn invalid_configuration_is_rejected() {
+        assert!(policy(&[("USERNAME_ALLOWED_CHARS", "[")]).is_err());
+        assert!(policy(&[("USERNAME_MIN_LEN", "ten")]).is_err());
+        assert!(policy(&[("USERNAME_MIN_LEN", "10"), ("USERNAME_MAX_LEN", "5")]).is_err());
+        assert!(policy(&[("USERNAME_MIN_LEN", "0")]).is_err());
+    }
+}
@@ -11,7 +11,8 @@ use crate::{
         MONGO_CLIENT_URI_STRING,
         REQUIRED_HEADERS
     },
-    user::User
+    user::User,
+    metrics,
 };
 use responder;
 use sha3::{ Digest, Sha3_256 };
@@ -20,23 +21,37 @@ use mongodb::{
         doc,
         Document
     },
+    options::ClientOptions,
     sync::{
         Client,
+        ClientSession,
         Collection,
         Database
     },
+    error::{
+        Error,
+        ErrorKind,
+        WriteFailure,
+        TRANSIENT_TRANSACTION_ERROR,
+        UNKNOWN_TRANSACTION_COMMIT_RESULT
+    },
 };
 use std::{time::{
     SystemTime,
     UNIX_EPOCH
-}, collections::HashMap, hash::Hash};
+}, collections::HashMap, hash::Hash, io::Write};
+
+/*- Connect to mongo, with every command counted by `metrics` -*/
+fn mclient() -> mongodb::error::Result<Client> {
+    let mut options:ClientOptions = ClientOptions::parse(MONGO_CLIENT_URI_STRING)?;
+    options.command_event_handler = Some(metrics::database_handler());
+    Client::with_options(options)
+}
 
 /*- Quick way of establishing a connection with the mongo client -*/
 pub(super) fn establish_mclient<Type__>(collection_name:&str) -> Collection<Type__> {
     /*- Establish the mongodb connection -*/
-    let client:Client = Client::with_uri_str(
-        MONGO_CLIENT_URI_STRING
-    ).expect("Failed to initialize standalone client.");
+    let client:Client = mclient().expect("Failed to initialize standalone client.");
 
     /*- Get the database -*/
     let db:Database = client.database("test");
@@ -48,6 +63,72 @@ pub(super) fn establish_mclient<Type__>(collection_name:&str) -> Collection<Type
     collection
 }
 
+/*- Every document matching the filter. None if any of them
+    can't be read, since a partial list would look complete -*/
+pub(crate) fn find_all<T>(collection:&Collection<T>, filter:Document) -> Option<Vec<T>>
+    where T: serde::de::DeserializeOwned + Unpin + Send + Sync
+{
+    collection.find(filter, None).ok()?.collect::<Result<Vec<T>, Error>>().ok()
+}
+
+/*- If a write failed because it broke a unique index -*/
+pub(crate) fn is_duplicate_key(err:&Error) -> bool {
+    const DUPLICATE_KEY:i32 = 11000;
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        _ => false
+    }
+}
+
+/*- If a write broke one unique index in particular. The
+    server names the index in the message -*/
+pub(crate) fn is_duplicate_key_on(err:&Error, index:&str) -> bool {
+    let message:&str = match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => &e.message,
+        ErrorKind::Command(e) => &e.message,
+        _ => return false
+    };
+    is_duplicate_key(err) && message.contains(&format!("index: {} ", index))
+}
+
+/*- How many times a transaction is tried before giving up -*/
+const MAX_TRANSACTION_ATTEMPTS:usize = 5;
+
+/*- Run `writes` in one transaction, retrying it if it conflicts
+    with another. Every collection written to must come from the
+    database passed to `writes`, and be written to with the session.
+    Transactions need MongoDB to run as a replica set -*/
+pub(crate) fn transaction<R>(mut writes:impl FnMut(&Database, &mut ClientSession) -> mongodb::error::Result<R>) -> mongodb::error::Result<R> {
+    let client:Client = mclient()?;
+    let db:Database = client.database("test");
+    let mut session:ClientSession = client.start_session(None)?;
+
+    let mut attempts:usize = 0;
+    loop {
+        attempts += 1;
+        session.start_transaction(None)?;
+        let result:R = match writes(&db, &mut session) {
+            Ok(e) => e,
+            Err(err) => {
+                session.abort_transaction().ok();
+                if err.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempts < MAX_TRANSACTION_ATTEMPTS { continue; };
+                return Err(err);
+            }
+        };
+
+        /*- Commits whose outcome is unknown are safe to repeat -*/
+        loop {
+            match session.commit_transaction() {
+                Ok(()) => return Ok(result),
+                Err(err) if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempts < MAX_TRANSACTION_ATTEMPTS => attempts += 1,
+                Err(err) if err.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempts < MAX_TRANSACTION_ATTEMPTS => break,
+                Err(err) => return Err(err)
+            };
+        };
+    }
+}
+
 /*- Most endpoints will require headers, and
     the required headers will be stored in an
     array that might be difficult to search in.
@@ -75,6 +156,14 @@ pub(super) fn hash(value:&str) -> String {
     return format!("{:x}", hasher.finalize());
 }
 
+/*- Hash bytes using the SHA-3 algorithm -*/
+pub(crate) fn hash_bytes(value:&[u8]) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(value);
+
+    format!("{:x}", hasher.finalize())
+}
+
 /*- Get unix epoch time -*/
 pub(super) fn get_unix_epoch_time() -> u64 {
     /*- Get the current time -*/
@@ -102,5 +191,270 @@ pub fn get_headers_checked<'e, T: Eq + Hash + Copy, const N:usize>(headers:&'e H
     Some(value_vec)
 }
 
+/*- Get a header regardless of its capitalization -*/
+pub(crate) fn get_header_ignore_caps<'a>(headers:&HashMap<&'a str, &'a str>, name:&str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+}
+
+/*- Read the `offset` and `limit` headers of a paginated
+    request, with `limit` clamped to 1..=`max_limit` -*/
+pub(crate) fn pagination(headers:&HashMap<&str, &str>, default_limit:i64, max_limit:i64) -> (u64, i64) {
+    let offset:u64 = headers.get("offset").and_then(|e| e.parse().ok()).unwrap_or(0);
+    let limit:i64 = headers.get("limit")
+        .and_then(|e| e.parse::<i64>().ok())
+        .unwrap_or(default_limit)
+        .clamp(1, max_limit);
+
+    (offset, limit)
+}
+
+/*- Route params include the query string, as in
+    "suid?size=64". Split it into the param
+    and a map of query parameters -*/
+pub(crate) fn split_query(param:&str) -> (&str, HashMap<&str, &str>) {
+    let (value, query) = match param.split_once('?') {
+        Some(e) => e,
+        None => return (param, HashMap::new())
+    };
+
+    /*- Parse "key=value&key=value" -*/
+    let query:HashMap<&str, &str> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect();
+
+    (value, query)
+}
+
+/*- Decode a percent-encoded (and + for space) string -*/
+pub(crate) fn percent_decode(value:&str) -> String {
+    let bytes:&[u8] = value.as_bytes();
+    let mut decoded:Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i:usize = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => { decoded.push(byte); i += 2; },
+                    None => decoded.push(b'%')
+                };
+            },
+            byte => decoded.push(byte)
+        };
+        i += 1;
+    };
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/*- Percent-encode a string for use in a URL -*/
+pub(crate) fn percent_encode(value:&str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+/*- Parse an application/x-www-form-urlencoded body -*/
+pub(crate) fn parse_form(body:&str) -> HashMap<String, String> {
+    body.trim()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+/*- Respond with raw bytes and custom headers, for
+    things `Respond` can't express (binary bodies,
+    caching headers) -*/
+pub(crate) fn respond_bytes(stream:&mut responder::Stream, status:&str, headers:&[(&str, String)], body:&[u8]) -> () {
+    write_response(stream.get_mut_inner_ref(), status, headers, body);
+}
+
+/*- Like `respond_bytes`, for connections which were
+    handed off from their request thread -*/
+pub(crate) fn write_response<W:Write>(connection:&mut W, status:&str, headers:&[(&str, String)], body:&[u8]) -> () {
+    if let Some(code) = status.split_whitespace().next().and_then(|e| e.parse::<u16>().ok()) {
+        metrics::record_status(code);
+    };
+    let mut response:Vec<u8> = format!("HTTP/1.1 {}\r\n", status).into_bytes();
+
+    /*- Headers -*/
+    for (key, value) in headers {
+        response.extend(format!("{}: {}\r\n", key, value).into_bytes());
+    };
+    response.extend(format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes());
+    response.extend(body);
+
+    connection.write_all(&response).unwrap_or_default();
+}
+
+/*- Respond with only a status code. Use instead of
+    `Stream::respond_status`, so that metrics see the status -*/
+pub(crate) fn respond_status(stream:&mut responder::Stream, status:u16) -> () {
+    metrics::record_status(status);
+    stream.respond_status(status);
+}
 
+/*- Day names and month names for HTTP-dates -*/
+const DAYS:[&str; 7]    = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS:[&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/*- Format unix time as an IMF-fixdate,
+    as in "Sun, 06 Nov 1994 08:49:37 GMT" -*/
+pub(crate) fn http_date(time:u64) -> String {
+    let days:i64 = (time / 86400) as i64;
+    let secs:u64 = time % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        secs / 3600, (secs / 60) % 60, secs % 60
+    )
+}
+
+/*- Parse an IMF-fixdate back to unix time. Comes from
+    clients (If-Modified-Since), so anything out of range
+    is rejected rather than wrapped around -*/
+pub(crate) fn parse_http_date(date:&str) -> Option<u64> {
+    let parts:Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 6 { return None; };
+
+    let day:u32 = parts[1].parse().ok()?;
+    let month:u32 = MONTHS.iter().position(|m| m == &parts[2])? as u32 + 1;
+    let year:i64 = parts[3].parse().ok()?;
+    let clock:Vec<u64> = parts[4].split(':').map(|e| e.parse().ok()).collect::<Option<Vec<u64>>>()?;
+    if clock.len() != 3 { return None; };
+
+    unix_time(year, month, day, clock[0], clock[1], clock[2])
+}
+
+/*- Format unix time as "20130524T000000Z", used by AWS -*/
+pub(crate) fn iso_basic_date(time:u64) -> String {
+    let (year, month, day) = civil_from_days((time / 86400) as i64);
+    let secs:u64 = time % 86400;
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year, month, day, secs / 3600, (secs / 60) % 60, secs % 60
+    )
+}
 
+/*- Parse "2009-10-12T17:50:30.000Z" to unix time -*/
+pub(crate) fn parse_iso_date(date:&str) -> Option<u64> {
+    let (date, time) = date.trim_end_matches('Z').split_once('T')?;
+    let date:Vec<&str> = date.split('-').collect();
+    let time:Vec<&str> = time.split(':').collect();
+    if date.len() != 3 || time.len() != 3 { return None; };
+
+    let seconds:f64 = time[2].parse().ok()?;
+    if !(0.0..60.0).contains(&seconds) { return None; };
+    unix_time(
+        date[0].parse().ok()?, date[1].parse().ok()?, date[2].parse().ok()?,
+        time[0].parse().ok()?, time[1].parse().ok()?, seconds as u64
+    )
+}
+
+/*- Years dates are accepted for -*/
+const MIN_YEAR:i64 = 1970;
+const MAX_YEAR:i64 = 9999;
+
+/*- Unix time of a date and time of day, if they're in range -*/
+fn unix_time(year:i64, month:u32, day:u32, hour:u64, minute:u64, second:u64) -> Option<u64> {
+    if hour >= 24 || minute >= 60 || second >= 60 { return None; };
+    let days:u64 = u64::try_from(days_from_civil(year, month, day)?).ok()?;
+
+    days.checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)
+}
+
+/*- Howard Hinnant's date algorithms -*/
+fn civil_from_days(days:i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+fn days_from_civil(year:i64, month:u32, day:u32) -> Option<i64> {
+    if !(MIN_YEAR..=MAX_YEAR).contains(&year) || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    };
+
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let yoe = (year - era * 400) as u64;
+    let mp = (if month > 2 { month - 3 } else { month + 9 }) as u64;
+    let doy = (153 * mp + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    Some(era * 146097 + doe as i64 - 719468)
+}
+fn days_in_month(year:i64, month:u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_dates_round_trip() {
+        assert_eq!(http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"), Some(1709251199));
+        assert_eq!(parse_http_date(&http_date(0)), Some(0));
+    }
+
+    #[test]
+    fn http_dates_out_of_range_are_rejected() {
+        for date in [
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 29 Feb 2023 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:37 GMT",
+            "Sun, 06 Nov 1994 08:49:60 GMT",
+            "Sun, 06 Nov 1994 18446744073709551615:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 06 Nov -9223372036854775808 08:49:37 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov 99999999999999999999 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{}", date);
+        };
+    }
+
+    #[test]
+    fn iso_dates_out_of_range_are_rejected() {
+        assert_eq!(parse_iso_date("2009-10-12T17:50:30.000Z"), Some(1255369830));
+        assert_eq!(parse_iso_date("2009-10-00T17:50:30.000Z"), None);
+        assert_eq!(parse_iso_date("2009-13-12T17:50:30.000Z"), None);
+        assert_eq!(parse_iso_date("2009-10-12T17:50:-1Z"), None);
+        assert_eq!(parse_iso_date("2009-10-12T17:50:NaNZ"), None);
+    }
+}
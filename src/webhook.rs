@@ -0,0 +1,679 @@
+/*- Global allowances -*/
+#![allow(
+    dead_code,
+    unused_variables,
+    unused_imports
+)]
+
+/*- Imports -*/
+use crate::{
+    utils,
+    api::do_json,
+    audit::{ self, AuditEvent, Outcome },
+    dict::DICTIONARY,
+    project::{ self, Project },
+    storage::{ hmac_sha256, hex },
+    user::{ User, UserClaims, AuthorizationStatus, authenticate, is_admin, generate_suid },
+};
+use responder::prelude::*;
+use serde::{ Serialize, Deserialize };
+use serde_json::{ self, Value };
+use rand::{ self, Rng, distributions::Alphanumeric };
+use ureq;
+use mongodb::{
+    bson::{ self, doc, Document },
+    options::{ FindOptions, FindOneAndUpdateOptions, ReturnDocument },
+    sync::{ ClientSession, Collection, Database },
+};
+use std::{
+    io, thread,
+    net::{ IpAddr, SocketAddr, ToSocketAddrs },
+    sync::OnceLock,
+    time::Duration,
+};
+
+/*- Constants -*/
+const WEBHOOK_COLLECTION:&'static str  = "webhooks";
+const DELIVERY_COLLECTION:&'static str = "webhook_deliveries";
+const SECRET_PREFIX:&'static str       = "whsec_";
+const SECRET_LEN:usize                 = 40;
+const MAX_WEBHOOKS:u64                 = 20;
+const DEFAULT_PAGE_SIZE:i64            = 50;
+const MAX_PAGE_SIZE:i64                = 200;
+
+/*- Retries. The n:th retry waits RETRY_BASE_DELAY * 2^(n-1)
+    seconds, at most MAX_RETRY_DELAY. Deliveries which have
+    failed MAX_ATTEMPTS times are dead-lettered -*/
+const MAX_ATTEMPTS:u32                 = 10;
+const RETRY_BASE_DELAY:u64             = 30;
+const MAX_RETRY_DELAY:u64              = 60*60*6;
+
+/*- How often the queue is checked, how long a claimed delivery
+    is hidden from other workers, and how long receivers get -*/
+const DELIVERY_INTERVAL:u64            = 5;
+const DELIVERY_LEASE:u64               = 60;
+const REQUEST_TIMEOUT:u64              = 10;
+const MAX_ERROR_LEN:usize              = 512;
+
+/*- What webhooks can subscribe to -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WebhookEvent {
+    #[serde(rename = "account.created")]  AccountCreated,
+    #[serde(rename = "account.verified")] AccountVerified,
+    #[serde(rename = "account.updated")]  AccountUpdated,
+    #[serde(rename = "account.deleted")]  AccountDeleted,
+    #[serde(rename = "password.changed")] PasswordChanged,
+    #[serde(rename = "avatar.uploaded")]  AvatarUploaded,
+}
+impl WebhookEvent {
+    /*- The name the event is stored and sent as -*/
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::AccountCreated  => "account.created",
+            WebhookEvent::AccountVerified => "account.verified",
+            WebhookEvent::AccountUpdated  => "account.updated",
+            WebhookEvent::AccountDeleted  => "account.deleted",
+            WebhookEvent::PasswordChanged => "password.changed",
+            WebhookEvent::AvatarUploaded  => "avatar.uploaded",
+        }
+    }
+}
+
+/// # Webhook
+/// A subscription of a URL to some of a projects' events.
+/// The secret is kept as is, since it's needed to sign
+/// payloads, and only returned when the webhook is created.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Webhook {
+    pub id        : String,
+    pub project_id: String,
+    pub url       : String,
+    pub secret    : String,
+    pub events    : Vec<WebhookEvent>,
+    pub created_at: u64,
+    pub created_by: String,
+}
+
+/*- Where a delivery is at -*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeliveryStatus {
+    Pending,
+    Delivered,
+
+    /*- Gave up after MAX_ATTEMPTS, see `redeliver` -*/
+    Dead,
+}
+
+/// # Delivery
+/// One event on its way to one webhook. Deliveries are the
+/// queue, so events fired before a restart are still sent.
+/// The payload is fixed when the event fires, so that every
+/// attempt sends the same body.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Delivery {
+    pub id             : String,
+    pub webhook_id     : String,
+    pub project_id     : String,
+    pub event          : WebhookEvent,
+    pub payload        : String,
+    pub status         : DeliveryStatus,
+    pub attempts       : u32,
+    pub next_attempt_at: u64,
+    pub locked_until   : u64,
+    pub last_response  : Option<u16>,
+    pub last_error     : Option<String>,
+    pub created_at     : u64,
+    pub completed_at   : Option<u64>,
+}
+
+/*- Quick ways of getting the collections -*/
+fn webhooks() -> Collection<Webhook> {
+    utils::establish_mclient::<Webhook>(WEBHOOK_COLLECTION)
+}
+fn deliveries() -> Collection<Delivery> {
+    utils::establish_mclient::<Delivery>(DELIVERY_COLLECTION)
+}
+
+/*- A random alphanumeric string -*/
+fn random_string(len:usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/*- Public fields of a webhook -*/
+fn describe(webhook:&Webhook) -> Value {
+    serde_json::json!({
+        "id"        : webhook.id,
+        "url"       : webhook.url,
+        "events"    : webhook.events,
+        "created_at": webhook.created_at,
+    })
+}
+
+/*- What webhooks are told about a user -*/
+pub(crate) fn user_data(user:&User) -> Value {
+    serde_json::json!({
+        "suid"          : user.suid,
+        "username"      : user.username,
+        "displayname"   : user.displayname,
+        "email"         : user.email,
+        "email_verified": user.email_verified,
+        "guest"         : user.guest,
+        "deletion_scheduled_at": user.deletion_scheduled_at,
+    })
+}
+
+/*- Queue an event for every webhook of the project subscribed
+    to it. The outbox calls this within the transaction changing
+    the user, so that the event is queued if and only if the
+    change commits. Sending happens in `delivery_loop` -*/
+pub(crate) fn enqueue(db:&Database, session:&mut ClientSession, project_id:&str, event:WebhookEvent, data:&Value) -> mongodb::error::Result<()> {
+    let mut cursor = db.collection::<Webhook>(WEBHOOK_COLLECTION)
+        .find_with_session(doc!{ "project_id": project_id, "events": event.name() }, None, session)?;
+    let subscribed:Vec<Webhook> = cursor.iter(session).collect::<mongodb::error::Result<Vec<Webhook>>>()?;
+    if subscribed.is_empty() { return Ok(()); };
+
+    let now:u64 = utils::get_unix_epoch_time();
+    let queued:Vec<Delivery> = subscribed.iter()
+        .map(|webhook| {
+            let id:String = generate_suid();
+            Delivery {
+                payload        : serde_json::json!({
+                    "id"        : id,
+                    "event"     : event,
+                    "project_id": project_id,
+                    "created_at": now,
+                    "data"      : data,
+                }).to_string(),
+                id,
+                webhook_id     : webhook.id.clone(),
+                project_id     : project_id.to_string(),
+                event,
+                status         : DeliveryStatus::Pending,
+                attempts       : 0,
+                next_attempt_at: now,
+                locked_until   : 0,
+                last_response  : None,
+                last_error     : None,
+                created_at     : now,
+                completed_at   : None,
+            }
+        })
+        .collect();
+    db.collection::<Delivery>(DELIVERY_COLLECTION).insert_many_with_session(queued, None, session)?;
+
+    Ok(())
+}
+
+/*- Whether webhooks may be sent to an address. Ones on this
+    machine or in private networks would let project admins
+    reach services which aren't reachable from outside -*/
+fn is_public(ip:&IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast()
+
+                /*- "This network", and carrier-grade NAT -*/
+                || a == 0 || (a == 100 && (64..128).contains(&b)))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public(&IpAddr::V4(mapped)),
+            None => {
+                let first:u16 = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+
+                    /*- Unique local and link-local -*/
+                    || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            }
+        }
+    }
+}
+
+/*- Resolve a target, if every address it resolves to is public -*/
+fn public_addresses(target:impl ToSocketAddrs) -> io::Result<Vec<SocketAddr>> {
+    let addresses:Vec<SocketAddr> = target.to_socket_addrs()?.collect();
+    match !addresses.is_empty() && addresses.iter().all(|address| is_public(&address.ip())) {
+        true => Ok(addresses),
+        false => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Webhook URL doesn't resolve to a public address"))
+    }
+}
+
+/*- The host and port of an http or https URL. IPv6
+    hosts are returned without their brackets -*/
+fn target(url:&str) -> Option<(String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let default_port:u16 = match scheme.to_ascii_lowercase().as_str() {
+        "https" => 443,
+        "http" => 80,
+        _ => return None
+    };
+    let authority:&str = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    if authority.contains('@') { return None; };
+
+    /*- Split off the port, minding IPv6 literals -*/
+    let (host, port):(&str, Option<&str>) = match authority.strip_prefix('[') {
+        Some(inner) => match inner.split_once(']')? {
+            (host, "") => (host, None),
+            (host, port) => (host, Some(port.strip_prefix(':')?))
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None)
+        }
+    };
+    if host.is_empty() { return None; };
+    let port:u16 = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port
+    };
+
+    Some((host.to_ascii_lowercase(), port))
+}
+
+/*- The client deliveries are sent with. It doesn't follow
+    redirects, which could point anywhere, and only connects
+    to public addresses. That's checked as each connection is
+    made, so a DNS record changed after `create` can't get
+    around it -*/
+fn agent() -> &'static ureq::Agent {
+    static AGENT:OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| ureq::AgentBuilder::new()
+        .redirects(0)
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+        .resolver(|netloc:&str| public_addresses(netloc))
+        .build())
+}
+
+/*- The signature of a payload. Receivers recompute it over
+    `<X-Webhook-Timestamp>.<body>` with their secret, and should
+    reject old timestamps so that requests can't be replayed -*/
+pub(crate) fn sign(secret:&str, timestamp:u64, body:&str) -> String {
+    format!("sha256={}", hex(&hmac_sha256(secret.as_bytes(), format!("{}.{}", timestamp, body).as_bytes())))
+}
+
+/*- Seconds to wait before the next attempt -*/
+fn retry_delay(attempts:u32) -> u64 {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+/*- Send a delivery once. Any 2xx counts as delivered -*/
+fn attempt(webhook:&Webhook, delivery:&Delivery) -> Result<u16, (Option<u16>, String)> {
+    let timestamp:u64 = utils::get_unix_epoch_time();
+    let response = agent().post(&webhook.url)
+        .set("Content-Type", "application/json")
+        .set("User-Agent", "projects-accounts-webhooks")
+        .set("X-Webhook-Id", &webhook.id)
+        .set("X-Webhook-Delivery", &delivery.id)
+        .set("X-Webhook-Event", delivery.event.name())
+        .set("X-Webhook-Timestamp", &timestamp.to_string())
+        .set("X-Webhook-Signature", &sign(&webhook.secret, timestamp, &delivery.payload))
+        .send_string(&delivery.payload);
+
+    match response {
+        Ok(response) if (200..300).contains(&response.status()) => Ok(response.status()),
+        Ok(response) => Err((Some(response.status()), response.status_text().to_string())),
+        Err(ureq::Error::Status(status, response)) => Err((Some(status), response.status_text().to_string())),
+        Err(err) => Err((None, err.to_string()))
+    }
+}
+
+/*- Claim the next due delivery. The lease keeps other
+    workers from sending it at the same time, and makes it
+    due again if this worker dies while sending it -*/
+fn claim() -> Option<Delivery> {
+    let now:i64 = utils::get_unix_epoch_time() as i64;
+    deliveries().find_one_and_update(
+        doc!{
+            "status": "pending",
+            "next_attempt_at": { "$lte": now },
+            "locked_until": { "$lte": now },
+        },
+        doc!{ "$set": { "locked_until": now + DELIVERY_LEASE as i64 } },
+        FindOneAndUpdateOptions::builder()
+            .sort(doc!{ "next_attempt_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build()
+    ).ok().flatten()
+}
+
+/*- Send one claimed delivery, and record how it went -*/
+fn deliver(delivery:Delivery) -> () {
+    let now:u64 = utils::get_unix_epoch_time();
+
+    /*- Deliveries of deleted webhooks are removed with them,
+        but one might have been claimed just before that -*/
+    let webhook:Webhook = match webhooks().find_one(doc!{ "id": &delivery.webhook_id }, None) {
+        Ok(Some(e)) => e,
+        Ok(None) => {
+            deliveries().delete_one(doc!{ "id": &delivery.id }, None).ok();
+            return;
+        },
+        Err(_) => return
+    };
+
+    let attempts:u32 = delivery.attempts + 1;
+    let mut update:Document = match attempt(&webhook, &delivery) {
+        Ok(status) => doc!{
+            "status"       : "delivered",
+            "attempts"     : attempts as i64,
+            "last_response": status as i64,
+            "last_error"   : null,
+            "completed_at" : now as i64,
+        },
+        Err((status, error)) => {
+            let mut update:Document = doc!{
+                "attempts"     : attempts as i64,
+                "last_response": status.map(|e| e as i64),
+                "last_error"   : error.chars().take(MAX_ERROR_LEN).collect::<String>(),
+            };
+            match attempts >= MAX_ATTEMPTS {
+                true => {
+                    update.insert("status", "dead");
+                    update.insert("completed_at", now as i64);
+                },
+                false => { update.insert("next_attempt_at", (now + retry_delay(attempts)) as i64); }
+            };
+            update
+        }
+    };
+
+    /*- Release the lease -*/
+    update.insert("locked_until", 0i64);
+    deliveries().update_one(doc!{ "id": &delivery.id }, doc!{ "$set": update }, None).ok();
+}
+
+/*- Send due deliveries. Runs forever, so call it from its own thread -*/
+pub(crate) fn delivery_loop() -> () {
+    loop {
+        while let Some(delivery) = claim() {
+            deliver(delivery);
+        };
+
+        thread::sleep(Duration::from_secs(DELIVERY_INTERVAL));
+    }
+}
+
+/*- Get the project, if the caller is one of its admins -*/
+fn admin(stream:&mut Stream) -> Option<(Project, UserClaims)> {
+    let project:Project = project::require(stream)?;
+    match authenticate(stream.headers.clone()) {
+        AuthorizationStatus::Authorized(claims) if claims.aud == project.token_audience && claims.api_key.is_none() && is_admin(&claims.suid) => Some((project, claims)),
+        _ => {
+            stream.respond(401, do_json(401, DICTIONARY.error.unauthorized));
+            None
+        }
+    }
+}
+
+/*- Subscribe a URL to events. Takes the `url` header and
+    space separated `events`. The signing secret is only
+    ever returned here -*/
+pub(crate) fn create(stream: &mut Stream) -> () {
+    let (project, claims) = match admin(stream) { Some(e) => e, None => return };
+    let url:String = match stream.headers.get("url").map(|e| e.trim()) {
+        Some(url) if url.len() <= 2048 && !url.contains(char::is_whitespace)
+            && target(url).map_or(false, |(host, port)| public_addresses((host.as_str(), port)).is_ok()) => url.to_string(),
+        _ => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.webhook_url))
+    };
+    let events:Vec<WebhookEvent> = match stream.headers.get("events") {
+        Some(events) => match events.split(' ')
+            .filter(|e| !e.is_empty())
+            .map(|e| serde_json::from_value::<WebhookEvent>(Value::String(e.to_string())))
+            .collect::<Result<Vec<WebhookEvent>, _>>() {
+            Ok(events) if !events.is_empty() => events,
+            _ => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.webhook_events))
+        },
+        None => return stream.respond(400, do_json(400, DICTIONARY.error.invalid.webhook_events))
+    };
+
+    if webhooks().count_documents(doc!{ "project_id": &project.id }, None).unwrap_or(0) >= MAX_WEBHOOKS {
+        return stream.respond(409, do_json(409, DICTIONARY.error.too_many_webhooks));
+    };
+
+    let webhook = Webhook {
+        id        : generate_suid(),
+        project_id: project.id.clone(),
+        url,
+        secret    : format!("{}{}", SECRET_PREFIX, random_string(SECRET_LEN)),
+        events,
+        created_at: utils::get_unix_epoch_time(),
+        created_by: claims.suid.clone(),
+    };
+    if webhooks().insert_one(&webhook, None).is_err() {
+        return stream.respond(500, do_json(500, "Internal server error"));
+    };
+    audit::record(stream, AuditEvent::WebhookCreated, Outcome::Success, Some(&claims.suid), Some(&webhook.id));
+
+    let mut body:Value = describe(&webhook);
+    body["secret"] = Value::String(webhook.secret);
+    stream.respond(200, Respond::new().json(&body.to_string()));
+}
+
+/*- The projects' webhooks -*/
+pub(crate) fn list(stream: &mut Stream) -> () {
+    let (project, claims) = match admin(stream) { Some(e) => e, None => return };
+
+    let options = FindOptions::builder().sort(doc!{ "created_at": -1 }).build();
+    match webhooks().find(doc!{ "project_id": &project.id }, options) {
+        Ok(cursor) => {
+            let items:Vec<Value> = cursor.flatten().map(|webhook| describe(&webhook)).collect();
+            stream.respond(200, Respond::new().json(&serde_json::to_string(&items).unwrap_or_default()));
+        },
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Delete a webhook, and whatever is still queued for it -*/
+pub(crate) fn delete(stream: &mut Stream) -> () {
+    let (project, claims) = match admin(stream) { Some(e) => e, None => return };
+    let id:String = match stream.params.get("id") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+
+    match webhooks().delete_one(doc!{ "id": &id, "project_id": &project.id }, None) {
+        Ok(result) if result.deleted_count == 1 => {
+            deliveries().delete_many(doc!{ "webhook_id": &id, "status": "pending" }, None).ok();
+            audit::record(stream, AuditEvent::WebhookDeleted, Outcome::Success, Some(&claims.suid), Some(&id));
+            stream.respond(200, do_json(200, "Success!"));
+        },
+        Ok(_) => stream.respond(404, do_json(404, DICTIONARY.error.not_found.webhook)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+/*- Respond with a page of deliveries, newest first. The
+    optional `webhook` header narrows them to one webhook -*/
+fn respond_deliveries(stream:&mut Stream, project:&Project, status:Option<DeliveryStatus>) -> () {
+    let (offset, limit) = utils::pagination(&stream.headers, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE);
+    let mut filter:Document = doc!{ "project_id": &project.id };
+    if let Some(webhook) = stream.headers.get("webhook") {
+        filter.insert("webhook_id", webhook.to_string());
+    };
+    if let Some(status) = status {
+        filter.insert("status", bson::to_bson(&status).unwrap_or_default());
+    };
+
+    let options = FindOptions::builder()
+        .sort(doc!{ "created_at": -1 })
+        .skip(offset)
+        .limit(limit + 1)
+        .build();
+    let mut items:Vec<Delivery> = match deliveries().find(filter, options) {
+        Ok(cursor) => cursor.flatten().collect(),
+        Err(_) => return stream.respond(500, do_json(500, "Internal server error"))
+    };
+    let next:Option<u64> = match items.len() as i64 > limit {
+        true => Some(offset + limit as u64),
+        false => None
+    };
+    items.truncate(limit as usize);
+
+    stream.respond(200, Respond::new().json(&serde_json::json!({
+        "items" : items,
+        "offset": offset,
+        "limit" : limit,
+        "next"  : next,
+    }).to_string()));
+}
+
+/*- The delivery log. Takes an optional `status` header
+    of "pending", "delivered" or "dead" -*/
+pub(crate) fn log(stream: &mut Stream) -> () {
+    let (project, claims) = match admin(stream) { Some(e) => e, None => return };
+    let status:Option<DeliveryStatus> = match stream.headers.get("status") {
+        Some(status) => match serde_json::from_value(Value::String(status.to_string())) {
+            Ok(e) => Some(e),
+            Err(_) => return stream.respond(400, do_json(400, "Invalid headers"))
+        },
+        None => None
+    };
+    respond_deliveries(stream, &project, status);
+}
+
+/*- Deliveries which were given up on -*/
+pub(crate) fn dead_letters(stream: &mut Stream) -> () {
+    let (project, claims) = match admin(stream) { Some(e) => e, None => return };
+    respond_deliveries(stream, &project, Some(DeliveryStatus::Dead));
+}
+
+/*- Queue a dead delivery again, with a fresh set of attempts -*/
+pub(crate) fn redeliver(stream: &mut Stream) -> () {
+    let (project, claims) = match admin(stream) { Some(e) => e, None => return };
+    let id:String = match stream.params.get("id") {
+        Some(e) => e.to_string(),
+        None => return utils::respond_status(stream, 410)
+    };
+
+    match deliveries().update_one(
+        doc!{ "id": &id, "project_id": &project.id, "status": "dead" },
+        doc!{
+            "$set": {
+                "status"         : "pending",
+                "attempts"       : 0,
+                "next_attempt_at": utils::get_unix_epoch_time() as i64,
+                "locked_until"   : 0,
+            },
+            "$unset": { "completed_at": "" }
+        },
+        None
+    ) {
+        Ok(result) if result.modified_count == 1 => {
+            audit::record(stream, AuditEvent::WebhookRedelivered, Outcome::Success, Some(&claims.suid), Some(&id));
+            stream.respond(200, do_json(200, "Success!"));
+        },
+        Ok(_) => stream.respond(404, do_json(404, DICTIONARY.error.not_found.delivery)),
+        Err(_) => stream.respond(500, do_json(500, "Internal server error"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox;
+
+    fn ip(ip:&str) -> IpAddr { ip.parse().unwrap() }
+
+    #[test]
+    fn signatures_are_hmacs_of_the_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1700000000, "{\"a\":1}"),
+            "sha256=38877139021993b830af32feea6e18a8da83eb2f6e49ee50bd9e4cf4ca4d3789"
+        );
+        assert_ne!(sign("whsec_test", 1700000001, "{\"a\":1}"), sign("whsec_test", 1700000000, "{\"a\":1}"));
+        assert_ne!(sign("whsec_other", 1700000000, "{\"a\":1}"), sign("whsec_test", 1700000000, "{\"a\":1}"));
+    }
+
+    #[test]
+    fn retries_back_off_up_to_the_maximum() {
+        assert_eq!(retry_delay(1), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(3), RETRY_BASE_DELAY * 4);
+        assert_eq!(retry_delay(MAX_ATTEMPTS), MAX_RETRY_DELAY.min(RETRY_BASE_DELAY * 2u64.pow(MAX_ATTEMPTS - 1)));
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+        assert!((1..MAX_ATTEMPTS).all(|attempts| retry_delay(attempts) <= retry_delay(attempts + 1)));
+    }
+
+    #[test]
+    fn only_public_addresses_are_targets() {
+        for address in ["93.184.216.34", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:8.8.8.8"] {
+            assert!(is_public(&ip(address)), "{}", address);
+        };
+        for address in [
+            "127.0.0.1", "127.1.2.3", "10.0.0.1", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+            "0.0.0.0", "0.1.2.3", "100.64.0.1", "255.255.255.255", "224.0.0.1",
+            "::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "ff02::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(&ip(address)), "{}", address);
+        };
+    }
+
+    #[test]
+    fn targets_are_read_from_the_authority() {
+        assert_eq!(target("https://example.com/hook"), Some(("example.com".to_string(), 443)));
+        assert_eq!(target("http://Example.com:8080?x=1"), Some(("example.com".to_string(), 8080)));
+        assert_eq!(target("https://[2001:db8::1]:8443/hook"), Some(("2001:db8::1".to_string(), 8443)));
+        assert_eq!(target("https://[2001:db8::1]/hook"), Some(("2001:db8::1".to_string(), 443)));
+
+        assert_eq!(target("ftp://example.com"), None);
+        assert_eq!(target("https://user@example.com"), None);
+        assert_eq!(target("https://example.com:port"), None);
+        assert_eq!(target("https://[::1"), None);
+        assert_eq!(target("https:///hook"), None);
+    }
+
+    #[test]
+    fn addresses_are_checked_as_they_resolve() {
+        assert!(public_addresses(("93.184.216.34", 443)).is_ok());
+        assert!(public_addresses(("127.0.0.1", 443)).is_err());
+        assert!(public_addresses(("169.254.169.254", 80)).is_err());
+        assert!(public_addresses("[::1]:443").is_err());
+        assert!(public_addresses(("localhost", 80)).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs MongoDB running as a replica set, see docker-compose.yml"]
+    fn deliveries_are_queued_with_the_change() {
+        let project_id:String = format!("webhook-test-{}", generate_suid());
+        let webhook = Webhook {
+            id        : generate_suid(),
+            project_id: project_id.clone(),
+            url       : "https://example.com/hook".to_string(),
+            secret    : format!("{}{}", SECRET_PREFIX, random_string(SECRET_LEN)),
+            events    : vec![WebhookEvent::AccountCreated],
+            created_at: utils::get_unix_epoch_time(),
+            created_by: generate_suid(),
+        };
+        webhooks().insert_one(&webhook, None).expect("creating a webhook");
+
+        /*- A committed change queues a delivery -*/
+        let user = User { suid: generate_suid(), project_id: project_id.clone(), ..User::default() };
+        outbox::record(&project_id, WebhookEvent::AccountCreated, |users, session| {
+            users.insert_one_with_session(&user, None, session).map(|_| Some(user.clone()))
+        }).expect("recording a change");
+        let queued:Vec<Delivery> = deliveries().find(doc!{ "webhook_id": &webhook.id }, None)
+            .expect("reading deliveries").flatten().collect();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].event, WebhookEvent::AccountCreated);
+
+        /*- Events it isn't subscribed to, and changes which
+            don't happen, queue nothing -*/
+        outbox::record(&project_id, WebhookEvent::AccountUpdated, |users, session| {
+            users.find_one_and_update_with_session(doc!{ "suid": &user.suid }, doc!{ "$set": { "displayname": "x" } }, None, session)
+        }).expect("recording a change");
+        outbox::record(&project_id, WebhookEvent::AccountCreated, |_, _| Ok(None)).expect("recording a change");
+        assert_eq!(deliveries().count_documents(doc!{ "webhook_id": &webhook.id }, None).expect("counting deliveries"), 1);
+
+        utils::establish_mclient::<User>("users").delete_many(doc!{ "project_id": &project_id }, None).ok();
+        webhooks().delete_many(doc!{ "project_id": &project_id }, None).ok();
+        deliveries().delete_many(doc!{ "project_id": &project_id }, None).ok();
+    }
+}
@@ -5,5 +5,8 @@ echo "  ██████    ▄▄▄█████▓    ▄▄▄
 docker-compose down
 docker-compose up -d mongo_account_manager
 
+## The key tokens of the default project are signed with
+: "${DEFAULT_SIGNING_KEY:?DEFAULT_SIGNING_KEY has to be set}"
+
 ## Start the account manager main handler
 cargo run 